@@ -0,0 +1,61 @@
+use std::num::NonZeroUsize;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use transaction_processor::Processor;
+
+const ROWS: usize = 100_000;
+const CLIENTS: u64 = 1_000;
+
+/// Deposits and withdrawals spread over `CLIENTS` clients, with every tenth deposit disputed and
+/// then resolved.
+fn generate_input() -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    let mut tx = 0;
+    while tx < ROWS as u64 {
+        tx += 1;
+        let client = tx % CLIENTS + 1;
+        if tx % 3 == 0 {
+            input.push_str(&format!("withdrawal,{},{},1.5\n", client, tx));
+        } else {
+            input.push_str(&format!("deposit,{},{},2.25\n", client, tx));
+        }
+        if tx % 10 == 1 {
+            input.push_str(&format!("dispute,{},{},\n", client, tx));
+            input.push_str(&format!("resolve,{},{},\n", client, tx));
+        }
+    }
+    input
+}
+
+fn bench_sharded(c: &mut Criterion) {
+    let input = generate_input();
+    let rows = input.lines().count() as u64 - 1;
+
+    let mut group = c.benchmark_group("process");
+    group.throughput(Throughput::Elements(rows));
+    group.sample_size(10);
+
+    group.bench_function("sequential", |b| {
+        b.iter(|| {
+            Processor::new()
+                .process(input.as_bytes(), std::io::sink())
+                .unwrap()
+        })
+    });
+
+    for workers in [2, 4, 8] {
+        group.bench_function(format!("sharded/{}", workers), |b| {
+            b.iter(|| {
+                Processor::new()
+                    .workers(NonZeroUsize::new(workers).unwrap())
+                    .process(input.as_bytes(), std::io::sink())
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_sharded);
+criterion_main!(benches);
@@ -0,0 +1,255 @@
+use serde::Deserialize;
+
+use crate::{
+    error::{ClaimPolicyError, ProcessTransactionError, Result},
+    exchange::Balance,
+    types::{ClaimType, MonetaryAmount, MonetaryTransaction},
+};
+
+/// Decides what claims against deposits and withdrawals do to a client's funds and account. Set
+/// with [`Exchange::set_claim_policy`](crate::Exchange::set_claim_policy), which uses a
+/// [`StandardClaimPolicy`] by default.
+///
+/// The exchange checks a claim before asking the policy about it: that the transaction can be
+/// claimed against at all, that a dispute isn't for more than is left of it, and that a resolve or
+/// chargeback isn't for more than is under dispute. Whatever the client's funds change by, the
+/// house account's chargebacks account changes by the opposite, apart from fees refunded by a
+/// chargeback, which are handled by the [`FeeSchedule`](crate::FeeSchedule).
+///
+/// Changing the policy while claims are open can leave funds held that a resolve or chargeback
+/// won't release.
+pub trait ClaimPolicy: std::fmt::Debug + Send + Sync {
+    /// The change to the client's funds in the asset of `transaction` when `amount` of it is
+    /// claimed, given the client's `balance` in that asset beforehand. Fails to refuse the claim.
+    fn balance_change(
+        &self,
+        claim_type: ClaimType,
+        transaction: MonetaryTransaction,
+        amount: MonetaryAmount,
+        balance: Balance,
+    ) -> Result<BalanceChange>;
+
+    /// Whether a chargeback locks the client's account.
+    fn locks_on_chargeback(&self) -> bool;
+}
+
+/// A change to a client's available and held funds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub available: MonetaryAmount,
+    pub held: MonetaryAmount,
+}
+
+impl BalanceChange {
+    pub fn new(available: MonetaryAmount, held: MonetaryAmount) -> Self {
+        Self { available, held }
+    }
+
+    /// The change to the client's total funds.
+    pub fn total(&self) -> MonetaryAmount {
+        self.available + self.held
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.available.is_zero() && self.held.is_zero()
+    }
+}
+
+/// The claim policy described in the README by default, with options for the semantics some
+/// partners need instead.
+///
+/// ```
+/// use transaction_processor::{ClaimPolicy, StandardClaimPolicy};
+///
+/// let config = r#"{"hold_disputed_withdrawals": true, "lock_on_chargeback": false}"#;
+/// let policy = StandardClaimPolicy::from_json(config.as_bytes()).unwrap();
+///
+/// assert!(policy.hold_disputed_withdrawals);
+/// assert!(!policy.locks_on_chargeback());
+/// assert!(policy.allow_negative_available);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StandardClaimPolicy {
+    /// Hold the disputed amount of a withdrawal, crediting it to the client's held funds until the
+    /// dispute is resolved or a chargeback releases it to their available funds. Otherwise a
+    /// disputed withdrawal doesn't change the client's funds until it's charged back. Off by
+    /// default.
+    pub hold_disputed_withdrawals: bool,
+    /// Lock the client's account on a chargeback. On by default.
+    pub lock_on_chargeback: bool,
+    /// Allow a dispute of a deposit to take the client's available funds below zero, when
+    /// they've spent some of it. Otherwise the dispute fails with
+    /// [`ProcessTransactionError::InsufficientFunds`]. On by default.
+    pub allow_negative_available: bool,
+}
+
+impl Default for StandardClaimPolicy {
+    fn default() -> Self {
+        Self {
+            hold_disputed_withdrawals: false,
+            lock_on_chargeback: true,
+            allow_negative_available: true,
+        }
+    }
+}
+
+impl StandardClaimPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a policy from a JSON object with any of the optional boolean fields
+    /// `hold_disputed_withdrawals`, `lock_on_chargeback` and `allow_negative_available`.
+    pub fn from_json<R: std::io::Read>(rdr: R) -> std::result::Result<Self, ClaimPolicyError> {
+        Ok(serde_json::from_reader(rdr)?)
+    }
+
+    pub fn with_hold_disputed_withdrawals(mut self, hold: bool) -> Self {
+        self.hold_disputed_withdrawals = hold;
+        self
+    }
+
+    pub fn with_lock_on_chargeback(mut self, lock: bool) -> Self {
+        self.lock_on_chargeback = lock;
+        self
+    }
+
+    pub fn with_allow_negative_available(mut self, allow: bool) -> Self {
+        self.allow_negative_available = allow;
+        self
+    }
+}
+
+impl ClaimPolicy for StandardClaimPolicy {
+    fn balance_change(
+        &self,
+        claim_type: ClaimType,
+        transaction: MonetaryTransaction,
+        amount: MonetaryAmount,
+        balance: Balance,
+    ) -> Result<BalanceChange> {
+        let hold = self.hold_disputed_withdrawals;
+        let change = match (transaction, claim_type) {
+            // Ring-fence the funds, which the client may have spent already
+            (MonetaryTransaction::Deposit(_), ClaimType::Dispute) => {
+                if !self.allow_negative_available && balance.available < amount {
+                    return Err(ProcessTransactionError::InsufficientFunds {
+                        requested: amount,
+                        available: balance.available,
+                    });
+                }
+                BalanceChange::new(-amount, amount)
+            }
+            (MonetaryTransaction::Deposit(_), ClaimType::Resolve) => {
+                BalanceChange::new(amount, -amount)
+            }
+            // The exchange takes hold of the held funds
+            (MonetaryTransaction::Deposit(_), ClaimType::Chargeback) => {
+                BalanceChange::new(MonetaryAmount::ZERO, -amount)
+            }
+            (MonetaryTransaction::Withdrawal(_), ClaimType::Dispute) if hold => {
+                BalanceChange::new(MonetaryAmount::ZERO, amount)
+            }
+            (MonetaryTransaction::Withdrawal(_), ClaimType::Resolve) if hold => {
+                BalanceChange::new(MonetaryAmount::ZERO, -amount)
+            }
+            (MonetaryTransaction::Withdrawal(_), ClaimType::Chargeback) if hold => {
+                BalanceChange::new(amount, -amount)
+            }
+            (MonetaryTransaction::Withdrawal(_), ClaimType::Dispute | ClaimType::Resolve) => {
+                BalanceChange::default()
+            }
+            // The exchange credits the client
+            (MonetaryTransaction::Withdrawal(_), ClaimType::Chargeback) => {
+                BalanceChange::new(amount, MonetaryAmount::ZERO)
+            }
+            (MonetaryTransaction::Transfer { .. } | MonetaryTransaction::Convert { .. }, _) => {
+                return Err(ProcessTransactionError::NotDisputable);
+            }
+        };
+        Ok(change)
+    }
+
+    fn locks_on_chargeback(&self) -> bool {
+        self.lock_on_chargeback
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    fn balance(available: MonetaryAmount) -> Balance {
+        Balance {
+            available,
+            held: MonetaryAmount::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_standard_policy_holds_disputed_withdrawals_when_asked() {
+        let withdrawal = MonetaryTransaction::Withdrawal(dec!(5));
+        let claims = [
+            ClaimType::Dispute,
+            ClaimType::Resolve,
+            ClaimType::Chargeback,
+        ];
+
+        let changes = |policy: StandardClaimPolicy| {
+            claims.map(|claim_type| {
+                policy
+                    .balance_change(claim_type, withdrawal, dec!(2), balance(dec!(0)))
+                    .unwrap()
+            })
+        };
+        assert_eq!(
+            changes(StandardClaimPolicy::new()),
+            [
+                BalanceChange::default(),
+                BalanceChange::default(),
+                BalanceChange::new(dec!(2), dec!(0)),
+            ]
+        );
+        assert_eq!(
+            changes(StandardClaimPolicy::new().with_hold_disputed_withdrawals(true)),
+            [
+                BalanceChange::new(dec!(0), dec!(2)),
+                BalanceChange::new(dec!(0), dec!(-2)),
+                BalanceChange::new(dec!(2), dec!(-2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_standard_policy_can_refuse_overdrawing_disputes() {
+        let deposit = MonetaryTransaction::Deposit(dec!(5));
+        let dispute = |policy: StandardClaimPolicy, available| {
+            policy.balance_change(ClaimType::Dispute, deposit, dec!(5), balance(available))
+        };
+
+        assert_eq!(
+            dispute(StandardClaimPolicy::new(), dec!(1)).unwrap(),
+            BalanceChange::new(dec!(-5), dec!(5))
+        );
+        let strict = StandardClaimPolicy::new().with_allow_negative_available(false);
+        assert!(dispute(strict, dec!(5)).is_ok());
+        assert!(matches!(
+            dispute(strict, dec!(4.9999)),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        assert!(matches!(
+            StandardClaimPolicy::from_json(r#"{"lock": false}"#.as_bytes()),
+            Err(ClaimPolicyError::Json(_))
+        ));
+        assert_eq!(
+            StandardClaimPolicy::from_json("{}".as_bytes()).unwrap(),
+            StandardClaimPolicy::default()
+        );
+    }
+}
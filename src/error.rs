@@ -1,11 +1,50 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::types::{ClientId, MonetaryAmount, RequestType, TransactionId};
+
 pub type Result<T> = std::result::Result<T, ProcessTransactionError>;
 
+/// Broad classification of a [`ProcessTransactionError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorCategory {
+    /// The request itself is invalid, regardless of the state of the exchange.
+    Validation,
+    /// The client isn't allowed to act on the transaction.
+    Authorization,
+    /// The request is not valid given the current state of the client or transaction.
+    State,
+    /// Applying the request would overflow a balance.
+    Arithmetic,
+    /// The exchange failed to read or write its transaction storage. This isn't caused by the
+    /// request.
+    Storage,
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCategory::Validation => "validation",
+            ErrorCategory::Authorization => "authorization",
+            ErrorCategory::State => "state",
+            ErrorCategory::Arithmetic => "arithmetic",
+            ErrorCategory::Storage => "storage",
+        })
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ProcessTransactionError {
     #[error("Client is locked")]
     ClientLocked,
-    #[error("Invalid data: {0}")]
-    InvalidData(&'static str),
+    #[error("Amount is required for this transaction")]
+    MissingAmount,
+    #[error("Amount must be positive")]
+    NegativeAmount,
+    #[error("Claim amount must be more than zero")]
+    ZeroClaimAmount,
     #[error("Transaction already exists")]
     DuplicateTransaction,
     #[error("Transaction does not exist")]
@@ -16,8 +55,458 @@ pub enum ProcessTransactionError {
     ClientNotFound,
     #[error("Arithmetic overflow occurred")]
     Overflow,
-    #[error("Insufficient funds")]
-    InsufficientFunds,
-    #[error("Invalid operation: {0}")]
-    InvalidOperation(&'static str),
+    /// `available` is how much could have been taken, including any credit limit.
+    #[error("Insufficient funds: {requested} requested, {available} available")]
+    InsufficientFunds {
+        requested: MonetaryAmount,
+        available: MonetaryAmount,
+    },
+    #[error("Transaction already disputed")]
+    AlreadyDisputed,
+    #[error("Transaction has nothing to dispute")]
+    NothingToDispute,
+    #[error("No dispute to resolve")]
+    NoDisputeToResolve,
+    #[error("No dispute to chargeback")]
+    NoDisputeToChargeback,
+    #[error("Timestamp is required to order transactions")]
+    MissingTimestamp,
+    #[error("Timestamp is too far behind earlier transactions")]
+    OutOfOrder,
+    #[error("Transaction is outside the dispute window")]
+    DisputeWindowExpired,
+    #[error("Destination client is required for a transfer")]
+    MissingDestination,
+    #[error("Cannot transfer to the same client")]
+    TransferToSelf,
+    #[error("Destination client not found")]
+    DestinationNotFound,
+    #[error("Destination client is locked")]
+    DestinationLocked,
+    #[error("Transfers and conversions cannot be disputed")]
+    NotDisputable,
+    #[error("Target asset is required for a conversion")]
+    MissingTargetAsset,
+    #[error("Cannot convert an asset to itself")]
+    ConvertToSameAsset,
+    #[error("No exchange rate for this pair of assets")]
+    UnknownPair,
+    #[error("Exchange rate is too old")]
+    StaleRate,
+    #[error("Converted amount rounds to zero")]
+    ConversionTooSmall,
+    #[error("Operator is required for an administrative request")]
+    MissingOperator,
+    #[error("Reason code is required for an administrative request")]
+    MissingReason,
+    #[error("Operator is not authorized")]
+    UnauthorizedOperator,
+    #[error("Client is frozen")]
+    ClientFrozen,
+    #[error("Client is closed")]
+    ClientClosed,
+    #[error("Client is not locked or frozen")]
+    NotLocked,
+    #[error("Client is already frozen")]
+    AlreadyFrozen,
+    #[error("Cannot close an account that holds funds")]
+    BalanceNotZero,
+    #[error("Dispute is for more than is left of the transaction to dispute")]
+    DisputeExceedsTransaction,
+    #[error("Claim is for more than is disputed")]
+    ClaimExceedsDispute,
+    #[error("Transaction storage failed: {0}")]
+    Storage(std::io::Error),
+}
+
+impl ProcessTransactionError {
+    /// A stable code identifying the error, suitable for matching on by downstream systems.
+    ///
+    /// Codes are never changed or reused once published, unlike the error messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProcessTransactionError::ClientLocked => "E_CLIENT_LOCKED",
+            ProcessTransactionError::MissingAmount => "E_MISSING_AMOUNT",
+            ProcessTransactionError::NegativeAmount => "E_NEGATIVE_AMOUNT",
+            ProcessTransactionError::ZeroClaimAmount => "E_ZERO_CLAIM_AMOUNT",
+            ProcessTransactionError::DuplicateTransaction => "E_DUPLICATE_TRANSACTION",
+            ProcessTransactionError::TransactionNotFound => "E_TRANSACTION_NOT_FOUND",
+            ProcessTransactionError::Unauthorized => "E_UNAUTHORIZED",
+            ProcessTransactionError::ClientNotFound => "E_CLIENT_NOT_FOUND",
+            ProcessTransactionError::Overflow => "E_OVERFLOW",
+            ProcessTransactionError::InsufficientFunds { .. } => "E_INSUFFICIENT_FUNDS",
+            ProcessTransactionError::AlreadyDisputed => "E_ALREADY_DISPUTED",
+            ProcessTransactionError::NothingToDispute => "E_NOTHING_TO_DISPUTE",
+            ProcessTransactionError::NoDisputeToResolve => "E_NO_DISPUTE_TO_RESOLVE",
+            ProcessTransactionError::NoDisputeToChargeback => "E_NO_DISPUTE_TO_CHARGEBACK",
+            ProcessTransactionError::MissingTimestamp => "E_MISSING_TIMESTAMP",
+            ProcessTransactionError::OutOfOrder => "E_OUT_OF_ORDER",
+            ProcessTransactionError::DisputeWindowExpired => "E_DISPUTE_WINDOW_EXPIRED",
+            ProcessTransactionError::MissingDestination => "E_MISSING_DESTINATION",
+            ProcessTransactionError::TransferToSelf => "E_TRANSFER_TO_SELF",
+            ProcessTransactionError::DestinationNotFound => "E_DESTINATION_NOT_FOUND",
+            ProcessTransactionError::DestinationLocked => "E_DESTINATION_LOCKED",
+            ProcessTransactionError::NotDisputable => "E_NOT_DISPUTABLE",
+            ProcessTransactionError::MissingTargetAsset => "E_MISSING_TARGET_ASSET",
+            ProcessTransactionError::ConvertToSameAsset => "E_CONVERT_TO_SAME_ASSET",
+            ProcessTransactionError::UnknownPair => "E_UNKNOWN_PAIR",
+            ProcessTransactionError::StaleRate => "E_STALE_RATE",
+            ProcessTransactionError::ConversionTooSmall => "E_CONVERSION_TOO_SMALL",
+            ProcessTransactionError::MissingOperator => "E_MISSING_OPERATOR",
+            ProcessTransactionError::MissingReason => "E_MISSING_REASON",
+            ProcessTransactionError::UnauthorizedOperator => "E_UNAUTHORIZED_OPERATOR",
+            ProcessTransactionError::ClientFrozen => "E_CLIENT_FROZEN",
+            ProcessTransactionError::ClientClosed => "E_CLIENT_CLOSED",
+            ProcessTransactionError::NotLocked => "E_NOT_LOCKED",
+            ProcessTransactionError::AlreadyFrozen => "E_ALREADY_FROZEN",
+            ProcessTransactionError::BalanceNotZero => "E_BALANCE_NOT_ZERO",
+            ProcessTransactionError::DisputeExceedsTransaction => "E_DISPUTE_EXCEEDS_TRANSACTION",
+            ProcessTransactionError::ClaimExceedsDispute => "E_CLAIM_EXCEEDS_DISPUTE",
+            ProcessTransactionError::Storage(_) => "E_STORAGE",
+        }
+    }
+
+    /// Codes the error was published under before it had a code of its own. `E_INVALID_DATA` and
+    /// `E_INVALID_OPERATION` covered several errors, which were later split into their own codes.
+    pub fn aliases(&self) -> &'static [&'static str] {
+        match self {
+            ProcessTransactionError::MissingAmount | ProcessTransactionError::NegativeAmount => {
+                &["E_INVALID_DATA"]
+            }
+            ProcessTransactionError::AlreadyDisputed
+            | ProcessTransactionError::NoDisputeToResolve
+            | ProcessTransactionError::NoDisputeToChargeback => &["E_INVALID_OPERATION"],
+            _ => &[],
+        }
+    }
+
+    /// Whether the error has the code, or had it as one of its [aliases](Self::aliases).
+    pub fn has_code(&self, code: &str) -> bool {
+        self.code() == code || self.aliases().contains(&code)
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            ProcessTransactionError::MissingAmount
+            | ProcessTransactionError::NegativeAmount
+            | ProcessTransactionError::ZeroClaimAmount
+            | ProcessTransactionError::MissingTimestamp
+            | ProcessTransactionError::OutOfOrder
+            | ProcessTransactionError::MissingDestination
+            | ProcessTransactionError::TransferToSelf
+            | ProcessTransactionError::NotDisputable
+            | ProcessTransactionError::MissingTargetAsset
+            | ProcessTransactionError::ConvertToSameAsset
+            | ProcessTransactionError::UnknownPair
+            | ProcessTransactionError::ConversionTooSmall
+            | ProcessTransactionError::MissingOperator
+            | ProcessTransactionError::MissingReason
+            | ProcessTransactionError::DuplicateTransaction
+            | ProcessTransactionError::TransactionNotFound => ErrorCategory::Validation,
+            ProcessTransactionError::Unauthorized
+            | ProcessTransactionError::UnauthorizedOperator => ErrorCategory::Authorization,
+            ProcessTransactionError::ClientLocked
+            | ProcessTransactionError::ClientNotFound
+            | ProcessTransactionError::DestinationNotFound
+            | ProcessTransactionError::DestinationLocked
+            | ProcessTransactionError::InsufficientFunds { .. }
+            | ProcessTransactionError::AlreadyDisputed
+            | ProcessTransactionError::NothingToDispute
+            | ProcessTransactionError::NoDisputeToResolve
+            | ProcessTransactionError::NoDisputeToChargeback
+            | ProcessTransactionError::StaleRate
+            | ProcessTransactionError::ClientFrozen
+            | ProcessTransactionError::ClientClosed
+            | ProcessTransactionError::NotLocked
+            | ProcessTransactionError::AlreadyFrozen
+            | ProcessTransactionError::BalanceNotZero
+            | ProcessTransactionError::DisputeExceedsTransaction
+            | ProcessTransactionError::ClaimExceedsDispute
+            | ProcessTransactionError::DisputeWindowExpired => ErrorCategory::State,
+            ProcessTransactionError::Overflow => ErrorCategory::Arithmetic,
+            ProcessTransactionError::Storage(_) => ErrorCategory::Storage,
+        }
+    }
+}
+
+/// Why a row could not be deserialized, depending on the input format.
+#[derive(thiserror::Error, Debug)]
+pub enum MalformedRecord {
+    #[error(transparent)]
+    Csv(csv::Error),
+    #[error(transparent)]
+    Json(serde_json::Error),
+}
+
+/// A row that could not be applied to the exchange.
+#[derive(thiserror::Error, Debug)]
+pub enum RowError {
+    /// The row could not be deserialized.
+    #[error("Malformed record: {0}")]
+    Malformed(MalformedRecord),
+    /// The row deserialized but failed validation.
+    #[error("Invalid record: {0}")]
+    Invalid(ProcessTransactionError),
+    /// The exchange refused the transaction.
+    #[error("Error processing transaction: {0}")]
+    Rejected(ProcessTransactionError),
+}
+
+impl RowError {
+    /// A stable code identifying the error, see [`ProcessTransactionError::code`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            RowError::Malformed(_) => "E_MALFORMED_RECORD",
+            RowError::Invalid(e) | RowError::Rejected(e) => e.code(),
+        }
+    }
+
+    /// Earlier codes of the error, see [`ProcessTransactionError::aliases`].
+    pub fn aliases(&self) -> &'static [&'static str] {
+        match self {
+            RowError::Malformed(_) => &[],
+            RowError::Invalid(e) | RowError::Rejected(e) => e.aliases(),
+        }
+    }
+
+    /// Whether the error has the code, or had it as one of its aliases.
+    pub fn has_code(&self, code: &str) -> bool {
+        self.code() == code || self.aliases().contains(&code)
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            RowError::Malformed(_) => ErrorCategory::Validation,
+            RowError::Invalid(e) | RowError::Rejected(e) => e.category(),
+        }
+    }
+
+    /// The underlying error message, without the stage the row failed at.
+    pub fn message(&self) -> String {
+        match self {
+            RowError::Malformed(e) => e.to_string(),
+            RowError::Invalid(e) | RowError::Rejected(e) => e.to_string(),
+        }
+    }
+
+    /// Whether the row never made it as far as the exchange.
+    pub fn is_malformed(&self) -> bool {
+        matches!(self, RowError::Malformed(_) | RowError::Invalid(_))
+    }
+}
+
+/// Where a row was found in the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputPosition {
+    /// Byte offset of the start of the row.
+    pub byte: u64,
+    /// 1-based line number of the start of the row.
+    pub line: u64,
+}
+
+impl From<&csv::Position> for InputPosition {
+    fn from(position: &csv::Position) -> Self {
+        Self {
+            byte: position.byte(),
+            line: position.line(),
+        }
+    }
+}
+
+/// Everything known about the row an error occurred on.
+///
+/// Fields are filled in as far as the row could be parsed, so a malformed row may only have a
+/// position.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorContext {
+    pub position: Option<InputPosition>,
+    pub transaction: Option<TransactionId>,
+    pub client: Option<ClientId>,
+    pub request_type: Option<RequestType>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(position) = self.position {
+            parts.push(format!("line {}", position.line));
+            parts.push(format!("byte {}", position.byte));
+        }
+        if let Some(transaction) = self.transaction {
+            parts.push(format!("tx {}", transaction));
+        }
+        if let Some(client) = self.client {
+            parts.push(format!("client {}", client));
+        }
+        if let Some(request_type) = self.request_type {
+            parts.push(request_type.to_string());
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// A [`RowError`] along with the input it occurred on.
+#[derive(thiserror::Error, Debug)]
+#[error("{code}: {error} ({context})", code = error.code())]
+pub struct BadRow {
+    // Boxed to keep `ProcessError` small
+    context: Box<ErrorContext>,
+    #[source]
+    error: RowError,
+}
+
+impl BadRow {
+    pub fn new(context: ErrorContext, error: RowError) -> Self {
+        Self {
+            context: Box::new(context),
+            error,
+        }
+    }
+
+    pub fn context(&self) -> &ErrorContext {
+        &self.context
+    }
+
+    pub fn error(&self) -> &RowError {
+        &self.error
+    }
+}
+
+/// Errors that stop a processing run entirely.
+///
+/// Whether a bad row stops the run depends on the [`IngestPolicy`](crate::IngestPolicy).
+#[derive(thiserror::Error, Debug)]
+pub enum ProcessError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("CSV error: {0}")]
+    Csv(csv::Error),
+    #[error("{0}")]
+    BadRow(BadRow),
+    #[error("Aborted after exceeding {limit} bad rows. {last}")]
+    TooManyBadRows { limit: u64, last: BadRow },
+    /// A custom [`BalanceSink`](crate::BalanceSink) failed.
+    #[error("Failed to write balances: {0}")]
+    Sink(Box<dyn std::error::Error + Send + Sync>),
+    /// Adding up a total across clients or shards overflowed, though each part fit on its own.
+    /// The field says which total.
+    #[error("Arithmetic overflow adding up {0}")]
+    Overflow(&'static str),
+}
+
+impl From<csv::Error> for ProcessError {
+    fn from(err: csv::Error) -> Self {
+        if err.is_io_error() {
+            ProcessError::Io(err.into())
+        } else {
+            ProcessError::Csv(err)
+        }
+    }
+}
+
+/// Errors saving or loading a snapshot of an [`Exchange`](crate::Exchange).
+#[derive(thiserror::Error, Debug)]
+pub enum SnapshotError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid snapshot: {0}")]
+    Json(serde_json::Error),
+    #[error("Unsupported snapshot version {found}, expected {expected}")]
+    UnsupportedVersion { found: u32, expected: u32 },
+    /// The snapshot could be read but its contents don't make sense together.
+    #[error("Inconsistent snapshot: {0}")]
+    Inconsistent(String),
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_io() {
+            SnapshotError::Io(err.into())
+        } else {
+            SnapshotError::Json(err)
+        }
+    }
+}
+
+/// Errors loading a [`RateTable`](crate::RateTable).
+#[derive(thiserror::Error, Debug)]
+pub enum RateTableError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("CSV error: {0}")]
+    Csv(csv::Error),
+    #[error("Invalid rate on line {line}: {message}")]
+    Invalid { line: u64, message: String },
+}
+
+impl From<csv::Error> for RateTableError {
+    fn from(err: csv::Error) -> Self {
+        if err.is_io_error() {
+            RateTableError::Io(err.into())
+        } else {
+            RateTableError::Csv(err)
+        }
+    }
+}
+
+/// Errors loading a [`FeeSchedule`](crate::FeeSchedule).
+#[derive(thiserror::Error, Debug)]
+pub enum FeeScheduleError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid fee schedule: {0}")]
+    Json(serde_json::Error),
+    #[error("Invalid fee schedule: {0}")]
+    Invalid(String),
+}
+
+impl From<serde_json::Error> for FeeScheduleError {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_io() {
+            FeeScheduleError::Io(err.into())
+        } else {
+            FeeScheduleError::Json(err)
+        }
+    }
+}
+
+/// Errors loading a [`StandardClaimPolicy`](crate::StandardClaimPolicy).
+#[derive(thiserror::Error, Debug)]
+pub enum ClaimPolicyError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid claim policy: {0}")]
+    Json(serde_json::Error),
+}
+
+impl From<serde_json::Error> for ClaimPolicyError {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_io() {
+            ClaimPolicyError::Io(err.into())
+        } else {
+            ClaimPolicyError::Json(err)
+        }
+    }
+}
+
+/// Errors loading [`CreditLimits`](crate::CreditLimits).
+#[derive(thiserror::Error, Debug)]
+pub enum CreditLimitsError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("CSV error: {0}")]
+    Csv(csv::Error),
+    #[error("Invalid credit limit on line {line}: {message}")]
+    Invalid { line: u64, message: String },
+}
+
+impl From<csv::Error> for CreditLimitsError {
+    fn from(err: csv::Error) -> Self {
+        if err.is_io_error() {
+            CreditLimitsError::Io(err.into())
+        } else {
+            CreditLimitsError::Csv(err)
+        }
+    }
 }
@@ -0,0 +1,202 @@
+use crate::{
+    error::ProcessError,
+    exchange::{AccountStatus, ClaimState, HouseAccount},
+    sink::{AuditRow, write_audit},
+    types::{
+        Asset, ClientId, MonetaryAmount, MonetaryTransaction, OperatorId, ReasonCode, Timestamp,
+        TransactionId, TransactionRequest,
+    },
+};
+
+/// An entry in an [`EventLog`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Position of the event in the log, starting from 0.
+    pub seq: u64,
+    /// The client the event belongs to.
+    pub client: ClientId,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// A request was accepted by the exchange. The state changes it caused follow it in the log.
+    Accepted(TransactionRequest),
+    /// The state of the client changed.
+    Changed(StateChange),
+}
+
+/// A single change to the state of a client, from which the exchange can be rebuilt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateChange {
+    /// The client was created. This is recorded even if the request that created the client was
+    /// then rejected.
+    ClientCreated,
+    /// A deposit, withdrawal, transfer or conversion was recorded against the client, so it can be
+    /// claimed later.
+    TransactionRecorded {
+        transaction: TransactionId,
+        request: MonetaryTransaction,
+        asset: Asset,
+        /// The fee charged on the transaction, which isn't included in `request`.
+        fee: MonetaryAmount,
+        timestamp: Option<Timestamp>,
+    },
+    /// The client's balances in `asset` changed by the given amounts because of `transaction`. A
+    /// dispute of a deposit moves funds from available to held, for example, and a resolve moves
+    /// them back.
+    BalanceChanged {
+        transaction: TransactionId,
+        asset: Asset,
+        available: MonetaryAmount,
+        held: MonetaryAmount,
+    },
+    /// The claim against `transaction` changed to `claim`, which is how much of it is disputed
+    /// and charged back in total.
+    ClaimChanged {
+        transaction: TransactionId,
+        claim: ClaimState,
+    },
+    /// The house account's balance in `asset` changed by `amount` because of the client's
+    /// `transaction`. Each is the counter-entry of a change to the client's balances, such as a
+    /// fee taken from them or funds taken back by a chargeback.
+    HouseChanged {
+        transaction: TransactionId,
+        asset: Asset,
+        account: HouseAccount,
+        amount: MonetaryAmount,
+    },
+    /// The status of the client's account changed because of `transaction`, either by an
+    /// `operator` or, without one, by the exchange itself, as when a chargeback locks it.
+    StatusChanged {
+        transaction: TransactionId,
+        status: AccountStatus,
+        operator: Option<OperatorId>,
+        reason: ReasonCode,
+    },
+    /// An `operator` changed the client's credit limit in `asset` to `limit` because of
+    /// `transaction`.
+    LimitChanged {
+        transaction: TransactionId,
+        asset: Asset,
+        limit: MonetaryAmount,
+        operator: OperatorId,
+        reason: ReasonCode,
+    },
+    /// `transaction` left the dispute window, so its details were dropped. Only its ID and owner
+    /// are kept.
+    TransactionExpired { transaction: TransactionId },
+}
+
+/// Append-only log of every accepted request and the state changes it caused, recorded by an
+/// [`Exchange`](crate::Exchange) created with
+/// [`Exchange::with_event_log`](crate::Exchange::with_event_log).
+///
+/// [`Exchange::replay`](crate::Exchange::replay) rebuilds the exchange from its log.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EventLog {
+    events: Vec<Event>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn append(&mut self, client: ClientId, kind: EventKind) {
+        let seq = self.events.len() as u64;
+        self.events.push(Event { seq, client, kind });
+    }
+
+    /// Move the events of `other` to the end of this log, renumbering them.
+    pub(crate) fn append_log(&mut self, other: &mut EventLog) {
+        for event in other.events.drain(..) {
+            self.append(event.client, event.kind);
+        }
+    }
+
+    /// The start of the log, up to the first accepted request timestamped after `as_of`.
+    /// [`Exchange::replay`](crate::Exchange::replay) rebuilds the exchange as it was at that
+    /// time from it.
+    ///
+    /// This assumes requests were applied in timestamp order, such as with
+    /// [`TimestampOrder::Reorder`](crate::TimestampOrder::Reorder). Requests without a timestamp
+    /// never end the log early.
+    pub fn until(&self, as_of: Timestamp) -> EventLog {
+        let end = self
+            .events
+            .iter()
+            .position(|event| match &event.kind {
+                EventKind::Accepted(request) => request
+                    .timestamp()
+                    .is_some_and(|timestamp| timestamp > as_of),
+                EventKind::Changed(_) => false,
+            })
+            .unwrap_or(self.events.len());
+        EventLog {
+            events: self.events[..end].to_vec(),
+        }
+    }
+
+    /// Write every change to the status or credit limit of an account as CSV, in the order they
+    /// happened, with the columns `seq`, `client`, `tx`, `status`, `operator`, `reason`,
+    /// `timestamp`, `asset` and `limit`. The operator is empty for changes made by the exchange
+    /// itself, and the timestamp is that of the request that made the change, if it had one. A
+    /// change of status has an empty asset and limit, and a change of limit an empty status.
+    pub fn write_audit<W: std::io::Write>(&self, wtr: W) -> Result<(), ProcessError> {
+        let mut timestamp = None;
+        let mut rows = Vec::new();
+        for event in &self.events {
+            match event.kind {
+                EventKind::Accepted(request) => timestamp = request.timestamp(),
+                EventKind::Changed(StateChange::StatusChanged {
+                    transaction,
+                    status,
+                    operator,
+                    reason,
+                }) => rows.push(AuditRow {
+                    seq: event.seq,
+                    client: event.client,
+                    tx: transaction,
+                    status: Some(status),
+                    operator,
+                    reason,
+                    timestamp: timestamp.map(|timestamp: Timestamp| timestamp.to_string()),
+                    asset: Asset::default(),
+                    limit: None,
+                }),
+                EventKind::Changed(StateChange::LimitChanged {
+                    transaction,
+                    asset,
+                    limit,
+                    operator,
+                    reason,
+                }) => rows.push(AuditRow {
+                    seq: event.seq,
+                    client: event.client,
+                    tx: transaction,
+                    status: None,
+                    operator: Some(operator),
+                    reason,
+                    timestamp: timestamp.map(|timestamp: Timestamp| timestamp.to_string()),
+                    asset,
+                    limit: Some(limit),
+                }),
+                EventKind::Changed(_) => {}
+            }
+        }
+        write_audit(wtr, &rows)
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
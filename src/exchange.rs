@@ -1,497 +1,4394 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    TransactionRequest,
-    error::{ProcessTransactionError, Result},
-    types::{ClaimType, ClientId, MonetaryAmount, MonetaryTransaction, RequestType, TransactionId},
+    claims::{ClaimPolicy, StandardClaimPolicy},
+    error::{ProcessError, ProcessTransactionError, Result, SnapshotError},
+    event::{Event, EventKind, EventLog, StateChange},
+    fees::{DisputedFees, FeeSchedule, Fees},
+    limits::CreditLimits,
+    rates::RateTable,
+    sink::{
+        BalanceSink, ClientSnapshot, CollectionsRow, HouseReportRow, write_collections_report,
+        write_house_report,
+    },
+    snapshot::{
+        BalanceRecord, ClientRecord, HouseRecord, LimitRecord, OwnerRecord, SNAPSHOT_VERSION,
+        Snapshot, TransactionRecord, read_snapshot, write_snapshot,
+    },
+    store::{TransactionInformation, TransactionStore},
+    types::{
+        AdminAction, AdminRequest, Asset, ClaimRequest, ClaimType, ClientId, MonetaryAmount,
+        MonetaryTransaction, OperatorId, ReasonCode, RequestType, Timestamp, TransactionId,
+        TransactionRequest,
+    },
 };
 
+/// The transaction engine, holding the state of every client's account.
+///
+/// ```
+/// use rust_decimal::dec;
+/// use transaction_processor::{ClaimType, ClientId, Exchange, TransactionId, TransactionRequest};
+///
+/// let mut exchange = Exchange::new();
+/// let client = ClientId(1);
+///
+/// let deposit = TransactionRequest::deposit(client, TransactionId(1), dec!(10)).unwrap();
+/// exchange.process_transaction(deposit).unwrap();
+/// let dispute = TransactionRequest::claim(client, TransactionId(1), ClaimType::Dispute);
+/// exchange.process_transaction(dispute).unwrap();
+///
+/// let view = exchange.client(client).unwrap();
+/// assert_eq!(view.available(), dec!(0));
+/// assert_eq!(view.held(), dec!(10));
+/// ```
 pub struct Exchange {
     clients: HashMap<ClientId, Client>,
-    transactions: HashMap<TransactionId, ClientId>,
+    /// Shared with every shard of a [`ShardedExchange`](crate::ShardedExchange).
+    transactions: Arc<TransactionStore>,
+    events: Option<EventLog>,
+    dispute_window: Option<DisputeWindow>,
+    /// Shared with every shard of a [`ShardedExchange`](crate::ShardedExchange).
+    rates: Arc<RateTable>,
+    /// Shared with every shard of a [`ShardedExchange`](crate::ShardedExchange).
+    fees: Arc<FeeSchedule>,
+    /// Operators allowed to make administrative requests. Shared with every shard of a
+    /// [`ShardedExchange`](crate::ShardedExchange).
+    operators: Arc<HashSet<OperatorId>>,
+    /// Shared with every shard of a [`ShardedExchange`](crate::ShardedExchange).
+    claim_policy: Arc<dyn ClaimPolicy>,
+    /// Limits of clients who haven't been given one by an operator. Shared with every shard of a
+    /// [`ShardedExchange`](crate::ShardedExchange).
+    credit_limits: Arc<CreditLimits>,
+    /// The house account in each asset. Each shard of a
+    /// [`ShardedExchange`](crate::ShardedExchange) keeps its own, which are added up when the
+    /// shards are merged.
+    house: BTreeMap<Asset, HouseBalance>,
+    /// The latest timestamp of any request the exchange has accepted. Each shard of a
+    /// [`ShardedExchange`](crate::ShardedExchange) keeps its own, the latest of which is kept
+    /// when the shards are merged.
+    latest: Option<Timestamp>,
+}
+
+impl Default for Exchange {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Exchange {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
-            transactions: HashMap::new(),
+            transactions: Arc::new(TransactionStore::in_memory()),
+            events: None,
+            dispute_window: None,
+            rates: Arc::new(RateTable::new()),
+            fees: Arc::new(FeeSchedule::new()),
+            operators: Arc::new(HashSet::new()),
+            claim_policy: Arc::new(StandardClaimPolicy::new()),
+            credit_limits: Arc::new(CreditLimits::new()),
+            house: BTreeMap::new(),
+            latest: None,
+        }
+    }
+
+    /// Only allow disputes within `window`. Transactions that leave the window are expired:
+    /// their details are dropped, and a later claim against them fails with
+    /// [`ProcessTransactionError::DisputeWindowExpired`].
+    ///
+    /// Only transactions recorded after the window is set are expired.
+    pub fn set_dispute_window(&mut self, window: DisputeWindow) {
+        self.dispute_window = Some(window);
+    }
+
+    /// Apply conversions at the rates in `rates`. Without a rate table, every conversion fails
+    /// with [`ProcessTransactionError::UnknownPair`].
+    pub fn set_rate_table(&mut self, rates: RateTable) {
+        self.rates = Arc::new(rates);
+    }
+
+    /// Charge the fees in `fees` on deposits and withdrawals from now on, collecting them into the
+    /// house account.
+    pub fn set_fee_schedule(&mut self, fees: FeeSchedule) {
+        self.fees = Arc::new(fees);
+    }
+
+    /// Allow `operators`, and only them, to make administrative requests. Without any, every
+    /// administrative request fails with [`ProcessTransactionError::UnauthorizedOperator`].
+    pub fn set_operators(&mut self, operators: impl IntoIterator<Item = OperatorId>) {
+        self.operators = Arc::new(operators.into_iter().collect());
+    }
+
+    /// Decide what claims do to clients' funds and accounts with `policy` from now on, instead of
+    /// the default [`StandardClaimPolicy`].
+    pub fn set_claim_policy(&mut self, policy: impl ClaimPolicy + 'static) {
+        self.claim_policy = Arc::new(policy);
+    }
+
+    /// Let clients withdraw down to minus their limit in `limits` from now on, unless an operator
+    /// has given them another with a `set_limit` request.
+    pub fn set_credit_limits(&mut self, limits: CreditLimits) {
+        self.credit_limits = Arc::new(limits);
+    }
+
+    /// The house account in each asset it has had any entries in, in ascending order of asset.
+    pub fn house_balances(&self) -> impl Iterator<Item = (Asset, HouseBalance)> + '_ {
+        self.house.iter().map(|(asset, balance)| (*asset, *balance))
+    }
+
+    /// The latest timestamp of any request the exchange has accepted, up to which the deficits
+    /// clients are still in are measured.
+    pub fn latest_timestamp(&self) -> Option<Timestamp> {
+        self.latest
+    }
+
+    /// Keep transactions in `store`, such as one created with
+    /// [`TransactionStore::spill_to_disk`], moving any already recorded into it.
+    pub fn set_transaction_store(&mut self, store: TransactionStore) -> std::io::Result<()> {
+        store.extend_from(&self.transactions)?;
+        self.transactions = Arc::new(store);
+        Ok(())
+    }
+
+    /// An empty exchange that records everything that happens to it in an [`EventLog`].
+    pub fn with_event_log() -> Self {
+        Self {
+            events: Some(EventLog::new()),
+            ..Self::new()
+        }
+    }
+
+    /// Record everything that happens to the exchange from now on in an [`EventLog`], if it isn't
+    /// already, such as for an exchange loaded from a snapshot. Unlike the log of an exchange
+    /// created with [`Exchange::with_event_log`], the log can't be replayed by itself unless the
+    /// exchange was empty.
+    pub fn start_event_log(&mut self) {
+        self.events.get_or_insert_with(EventLog::new);
+    }
+
+    /// Rebuild an exchange by applying the state changes in `log`, ending up in exactly the state
+    /// of the exchange that recorded it. The rebuilt exchange carries on recording to `log`.
+    ///
+    /// Fails if `log` refers to a client or transaction before it was created.
+    ///
+    /// Transactions expired by a [`DisputeWindow`] are expired again, but the rebuilt exchange
+    /// has no dispute window of its own. Conversions are replayed at the rates they were applied
+    /// at, so no rate table is needed either.
+    pub fn replay(log: EventLog) -> Result<Self> {
+        let mut exchange = Exchange::new();
+        // Deficits are tracked once every change caused by a request has been applied, as they
+        // are when the request is processed
+        let mut timestamp = None;
+        let mut changes = Vec::new();
+        for event in log.events() {
+            exchange.apply_event(event)?;
+            match &event.kind {
+                EventKind::Accepted(request) => {
+                    exchange.track_deficits(timestamp, changes.drain(..));
+                    timestamp = request.timestamp;
+                }
+                EventKind::Changed(change) => changes.push((event.client, change)),
+            }
+        }
+        exchange.track_deficits(timestamp, changes);
+        exchange.events = Some(log);
+        Ok(exchange)
+    }
+
+    /// Save the full state of the exchange to `wtr` as a versioned snapshot, from which
+    /// [`Exchange::load_snapshot`] can carry on processing later.
+    ///
+    /// The event log, dispute window, rate table, fee schedule and claim policy aren't part of the
+    /// snapshot, but which transactions are in each client's window is.
+    pub fn save_snapshot<W: std::io::Write>(
+        &self,
+        wtr: W,
+    ) -> std::result::Result<(), SnapshotError> {
+        let mut transactions = self.transactions.to_vec()?;
+        transactions.sort_by_key(|(transaction_id, _)| *transaction_id);
+
+        let mut client_transactions = HashMap::<ClientId, Vec<TransactionRecord>>::new();
+        for (transaction_id, info) in &transactions {
+            client_transactions
+                .entry(info.client)
+                .or_default()
+                .push(TransactionRecord::new(
+                    *transaction_id,
+                    info.request,
+                    info.asset,
+                    info.fee,
+                    info.claim,
+                    info.timestamp,
+                ));
+        }
+
+        let mut clients = self
+            .clients
+            .iter()
+            .map(|(client_id, client)| ClientRecord {
+                client: *client_id,
+                balances: client
+                    .balances
+                    .iter()
+                    .map(|(asset, balance)| BalanceRecord {
+                        asset: *asset,
+                        available: balance.available,
+                        held: balance.held,
+                    })
+                    .collect(),
+                status: client.status,
+                transactions: client_transactions.remove(client_id).unwrap_or_default(),
+                recent: client
+                    .recent
+                    .iter()
+                    .map(|(transaction_id, _)| *transaction_id)
+                    .collect(),
+                overdue: sorted(&client.overdue),
+                deficits: client.deficits.clone(),
+                limits: client
+                    .limits
+                    .iter()
+                    .map(|(asset, limit)| LimitRecord {
+                        asset: *asset,
+                        limit: *limit,
+                    })
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        clients.sort_by_key(|record| record.client);
+
+        let mut owners = transactions
+            .iter()
+            .map(|(transaction_id, info)| (*transaction_id, info.client))
+            .chain(self.transactions.expired_to_vec()?)
+            .map(|(tx, client)| OwnerRecord { tx, client })
+            .collect::<Vec<_>>();
+        owners.sort_by_key(|record| record.tx);
+
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            clients,
+            owners,
+            house: self
+                .house_balances()
+                .map(|(asset, balance)| HouseRecord {
+                    asset,
+                    fees: balance.fees,
+                    chargebacks: balance.chargebacks,
+                    conversions: balance.conversions,
+                })
+                .collect(),
+            latest: self.latest,
+        };
+        write_snapshot(wtr, &snapshot)
+    }
+
+    /// Load an exchange from a snapshot written by [`Exchange::save_snapshot`].
+    pub fn load_snapshot<R: std::io::Read>(rdr: R) -> std::result::Result<Self, SnapshotError> {
+        let snapshot = read_snapshot(rdr)?;
+
+        // Ownership is the same as which client each transaction is recorded against, so the
+        // owners in the snapshot are only needed for transactions that have expired
+        let mut exchange = Exchange::new();
+        for record in snapshot.clients {
+            for transaction in &record.transactions {
+                let info = TransactionInformation {
+                    client: record.client,
+                    request: transaction.request(),
+                    asset: transaction.asset,
+                    fee: transaction.fee,
+                    claim: transaction.claim,
+                    timestamp: transaction.timestamp,
+                };
+                exchange.transactions.insert(transaction.tx, info)?;
+            }
+
+            let timestamps = record
+                .transactions
+                .iter()
+                .map(|transaction| (transaction.tx, transaction.timestamp))
+                .collect::<HashMap<_, _>>();
+            let recent = record
+                .recent
+                .into_iter()
+                .map(|transaction_id| {
+                    let timestamp = timestamps.get(&transaction_id).copied().flatten();
+                    (transaction_id, timestamp)
+                })
+                .collect();
+
+            let client = Client {
+                balances: record
+                    .balances
+                    .into_iter()
+                    .map(|balance| {
+                        let amounts = Balance {
+                            available: balance.available,
+                            held: balance.held,
+                        };
+                        (balance.asset, amounts)
+                    })
+                    .collect(),
+                status: record.status,
+                recent,
+                overdue: record.overdue.into_iter().collect(),
+                deficits: record.deficits,
+                limits: record
+                    .limits
+                    .into_iter()
+                    .map(|record| (record.asset, record.limit))
+                    .collect(),
+            };
+            exchange.clients.insert(record.client, client);
+        }
+
+        exchange.latest = snapshot.latest;
+        exchange.house = snapshot
+            .house
+            .into_iter()
+            .map(|record| {
+                let balance = HouseBalance {
+                    fees: record.fees,
+                    chargebacks: record.chargebacks,
+                    conversions: record.conversions,
+                };
+                (record.asset, balance)
+            })
+            .collect();
+
+        for owner in snapshot.owners {
+            if exchange.transactions.get(owner.tx)?.is_none() {
+                exchange.transactions.expire(owner.tx, owner.client)?;
+            }
+        }
+
+        Ok(exchange)
+    }
+
+    /// Pass the balance of every client to `sink`, in ascending order of client ID and then of
+    /// asset.
+    pub fn write_balances(
+        &self,
+        sink: &mut dyn BalanceSink,
+    ) -> std::result::Result<(), ProcessError> {
+        // Sort clients by client_id for deterministic output.
+        // Could have instead used a BTreeMap in the exchange to maintain a sorted map but that reduces performance.
+        // Also could use the `indexmap` crate for a map that maintains insertion order.
+        let mut sorted_clients = self.clients().collect::<Vec<_>>();
+        sorted_clients.sort_by_key(|client| client.id());
+
+        let mut snapshots = sorted_clients
+            .iter()
+            .flat_map(|client| client.snapshots())
+            .collect::<Vec<_>>();
+        // Give every balance a headroom if any has one, so that CSV rows have the same columns
+        if snapshots.iter().any(|snapshot| snapshot.headroom.is_some()) {
+            for snapshot in &mut snapshots {
+                snapshot.headroom.get_or_insert(snapshot.available);
+            }
+        }
+        let assets = snapshots
+            .iter()
+            .map(|snapshot| snapshot.asset)
+            .collect::<BTreeSet<_>>();
+
+        sink.start(&assets.into_iter().collect::<Vec<_>>())?;
+        for snapshot in &snapshots {
+            sink.write(snapshot)?;
         }
+
+        sink.finish()
+    }
+
+    /// Write the house report as CSV, with a row for every asset held by a client or the house in
+    /// ascending order of asset, so that what the exchange owes its clients can be reconciled
+    /// against what it holds.
+    ///
+    /// The columns are the `asset`, which is empty for the default asset, the total of every
+    /// client's balance in it as `clients`, the `fees`, `chargebacks` and `conversions` accounts
+    /// of the house and their total as `house`, and `holdings`, the clients' total plus the
+    /// house's. Holdings are the funds deposited less the funds withdrawn.
+    pub fn write_house_report<W: std::io::Write>(
+        &self,
+        wtr: W,
+    ) -> std::result::Result<(), ProcessError> {
+        let overflow = || ProcessError::Overflow("the house report");
+        let mut clients = BTreeMap::<Asset, MonetaryAmount>::new();
+        for client in self.clients.values() {
+            for (asset, balance) in &client.balances {
+                let total = clients.entry(*asset).or_default();
+                *total = balance
+                    .available
+                    .checked_add(balance.held)
+                    .and_then(|balance| total.checked_add(balance))
+                    .ok_or_else(overflow)?;
+            }
+        }
+        let assets = clients
+            .keys()
+            .chain(self.house.keys())
+            .copied()
+            .collect::<BTreeSet<_>>();
+
+        let rows = assets
+            .into_iter()
+            .map(|asset| {
+                let clients = clients.get(&asset).copied().unwrap_or_default();
+                let house = self.house.get(&asset).copied().unwrap_or_default();
+                let total = house.total().map_err(|_| overflow())?;
+                Ok(HouseReportRow {
+                    asset,
+                    clients,
+                    fees: house.fees,
+                    chargebacks: house.chargebacks,
+                    conversions: house.conversions,
+                    house: total,
+                    holdings: clients.checked_add(total).ok_or_else(overflow)?,
+                })
+            })
+            .collect::<std::result::Result<Vec<_>, ProcessError>>()?;
+        write_house_report(wtr, &rows)
+    }
+
+    /// Write the collections report as CSV, with a row for every deficit a client has been in, in
+    /// ascending order of client and then in the order they began, so that what clients owe the
+    /// exchange can be recovered.
+    ///
+    /// The columns are the `client`, the `asset`, which is empty for the default asset, the
+    /// client's `available` and `total` funds in it now, the `peak` of the deficit, its `since`
+    /// and `until` timestamps, its `duration_ms`, measured up to [`Exchange::latest_timestamp`]
+    /// for a deficit that hasn't `recovered`, whether it has, and the IDs of the `transactions`
+    /// that caused it, separated by spaces. Timestamps and durations are empty when unknown.
+    pub fn write_collections_report<W: std::io::Write>(
+        &self,
+        wtr: W,
+    ) -> std::result::Result<(), ProcessError> {
+        let mut clients = self.clients().collect::<Vec<_>>();
+        clients.sort_by_key(|client| client.id());
+
+        let rows = clients
+            .iter()
+            .flat_map(|client| {
+                client.deficits().iter().map(|deficit| {
+                    let balance = client.balance(deficit.asset);
+                    let transactions = deficit
+                        .transactions
+                        .iter()
+                        .map(|transaction| transaction.0.to_string())
+                        .collect::<Vec<_>>();
+                    CollectionsRow {
+                        client: client.id(),
+                        asset: deficit.asset,
+                        available: balance.available,
+                        total: balance.total(),
+                        peak: deficit.peak,
+                        since: deficit.since.map(|since| since.to_string()),
+                        until: deficit.until.map(|until| until.to_string()),
+                        duration_ms: deficit
+                            .duration(self.latest)
+                            .map(|duration| duration.as_millis()),
+                        recovered: deficit.recovered,
+                        transactions: transactions.join(" "),
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        write_collections_report(wtr, &rows)
+    }
+
+    /// The events recorded by an exchange created with [`Exchange::with_event_log`].
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.events.as_ref()
     }
 
+    /// Apply a single request.
     pub fn process_transaction(&mut self, request: TransactionRequest) -> Result<()> {
+        let stored = self
+            .transactions
+            .get(request.transaction)
+            .map_err(ProcessTransactionError::Storage)?;
+
         match request.request_type {
             RequestType::Monetary(transaction) => {
-                if self.transactions.contains_key(&request.transaction) {
+                let expired = self
+                    .transactions
+                    .expired_owner(request.transaction)
+                    .map_err(ProcessTransactionError::Storage)?;
+                if stored.is_some() || expired.is_some() {
                     return Err(ProcessTransactionError::DuplicateTransaction);
                 }
 
-                let client = self.clients.entry(request.client).or_insert(Client::new());
+                let created = !self.clients.contains_key(&request.client);
+                self.clients.entry(request.client).or_insert(Client::new());
 
-                client.process_monetary_request(request.transaction, transaction)?;
-
-                self.transactions
-                    .insert(request.transaction, request.client);
-
-                Ok(())
+                let changes = self.process_monetary_request(request, transaction);
+                self.commit(request, created, changes)
             }
-            RequestType::Claim(claim_type) => {
-                let transaction_owner = self
-                    .transactions
-                    .get(&request.transaction)
-                    .copied()
-                    .ok_or(ProcessTransactionError::TransactionNotFound)?;
+            RequestType::Claim(claim) => {
+                let Some(info) = stored else {
+                    // Ownership is still checked first, so that the answer doesn't depend on
+                    // whether the transaction has expired yet
+                    let expired = self
+                        .transactions
+                        .expired_owner(request.transaction)
+                        .map_err(ProcessTransactionError::Storage)?;
+                    return Err(match expired {
+                        Some(owner) if owner != request.client => {
+                            ProcessTransactionError::Unauthorized
+                        }
+                        Some(_) => ProcessTransactionError::DisputeWindowExpired,
+                        None => ProcessTransactionError::TransactionNotFound,
+                    });
+                };
 
-                if transaction_owner != request.client {
+                if info.client != request.client {
                     return Err(ProcessTransactionError::Unauthorized);
                 }
+                if claim.claim_type == ClaimType::Dispute
+                    && self.outside_dispute_window(&info, request.timestamp)
+                {
+                    return Err(ProcessTransactionError::DisputeWindowExpired);
+                }
 
                 let client = self
                     .clients
                     .get_mut(&request.client)
                     .ok_or(ProcessTransactionError::ClientNotFound)?;
 
-                client.process_claim(request.transaction, claim_type)?;
+                let changes = client.process_claim(
+                    request.transaction,
+                    &info,
+                    claim,
+                    self.claim_policy.as_ref(),
+                    self.fees.disputed_fees(),
+                    &self.house,
+                );
+                let changes = self.expire_transactions(request.client, request.timestamp, changes);
+                self.commit(request, false, attribute(request.client, changes))
+            }
+            RequestType::Admin(admin) => {
+                if !self.operators.contains(&admin.operator) {
+                    return Err(ProcessTransactionError::UnauthorizedOperator);
+                }
+                let client = self
+                    .clients
+                    .get_mut(&request.client)
+                    .ok_or(ProcessTransactionError::ClientNotFound)?;
 
-                Ok(())
+                let changes = client.process_admin(request.transaction, request.asset, admin);
+                self.commit(request, false, attribute(request.client, changes))
             }
         }
     }
 
-    pub fn get_clients(&self) -> &HashMap<ClientId, Client> {
-        &self.clients
+    /// Apply a deposit, withdrawal, transfer or conversion for a client that exists. A transfer is
+    /// applied to both clients or to neither, and a conversion to both assets or to neither.
+    fn process_monetary_request(
+        &mut self,
+        request: TransactionRequest,
+        transaction: MonetaryTransaction,
+    ) -> Result<Vec<(ClientId, StateChange)>> {
+        // Everything that could stop the destination or target asset being credited is checked
+        // before the sender is debited
+        let credit = match transaction {
+            MonetaryTransaction::Transfer {
+                amount,
+                destination,
+            } => {
+                let receiver = self
+                    .clients
+                    .get(&destination)
+                    .ok_or(ProcessTransactionError::DestinationNotFound)?;
+                Some((
+                    destination,
+                    receiver.receive_transfer(request.transaction, request.asset, amount)?,
+                ))
+            }
+            MonetaryTransaction::Convert { amount, to } => {
+                let rate = self.rates.rate(request.asset, to, request.timestamp)?;
+                // Rounded the same way as the amounts of requests
+                let converted = amount
+                    .checked_mul(rate)
+                    .ok_or(ProcessTransactionError::Overflow)?
+                    .round_dp(4);
+                if converted.is_zero() {
+                    return Err(ProcessTransactionError::ConversionTooSmall);
+                }
+                let client = self
+                    .clients
+                    .get(&request.client)
+                    .ok_or(ProcessTransactionError::ClientNotFound)?;
+                let mut credit = client.credit(request.transaction, to, converted)?;
+                // The house takes the funds converted and pays out the funds they convert into
+                credit.extend([
+                    StateChange::HouseChanged {
+                        transaction: request.transaction,
+                        asset: request.asset,
+                        account: HouseAccount::Conversions,
+                        amount,
+                    },
+                    StateChange::HouseChanged {
+                        transaction: request.transaction,
+                        asset: to,
+                        account: HouseAccount::Conversions,
+                        amount: -converted,
+                    },
+                ]);
+                check_house(&self.house, &credit)?;
+                Some((request.client, credit))
+            }
+            _ => None,
+        };
+
+        let limit = self
+            .client(request.client)
+            .ok_or(ProcessTransactionError::ClientNotFound)?
+            .credit_limit(request.asset);
+        let client = self
+            .clients
+            .get_mut(&request.client)
+            .ok_or(ProcessTransactionError::ClientNotFound)?;
+        let changes = client.process_monetary_request(
+            request.transaction,
+            transaction,
+            request.asset,
+            request.timestamp,
+            self.fees.fees(request.client),
+            limit,
+            &self.house,
+        );
+        let mut changes = attribute(
+            request.client,
+            self.expire_transactions(request.client, request.timestamp, changes),
+        )?;
+
+        if let Some((credited, credit)) = credit {
+            let receiver = self
+                .clients
+                .get_mut(&credited)
+                .ok_or(ProcessTransactionError::ClientNotFound)?;
+            changes.extend(attribute(credited, receiver.apply_changes(credit))?);
+        }
+        Ok(changes)
     }
-}
 
-pub struct Client {
-    pub available: MonetaryAmount,
-    pub held: MonetaryAmount,
-    pub locked: bool,
-    transactions: HashMap<TransactionId, TransactionInformation>,
-}
+    /// Keep track of which of the client's transactions are within the dispute window as
+    /// `changes` record new ones and settle disputes and as time passes, adding a
+    /// [`StateChange::TransactionExpired`] for every transaction that leaves it.
+    fn expire_transactions(
+        &mut self,
+        client_id: ClientId,
+        now: Option<Timestamp>,
+        changes: Result<Vec<StateChange>>,
+    ) -> Result<Vec<StateChange>> {
+        let mut changes = changes?;
+        let Some(window) = self.dispute_window else {
+            return Ok(changes);
+        };
+        let client = self
+            .clients
+            .get_mut(&client_id)
+            .ok_or(ProcessTransactionError::ClientNotFound)?;
 
-struct TransactionInformation {
-    request: MonetaryTransaction,
-    /// We only need to keep track when there is a dispute or chargeback.
-    /// When a claim is un-disputed or resolved, we can go back to the None state
-    claim: Option<ClaimState>,
-}
+        for change in &changes {
+            if let StateChange::TransactionRecorded {
+                transaction,
+                timestamp,
+                ..
+            } = *change
+            {
+                // Transactions without a timestamp never leave a window of time
+                if matches!(window, DisputeWindow::Transactions(_)) || timestamp.is_some() {
+                    client.recent.push_back((transaction, timestamp));
+                }
+            }
+        }
 
-enum ClaimState {
-    Disputed,
-    Chargebacked,
-}
+        let mut leaving = Vec::new();
+        while let Some((transaction, timestamp)) = client.recent.front().copied() {
+            let left = match window {
+                DisputeWindow::Transactions(limit) => client.recent.len() > limit.get(),
+                DisputeWindow::Duration(duration) => match (timestamp, now) {
+                    (Some(timestamp), Some(now)) => timestamp < now.saturating_sub(duration),
+                    _ => false,
+                },
+            };
+            if !left {
+                break;
+            }
+            client.recent.pop_front();
+            leaving.push(transaction);
+        }
 
-impl Client {
-    fn new() -> Self {
-        Self {
-            available: MonetaryAmount::ZERO,
-            held: MonetaryAmount::ZERO,
-            locked: false,
-            transactions: HashMap::new(),
+        let mut expired = Vec::new();
+        for transaction in leaving {
+            // The funds held by an open dispute must still be released, so the transaction is
+            // kept until the dispute is settled
+            let claim = match claim_after(&changes, transaction) {
+                Some(claim) => claim,
+                None => self
+                    .transactions
+                    .get(transaction)
+                    .map_err(ProcessTransactionError::Storage)?
+                    .map(|info| info.claim)
+                    .unwrap_or_default(),
+            };
+            if claim.is_disputed() {
+                client.overdue.insert(transaction);
+            } else {
+                expired.push(transaction);
+            }
+        }
+
+        for change in &changes {
+            if let StateChange::ClaimChanged { transaction, claim } = *change
+                && !claim.is_disputed()
+                && client.overdue.remove(&transaction)
+            {
+                expired.push(transaction);
+            }
         }
+
+        changes.extend(
+            expired
+                .into_iter()
+                .map(|transaction| StateChange::TransactionExpired { transaction }),
+        );
+        Ok(changes)
     }
 
-    fn process_monetary_request(
+    /// Whether a transaction is too old to dispute at `now`. Transactions still waiting to be
+    /// expired can be found by a claim before the window moves on, which this catches.
+    fn outside_dispute_window(
+        &self,
+        info: &TransactionInformation,
+        now: Option<Timestamp>,
+    ) -> bool {
+        match (self.dispute_window, info.timestamp, now) {
+            (Some(DisputeWindow::Duration(duration)), Some(timestamp), Some(now)) => {
+                timestamp < now.saturating_sub(duration)
+            }
+            _ => false,
+        }
+    }
+
+    /// Apply the changes caused by `request` to the transaction store, the clients they belong to
+    /// having applied their own already, and record them if events are being recorded.
+    fn commit(
         &mut self,
-        transaction_id: TransactionId,
-        transaction: MonetaryTransaction,
+        request: TransactionRequest,
+        created: bool,
+        changes: Result<Vec<(ClientId, StateChange)>>,
     ) -> Result<()> {
-        if self.locked {
-            return Err(ProcessTransactionError::ClientLocked);
+        for (client, change) in changes.iter().flatten() {
+            self.apply_to_exchange(*client, change)?;
+        }
+        if let Ok(changes) = &changes {
+            self.latest = self.latest.max(request.timestamp);
+            let changes = changes.iter().map(|(client, change)| (*client, change));
+            self.track_deficits(request.timestamp, changes);
         }
 
-        match transaction {
-            MonetaryTransaction::Deposit(amount) => {
-                self.available = self
-                    .available
-                    .checked_add(amount)
-                    .ok_or(ProcessTransactionError::Overflow)?;
+        if let Some(events) = self.events.as_mut() {
+            if changes.is_ok() {
+                events.append(request.client, EventKind::Accepted(request));
             }
-            MonetaryTransaction::Withdrawal(amount) => {
-                if self.available < amount {
-                    return Err(ProcessTransactionError::InsufficientFunds);
-                }
-                self.available = self
-                    .available
-                    .checked_sub(amount)
-                    .ok_or(ProcessTransactionError::Overflow)?;
+            // A client is created even if the request that created it is rejected
+            if created {
+                events.append(
+                    request.client,
+                    EventKind::Changed(StateChange::ClientCreated),
+                );
+            }
+            for (client, change) in changes.iter().flatten() {
+                events.append(*client, EventKind::Changed(change.clone()));
             }
         }
 
-        self.transactions.insert(
-            transaction_id,
-            TransactionInformation {
-                request: transaction,
-                claim: None,
-            },
-        );
-        Ok(())
+        changes.map(|_| ())
     }
 
-    fn process_claim(
+    /// Apply the part of `change` that's kept by the exchange rather than by the client: the
+    /// transaction store and the house account.
+    fn apply_to_exchange(&mut self, client: ClientId, change: &StateChange) -> Result<()> {
+        match *change {
+            StateChange::TransactionRecorded {
+                transaction,
+                request,
+                asset,
+                fee,
+                timestamp,
+            } => {
+                let info = TransactionInformation {
+                    client,
+                    request,
+                    asset,
+                    fee,
+                    claim: ClaimState::default(),
+                    timestamp,
+                };
+                self.transactions
+                    .insert(transaction, info)
+                    .map_err(ProcessTransactionError::Storage)
+            }
+            StateChange::ClaimChanged { transaction, claim } => {
+                let exists = self
+                    .transactions
+                    .set_claim(transaction, claim)
+                    .map_err(ProcessTransactionError::Storage)?;
+                exists
+                    .then_some(())
+                    .ok_or(ProcessTransactionError::TransactionNotFound)
+            }
+            StateChange::TransactionExpired { transaction } => self
+                .transactions
+                .expire(transaction, client)
+                .map_err(ProcessTransactionError::Storage),
+            StateChange::HouseChanged {
+                asset,
+                account,
+                amount,
+                ..
+            } => {
+                let balance = self.house.entry(asset).or_default().account_mut(account);
+                *balance = balance
+                    .checked_add(amount)
+                    .ok_or(ProcessTransactionError::Overflow)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Keep track of when clients' funds go below zero and back up, once `changes`, caused by a
+    /// request with timestamp `at`, have all been applied.
+    fn track_deficits<'a>(
         &mut self,
-        transaction_id: TransactionId,
-        claim_type: ClaimType,
-    ) -> Result<()> {
-        if self.locked {
-            return Err(ProcessTransactionError::ClientLocked);
+        at: Option<Timestamp>,
+        changes: impl IntoIterator<Item = (ClientId, &'a StateChange)>,
+    ) {
+        for (client_id, change) in changes {
+            if let StateChange::BalanceChanged {
+                transaction,
+                asset,
+                available,
+                held,
+            } = *change
+                && let Some(client) = self.clients.get_mut(&client_id)
+            {
+                let lowered =
+                    available < MonetaryAmount::ZERO || available + held < MonetaryAmount::ZERO;
+                client.track_deficit(asset, transaction, lowered, at);
+            }
         }
+    }
 
-        let transaction_info = self
-            .transactions
-            .get_mut(&transaction_id)
-            .ok_or(ProcessTransactionError::TransactionNotFound)?;
+    fn apply_event(&mut self, event: &Event) -> Result<()> {
+        let change = match &event.kind {
+            EventKind::Accepted(request) => {
+                self.latest = self.latest.max(request.timestamp);
+                return Ok(());
+            }
+            EventKind::Changed(change) => change,
+        };
 
-        match claim_type {
-            ClaimType::Dispute => {
-                if transaction_info.claim.is_some() {
-                    return Err(ProcessTransactionError::InvalidOperation(
-                        "Transaction already disputed",
-                    ));
-                }
-                match transaction_info.request {
-                    MonetaryTransaction::Deposit(amount) => {
-                        self.held += amount;
-                        self.available -= amount;
-                    }
-                    MonetaryTransaction::Withdrawal(_) => {
-                        // No change to available funds, just mark as disputed
-                    }
-                };
-                transaction_info.claim = Some(ClaimState::Disputed);
-            }
-            ClaimType::Resolve => {
-                if let Some(ClaimState::Disputed) = transaction_info.claim {
-                    match transaction_info.request {
-                        MonetaryTransaction::Deposit(amount) => {
-                            self.held -= amount;
-                            self.available += amount;
-                        }
-                        MonetaryTransaction::Withdrawal(_) => {
-                            // No change to available funds as we'd only just marked as disputed
-                        }
-                    };
-                    transaction_info.claim = None;
-                } else {
-                    return Err(ProcessTransactionError::InvalidOperation(
-                        "No dispute to resolve",
-                    ));
-                }
+        match change {
+            StateChange::ClientCreated => {
+                self.clients.entry(event.client).or_insert(Client::new());
+                Ok(())
             }
-            ClaimType::Chargeback => {
-                if let Some(ClaimState::Disputed) = transaction_info.claim {
-                    match transaction_info.request {
-                        MonetaryTransaction::Deposit(amount) => {
-                            self.held -= amount;
-                        }
-                        MonetaryTransaction::Withdrawal(amount) => {
-                            self.available += amount;
-                        }
-                    };
-                    transaction_info.claim = Some(ClaimState::Chargebacked);
-                    self.locked = true;
-                } else {
-                    return Err(ProcessTransactionError::InvalidOperation(
-                        "No dispute to chargeback",
-                    ));
+            change => {
+                self.clients
+                    .get_mut(&event.client)
+                    .ok_or(ProcessTransactionError::ClientNotFound)?
+                    .apply_change(change)?;
+                self.apply_to_exchange(event.client, change)
+            }
+        }
+    }
+
+    /// Split the exchange into `count` exchanges holding disjoint sets of clients, chosen by
+    /// `shard_of`, along with the state that the shards don't own. Every shard shares the same
+    /// transaction store, and records events if this exchange does.
+    pub(crate) fn into_shards(
+        self,
+        count: usize,
+        shard_of: impl Fn(ClientId) -> usize,
+    ) -> (Vec<Exchange>, Unsharded) {
+        let mut shards = (0..count)
+            .map(|_| Exchange {
+                events: self.events.as_ref().map(|_| EventLog::new()),
+                dispute_window: self.dispute_window,
+                rates: Arc::clone(&self.rates),
+                fees: Arc::clone(&self.fees),
+                operators: Arc::clone(&self.operators),
+                claim_policy: Arc::clone(&self.claim_policy),
+                credit_limits: Arc::clone(&self.credit_limits),
+                ..Exchange::new()
+            })
+            .collect::<Vec<_>>();
+        for (client_id, client) in self.clients {
+            shards[shard_of(client_id)]
+                .clients
+                .insert(client_id, client);
+        }
+        for shard in &mut shards {
+            shard.transactions = Arc::clone(&self.transactions);
+        }
+        let unsharded = Unsharded {
+            transactions: self.transactions,
+            events: self.events,
+            dispute_window: self.dispute_window,
+            rates: self.rates,
+            fees: self.fees,
+            operators: self.operators,
+            claim_policy: self.claim_policy,
+            credit_limits: self.credit_limits,
+            house: self.house,
+            latest: self.latest,
+        };
+        (shards, unsharded)
+    }
+
+    /// Reverse of [`Exchange::into_shards`]. Events recorded by the shards are discarded, as
+    /// they're expected to have been moved to `unsharded` in order with
+    /// [`Exchange::take_events`].
+    ///
+    /// Fails with [`ProcessError::Overflow`] if the shards' house accounts don't fit once they're
+    /// added up. Each shard only checked its own entries, which fit on their own.
+    pub(crate) fn from_shards(
+        shards: impl IntoIterator<Item = Exchange>,
+        unsharded: Unsharded,
+    ) -> std::result::Result<Self, ProcessError> {
+        let mut exchange = Exchange::new();
+        exchange.house = unsharded.house;
+        exchange.latest = unsharded.latest;
+        for shard in shards {
+            exchange.clients.extend(shard.clients);
+            exchange.latest = exchange.latest.max(shard.latest);
+            for (asset, balance) in shard.house {
+                let house = exchange.house.entry(asset).or_default();
+                for account in [
+                    HouseAccount::Fees,
+                    HouseAccount::Chargebacks,
+                    HouseAccount::Conversions,
+                ] {
+                    let total = house.account_mut(account);
+                    *total = total
+                        .checked_add(*balance.account(account))
+                        .ok_or(ProcessError::Overflow("the house account"))?;
                 }
             }
         }
+        exchange.transactions = unsharded.transactions;
+        exchange.events = unsharded.events;
+        exchange.dispute_window = unsharded.dispute_window;
+        exchange.rates = unsharded.rates;
+        exchange.fees = unsharded.fees;
+        exchange.operators = unsharded.operators;
+        exchange.claim_policy = unsharded.claim_policy;
+        exchange.credit_limits = unsharded.credit_limits;
+        Ok(exchange)
+    }
 
-        Ok(())
+    /// Move a client out of this exchange, so that another shard can apply a transfer to it.
+    pub(crate) fn lend_client(&mut self, client_id: ClientId) -> LentClient {
+        LentClient {
+            id: client_id,
+            client: self.clients.remove(&client_id),
+        }
+    }
+
+    /// Put back a client moved out by [`Exchange::lend_client`], in this exchange or another.
+    pub(crate) fn restore_client(&mut self, lent: LentClient) {
+        if let Some(client) = lent.client {
+            self.clients.insert(lent.id, client);
+        }
+    }
+
+    /// Take the events recorded since the last call, if events are being recorded.
+    pub(crate) fn take_events(&mut self) -> Option<EventLog> {
+        self.events.as_mut().map(std::mem::take)
+    }
+
+    pub fn client(&self, client_id: ClientId) -> Option<ClientView<'_>> {
+        self.clients
+            .get(&client_id)
+            .map(|client| ClientView::new(client_id, client, &self.credit_limits))
+    }
+
+    /// Every client known to the exchange, in no particular order.
+    pub fn clients(&self) -> impl Iterator<Item = ClientView<'_>> {
+        self.clients
+            .iter()
+            .map(|(client_id, client)| ClientView::new(*client_id, client, &self.credit_limits))
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rust_decimal::{Decimal, dec};
+/// Pair each of `changes` with the client it applies to.
+fn attribute(
+    client: ClientId,
+    changes: Result<Vec<StateChange>>,
+) -> Result<Vec<(ClientId, StateChange)>> {
+    Ok(changes?
+        .into_iter()
+        .map(|change| (client, change))
+        .collect())
+}
 
-    #[test]
-    fn test_deposit_increases_available_balance() {
-        let mut client = Client::new();
-        let transaction_id = TransactionId(1);
-        let deposit_amount = dec!(1.234);
+/// The claim against `transaction` once `changes` are applied, if they change it.
+fn claim_after(changes: &[StateChange], transaction: TransactionId) -> Option<ClaimState> {
+    changes.iter().rev().find_map(|change| match *change {
+        StateChange::ClaimChanged {
+            transaction: changed,
+            claim,
+        } if changed == transaction => Some(claim),
+        _ => None,
+    })
+}
 
-        let result = client
-            .process_monetary_request(transaction_id, MonetaryTransaction::Deposit(deposit_amount));
+/// How much of a transaction can be claimed against: the amount of a withdrawal, or of a deposit
+/// less its fee, which the client was never credited with. Both clients agreed to a transfer, so a
+/// mistaken one is undone with another, and likewise a conversion the client made within their
+/// own account.
+fn claimable(info: &TransactionInformation) -> Result<MonetaryAmount> {
+    match info.request {
+        MonetaryTransaction::Deposit(amount) => Ok(amount - info.fee),
+        MonetaryTransaction::Withdrawal(amount) => Ok(amount),
+        MonetaryTransaction::Transfer { .. } | MonetaryTransaction::Convert { .. } => {
+            Err(ProcessTransactionError::NotDisputable)
+        }
+    }
+}
 
-        assert!(result.is_ok());
-        assert_eq!(client.available, deposit_amount);
-        assert_eq!(client.held, Decimal::ZERO);
-        assert!(!client.locked);
-        assert!(client.transactions.contains_key(&transaction_id));
+fn sorted(transactions: &HashSet<TransactionId>) -> Vec<TransactionId> {
+    let mut transactions = transactions.iter().copied().collect::<Vec<_>>();
+    transactions.sort();
+    transactions
+}
+
+/// State of an [`Exchange`] that isn't split between the shards of a
+/// [`ShardedExchange`](crate::ShardedExchange).
+pub(crate) struct Unsharded {
+    pub(crate) transactions: Arc<TransactionStore>,
+    pub(crate) events: Option<EventLog>,
+    pub(crate) dispute_window: Option<DisputeWindow>,
+    pub(crate) rates: Arc<RateTable>,
+    pub(crate) fees: Arc<FeeSchedule>,
+    pub(crate) operators: Arc<HashSet<OperatorId>>,
+    pub(crate) claim_policy: Arc<dyn ClaimPolicy>,
+    pub(crate) credit_limits: Arc<CreditLimits>,
+    pub(crate) house: BTreeMap<Asset, HouseBalance>,
+    pub(crate) latest: Option<Timestamp>,
+}
+
+/// A client moved between the shards of a [`ShardedExchange`](crate::ShardedExchange), or its
+/// absence if the shard didn't have it.
+pub(crate) struct LentClient {
+    id: ClientId,
+    client: Option<Client>,
+}
+
+/// Read-only view of a client's account.
+#[derive(Clone, Copy)]
+pub struct ClientView<'a> {
+    id: ClientId,
+    client: &'a Client,
+    credit_limits: &'a CreditLimits,
+}
+
+impl<'a> ClientView<'a> {
+    fn new(id: ClientId, client: &'a Client, credit_limits: &'a CreditLimits) -> Self {
+        Self {
+            id,
+            client,
+            credit_limits,
+        }
     }
 
-    #[test]
-    fn test_multiple_deposits_accumulate() {
-        let mut client = Client::new();
-        let first_deposit = dec!(0.5000);
-        let second_deposit = dec!(0.7500);
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
 
-        client
-            .process_monetary_request(
-                TransactionId(1),
-                MonetaryTransaction::Deposit(first_deposit),
-            )
-            .unwrap();
+    /// Funds available to withdraw in the default asset, see [`Balance::available`].
+    pub fn available(&self) -> MonetaryAmount {
+        self.balance(Asset::default()).available
+    }
 
-        client
-            .process_monetary_request(
-                TransactionId(2),
-                MonetaryTransaction::Deposit(second_deposit),
-            )
-            .unwrap();
+    /// Funds held in the default asset while a dispute is open.
+    pub fn held(&self) -> MonetaryAmount {
+        self.balance(Asset::default()).held
+    }
+
+    pub fn total(&self) -> MonetaryAmount {
+        self.balance(Asset::default()).total()
+    }
+
+    /// The client's balance in `asset`, which is zero if it has never held any.
+    pub fn balance(&self, asset: Asset) -> Balance {
+        self.client.balance(asset)
+    }
+
+    /// Every asset the client has held, in ascending order.
+    pub fn assets(&self) -> impl Iterator<Item = Asset> + 'a {
+        self.client.balances.keys().copied()
+    }
+
+    /// Whether the account can't make transactions, having been locked by a chargeback, frozen
+    /// or closed.
+    pub fn is_locked(&self) -> bool {
+        self.client.status != AccountStatus::Active
+    }
+
+    pub fn status(&self) -> AccountStatus {
+        self.client.status
+    }
+
+    /// Every time the client's funds in an asset have gone below zero, in the order they did.
+    pub fn deficits(&self) -> &'a [Deficit] {
+        &self.client.deficits
+    }
+
+    /// How far below zero the client's withdrawals can take their available funds in `asset`:
+    /// the limit an operator last gave them, or otherwise their limit in the exchange's
+    /// [`CreditLimits`], or zero.
+    pub fn credit_limit(&self, asset: Asset) -> MonetaryAmount {
+        self.client
+            .limits
+            .get(&asset)
+            .copied()
+            .unwrap_or_else(|| self.credit_limits.limit(self.id, asset).unwrap_or_default())
+    }
+
+    /// How much the client can still withdraw in `asset`, their available funds plus their
+    /// credit limit. Negative if a claim has taken them further below zero than their limit.
+    pub fn headroom(&self, asset: Asset) -> MonetaryAmount {
+        self.balance(asset).available + self.credit_limit(asset)
+    }
+
+    /// The client's balance in every asset it has held, in ascending order of asset. A client
+    /// that has never held anything has a single, empty balance in the default asset.
+    ///
+    /// Balances include the client's headroom if they have a credit limit in any of these assets.
+    pub fn snapshots(&self) -> Vec<ClientSnapshot> {
+        let credit = self
+            .client
+            .balances
+            .keys()
+            .chain([&Asset::default()])
+            .any(|asset| !self.credit_limit(*asset).is_zero());
+        let snapshot = |asset: Asset, balance: Balance| ClientSnapshot {
+            client_id: self.id(),
+            asset,
+            available: balance.available,
+            held: balance.held,
+            total: balance.total(),
+            locked: self.is_locked(),
+            headroom: credit.then(|| balance.available + self.credit_limit(asset)),
+        };
+
+        if self.client.balances.is_empty() {
+            return vec![snapshot(Asset::default(), Balance::default())];
+        }
+        self.client
+            .balances
+            .iter()
+            .map(|(asset, balance)| snapshot(*asset, *balance))
+            .collect()
+    }
+}
+
+/// A client's funds in a single asset.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    /// Funds available to withdraw. Can be negative if a deposit was disputed after being spent.
+    pub available: MonetaryAmount,
+    /// Funds held while a dispute is open.
+    pub held: MonetaryAmount,
+}
+
+impl Balance {
+    pub fn total(&self) -> MonetaryAmount {
+        self.available + self.held
+    }
+
+    /// The lower of the available and total funds, which is negative while the client is in
+    /// deficit.
+    fn lowest(&self) -> MonetaryAmount {
+        self.available.min(self.total())
+    }
+}
+
+/// A stretch of time during which a client's available or total funds in an asset were below
+/// zero, such as after a deposit they had already spent was disputed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deficit {
+    #[serde(default, skip_serializing_if = "Asset::is_default")]
+    pub asset: Asset,
+    /// The furthest below zero the client's available or total funds went.
+    pub peak: MonetaryAmount,
+    /// The transactions that took the client's funds below zero or further below it, in the order
+    /// they first did.
+    pub transactions: Vec<TransactionId>,
+    /// The timestamp of the request that started the deficit, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<Timestamp>,
+    /// Whether the client's funds have gone back up to zero or above.
+    #[serde(default)]
+    pub recovered: bool,
+    /// The timestamp of the request that ended the deficit, if it has ended and the request had
+    /// one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<Timestamp>,
+}
+
+impl Deficit {
+    /// How long the deficit lasted, or has lasted by `now` if it's still open. Unknown unless both
+    /// ends have a timestamp, and zero if they're out of order.
+    pub fn duration(&self, now: Option<Timestamp>) -> Option<std::time::Duration> {
+        let end = if self.recovered { self.until } else { now };
+        let (since, end) = self.since.zip(end)?;
+        let millis = end.as_millis().saturating_sub(since.as_millis());
+        Some(std::time::Duration::from_millis(
+            u64::try_from(millis).unwrap_or(0),
+        ))
+    }
+}
+
+/// The exchange's own funds in a single asset, kept in separate accounts.
+///
+/// Every change to a client's balances other than a deposit or withdrawal has its counter-entry
+/// here, so the clients' totals plus the house's always add up to the funds deposited less the
+/// funds withdrawn.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HouseBalance {
+    /// Fees collected from clients, less fees refunded.
+    pub fees: MonetaryAmount,
+    /// Funds taken back from clients by chargebacks of deposits, less funds given back to them by
+    /// chargebacks of withdrawals.
+    pub chargebacks: MonetaryAmount,
+    /// Funds clients converted out of this asset, less funds they converted into it.
+    pub conversions: MonetaryAmount,
+}
+
+impl HouseBalance {
+    /// The total of every account. Fails with [`ProcessTransactionError::Overflow`] if it's too
+    /// large, although each account isn't.
+    pub fn total(&self) -> Result<MonetaryAmount> {
+        self.fees
+            .checked_add(self.chargebacks)
+            .and_then(|total| total.checked_add(self.conversions))
+            .ok_or(ProcessTransactionError::Overflow)
+    }
+
+    fn account(&self, account: HouseAccount) -> &MonetaryAmount {
+        match account {
+            HouseAccount::Fees => &self.fees,
+            HouseAccount::Chargebacks => &self.chargebacks,
+            HouseAccount::Conversions => &self.conversions,
+        }
+    }
+
+    fn account_mut(&mut self, account: HouseAccount) -> &mut MonetaryAmount {
+        match account {
+            HouseAccount::Fees => &mut self.fees,
+            HouseAccount::Chargebacks => &mut self.chargebacks,
+            HouseAccount::Conversions => &mut self.conversions,
+        }
+    }
+}
+
+/// One of the accounts of a [`HouseBalance`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HouseAccount {
+    Fees,
+    Chargebacks,
+    Conversions,
+}
+
+/// Check that `house` can take every [`StateChange::HouseChanged`] in `changes` without
+/// overflowing, so that a request it can't take is refused before any of it is applied.
+fn check_house<'a>(
+    house: &BTreeMap<Asset, HouseBalance>,
+    changes: impl IntoIterator<Item = &'a StateChange>,
+) -> Result<()> {
+    let mut after = BTreeMap::new();
+    for change in changes {
+        if let StateChange::HouseChanged {
+            asset,
+            account,
+            amount,
+            ..
+        } = *change
+        {
+            let balance = after
+                .entry(asset)
+                .or_insert_with(|| house.get(&asset).copied().unwrap_or_default())
+                .account_mut(account);
+            *balance = balance
+                .checked_add(amount)
+                .ok_or(ProcessTransactionError::Overflow)?;
+        }
+    }
+    Ok(())
+}
+
+/// A client's account. The transactions it owns are kept in the exchange's [`TransactionStore`].
+struct Client {
+    /// Balances in each asset the client has held. A client whose transactions have all failed
+    /// has none.
+    balances: BTreeMap<Asset, Balance>,
+    status: AccountStatus,
+    /// The client's transactions within the dispute window and their timestamps, oldest first.
+    /// Only kept while the exchange has a [`DisputeWindow`].
+    recent: VecDeque<(TransactionId, Option<Timestamp>)>,
+    /// Disputed transactions that have left the dispute window, which expire once the dispute
+    /// is settled.
+    overdue: HashSet<TransactionId>,
+    /// Every time the client's funds in an asset have gone below zero, in the order they did. At
+    /// most one deficit in each asset is still open.
+    deficits: Vec<Deficit>,
+    /// Credit limits given to the client by an operator, which take the place of their limits in
+    /// the exchange's [`CreditLimits`].
+    limits: BTreeMap<Asset, MonetaryAmount>,
+}
+
+/// How long a transaction can be disputed for, set with [`Exchange::set_dispute_window`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeWindow {
+    /// Only a client's most recent deposits and withdrawals can be disputed.
+    Transactions(NonZeroUsize),
+    /// Transactions can only be disputed for this long after their timestamp, going by the
+    /// timestamp of the dispute. Transactions or disputes without a timestamp aren't limited.
+    Duration(std::time::Duration),
+}
+
+/// Whether an account can make transactions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Locked by a chargeback, until an operator unlocks it.
+    Locked,
+    /// Frozen by an operator, until an operator unlocks it.
+    Frozen,
+    /// Closed by an operator, for good.
+    Closed,
+}
+
+/// How much of a transaction is under dispute and how much of it has been charged back. Claims
+/// can be made against part of a transaction, but never against more of it than it moved.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClaimState {
+    /// Held until it's resolved or charged back, for a deposit.
+    #[serde(default, skip_serializing_if = "MonetaryAmount::is_zero")]
+    pub disputed: MonetaryAmount,
+    #[serde(default, skip_serializing_if = "MonetaryAmount::is_zero")]
+    pub charged_back: MonetaryAmount,
+}
+
+impl ClaimState {
+    /// Whether any of the transaction is under dispute.
+    pub fn is_disputed(&self) -> bool {
+        !self.disputed.is_zero()
+    }
+
+    /// Whether nothing has been disputed or charged back, or every dispute has been resolved.
+    pub fn is_clear(&self) -> bool {
+        self.disputed.is_zero() && self.charged_back.is_zero()
+    }
+}
+
+impl Client {
+    fn new() -> Self {
+        Self {
+            balances: BTreeMap::new(),
+            status: AccountStatus::Active,
+            recent: VecDeque::new(),
+            overdue: HashSet::new(),
+            deficits: Vec::new(),
+            limits: BTreeMap::new(),
+        }
+    }
+
+    fn balance(&self, asset: Asset) -> Balance {
+        self.balances.get(&asset).copied().unwrap_or_default()
+    }
+
+    /// Fails unless the account can make transactions.
+    fn check_active(&self) -> Result<()> {
+        match self.status {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Locked => Err(ProcessTransactionError::ClientLocked),
+            AccountStatus::Frozen => Err(ProcessTransactionError::ClientFrozen),
+            AccountStatus::Closed => Err(ProcessTransactionError::ClientClosed),
+        }
+    }
+
+    /// Apply a deposit, withdrawal, transfer or conversion. A withdrawal can take the client's
+    /// available funds down to `-limit`, their credit limit in `asset`. The fee it adds to
+    /// `house`, which the exchange applies afterwards, is checked before anything is applied.
+    #[allow(clippy::too_many_arguments)]
+    fn process_monetary_request(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: MonetaryTransaction,
+        asset: Asset,
+        timestamp: Option<Timestamp>,
+        fees: &Fees,
+        limit: MonetaryAmount,
+        house: &BTreeMap<Asset, HouseBalance>,
+    ) -> Result<Vec<StateChange>> {
+        self.check_active()?;
+
+        let fee = fees.fee(transaction)?;
+        let available = match transaction {
+            // The fee is taken out of the deposit, so it has to cover it
+            MonetaryTransaction::Deposit(amount) => {
+                if amount < fee {
+                    return Err(ProcessTransactionError::InsufficientFunds {
+                        requested: fee,
+                        available: amount,
+                    });
+                }
+                amount - fee
+            }
+            MonetaryTransaction::Withdrawal(amount)
+            | MonetaryTransaction::Transfer { amount, .. }
+            | MonetaryTransaction::Convert { amount, .. } => {
+                let debit = amount
+                    .checked_add(fee)
+                    .ok_or(ProcessTransactionError::Overflow)?;
+                // Credit only covers withdrawals, so transfers and conversions need the funds
+                let mut available = self.balance(asset).available;
+                if let MonetaryTransaction::Withdrawal(_) = transaction {
+                    available = available
+                        .checked_add(limit)
+                        .ok_or(ProcessTransactionError::Overflow)?;
+                }
+                if available < debit {
+                    return Err(ProcessTransactionError::InsufficientFunds {
+                        requested: debit,
+                        available,
+                    });
+                }
+                -debit
+            }
+        };
+
+        let mut changes = vec![StateChange::BalanceChanged {
+            transaction: transaction_id,
+            asset,
+            available,
+            held: MonetaryAmount::ZERO,
+        }];
+        if !fee.is_zero() {
+            changes.push(StateChange::HouseChanged {
+                transaction: transaction_id,
+                asset,
+                account: HouseAccount::Fees,
+                amount: fee,
+            });
+        }
+        changes.push(StateChange::TransactionRecorded {
+            transaction: transaction_id,
+            request: transaction,
+            asset,
+            fee,
+            timestamp,
+        });
+        check_house(house, &changes)?;
+        self.apply_changes(changes)
+    }
+
+    /// The changes that credit the client with a transfer, without applying them. Once these
+    /// succeed, applying them can't fail.
+    fn receive_transfer(
+        &self,
+        transaction_id: TransactionId,
+        asset: Asset,
+        amount: MonetaryAmount,
+    ) -> Result<Vec<StateChange>> {
+        if self.status != AccountStatus::Active {
+            return Err(ProcessTransactionError::DestinationLocked);
+        }
+        self.credit(transaction_id, asset, amount)
+    }
+
+    /// The change that adds `amount` to the client's available funds in `asset`, without applying
+    /// it. Once this succeeds, applying it can't fail.
+    fn credit(
+        &self,
+        transaction_id: TransactionId,
+        asset: Asset,
+        amount: MonetaryAmount,
+    ) -> Result<Vec<StateChange>> {
+        self.balance(asset)
+            .available
+            .checked_add(amount)
+            .ok_or(ProcessTransactionError::Overflow)?;
+
+        Ok(vec![StateChange::BalanceChanged {
+            transaction: transaction_id,
+            asset,
+            available: amount,
+            held: MonetaryAmount::ZERO,
+        }])
+    }
+
+    /// Apply a claim against one of the client's transactions. The changes it makes to `house`,
+    /// which the exchange applies afterwards, are checked before anything is applied.
+    fn process_claim(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction_info: &TransactionInformation,
+        claim: ClaimRequest,
+        policy: &dyn ClaimPolicy,
+        disputed_fees: DisputedFees,
+        house: &BTreeMap<Asset, HouseBalance>,
+    ) -> Result<Vec<StateChange>> {
+        self.check_active()?;
+
+        // Claims are applied in the asset of the original transaction
+        let asset = transaction_info.asset;
+        let balance_changed = |available, held| StateChange::BalanceChanged {
+            transaction: transaction_id,
+            asset,
+            available,
+            held,
+        };
+        let claim_changed = |claim| StateChange::ClaimChanged {
+            transaction: transaction_id,
+            claim,
+        };
+        let house_changed = |account, amount| StateChange::HouseChanged {
+            transaction: transaction_id,
+            asset,
+            account,
+            amount,
+        };
+        let state = transaction_info.claim;
+        let fee = transaction_info.fee;
+        let refund = disputed_fees == DisputedFees::Refund && !fee.is_zero();
+
+        // How much of the transaction the claim is for, and how much is disputed and charged back
+        // afterwards
+        let (amount, after) = match claim.claim_type {
+            ClaimType::Dispute => {
+                let undisputed = claimable(transaction_info)? - state.disputed - state.charged_back;
+                if undisputed.is_zero() {
+                    // A transaction that moved nothing, such as a deposit of zero or one that
+                    // only covered its fee, was never disputed
+                    return Err(if (state.disputed + state.charged_back).is_zero() {
+                        ProcessTransactionError::NothingToDispute
+                    } else {
+                        ProcessTransactionError::AlreadyDisputed
+                    });
+                }
+                let amount = claim.amount.unwrap_or(undisputed);
+                if amount > undisputed {
+                    return Err(ProcessTransactionError::DisputeExceedsTransaction);
+                }
+                let disputed = ClaimState {
+                    disputed: state.disputed + amount,
+                    ..state
+                };
+                (amount, disputed)
+            }
+            ClaimType::Resolve | ClaimType::Chargeback => {
+                if !state.is_disputed() {
+                    return Err(match claim.claim_type {
+                        ClaimType::Resolve => ProcessTransactionError::NoDisputeToResolve,
+                        _ => ProcessTransactionError::NoDisputeToChargeback,
+                    });
+                }
+                let amount = claim.amount.unwrap_or(state.disputed);
+                if amount > state.disputed {
+                    return Err(ProcessTransactionError::ClaimExceedsDispute);
+                }
+                let charged_back = match claim.claim_type {
+                    ClaimType::Chargeback => state.charged_back + amount,
+                    _ => state.charged_back,
+                };
+                let settled = ClaimState {
+                    disputed: state.disputed - amount,
+                    charged_back,
+                };
+                (amount, settled)
+            }
+        };
+
+        let change = policy.balance_change(
+            claim.claim_type,
+            transaction_info.request,
+            amount,
+            self.balance(asset),
+        )?;
+        // Whatever the client gains or loses, the chargebacks account loses or gains
+        let mut available = change.available;
+        let mut counter = -change.total();
+        // A refunded fee is given up by the chargeback of the last of the transaction: it goes
+        // back to the client for a withdrawal, and leaves the exchange along with the rest of the
+        // deposit for a deposit, moving from the fees account to the chargebacks account
+        let refund = refund
+            && claim.claim_type == ClaimType::Chargeback
+            && after.charged_back == claimable(transaction_info)?;
+        if refund {
+            match transaction_info.request {
+                MonetaryTransaction::Deposit(_) => counter += fee,
+                _ => available += fee,
+            }
+        }
+
+        let mut changes = Vec::new();
+        if !available.is_zero() || !change.held.is_zero() {
+            changes.push(balance_changed(available, change.held));
+        }
+        if refund {
+            changes.push(house_changed(HouseAccount::Fees, -fee));
+        }
+        if !counter.is_zero() {
+            changes.push(house_changed(HouseAccount::Chargebacks, counter));
+        }
+        changes.push(claim_changed(after));
+        if claim.claim_type == ClaimType::Chargeback && policy.locks_on_chargeback() {
+            changes.push(StateChange::StatusChanged {
+                transaction: transaction_id,
+                status: AccountStatus::Locked,
+                operator: None,
+                reason: ReasonCode::CHARGEBACK,
+            });
+        }
+
+        check_house(house, &changes)?;
+        self.apply_changes(changes)
+    }
+
+    /// Apply an administrative change to the account. A new credit limit is in `asset`.
+    fn process_admin(
+        &mut self,
+        transaction_id: TransactionId,
+        asset: Asset,
+        admin: AdminRequest,
+    ) -> Result<Vec<StateChange>> {
+        let status = match (admin.action, self.status) {
+            (_, AccountStatus::Closed) => return Err(ProcessTransactionError::ClientClosed),
+            (AdminAction::Unlock, AccountStatus::Active) => {
+                return Err(ProcessTransactionError::NotLocked);
+            }
+            (AdminAction::Unlock, _) => AccountStatus::Active,
+            (AdminAction::Freeze, AccountStatus::Frozen) => {
+                return Err(ProcessTransactionError::AlreadyFrozen);
+            }
+            (AdminAction::Freeze, _) => AccountStatus::Frozen,
+            // Including funds held by an open dispute or owed after a chargeback
+            (AdminAction::Close, _) => {
+                if self
+                    .balances
+                    .values()
+                    .any(|balance| *balance != Balance::default())
+                {
+                    return Err(ProcessTransactionError::BalanceNotZero);
+                }
+                AccountStatus::Closed
+            }
+            (AdminAction::SetLimit(limit), _) => {
+                if limit.is_sign_negative() {
+                    return Err(ProcessTransactionError::NegativeAmount);
+                }
+                return self.apply_changes(vec![StateChange::LimitChanged {
+                    transaction: transaction_id,
+                    asset,
+                    limit,
+                    operator: admin.operator,
+                    reason: admin.reason,
+                }]);
+            }
+        };
+
+        self.apply_changes(vec![StateChange::StatusChanged {
+            transaction: transaction_id,
+            status,
+            operator: Some(admin.operator),
+            reason: admin.reason,
+        }])
+    }
+
+    /// Apply `changes` to the client in order, returning them so that the exchange can apply
+    /// the rest and record them. Changes are only decided by the `process_*` methods and by
+    /// replaying a log, and are only ever applied here and by the exchange.
+    fn apply_changes(&mut self, changes: Vec<StateChange>) -> Result<Vec<StateChange>> {
+        for change in &changes {
+            self.apply_change(change)?;
+        }
+        Ok(changes)
+    }
+
+    fn apply_change(&mut self, change: &StateChange) -> Result<()> {
+        match *change {
+            // Applied by the exchange
+            StateChange::ClientCreated
+            | StateChange::TransactionRecorded { .. }
+            | StateChange::ClaimChanged { .. }
+            | StateChange::HouseChanged { .. }
+            | StateChange::TransactionExpired { .. } => {}
+            StateChange::BalanceChanged {
+                asset,
+                available,
+                held,
+                ..
+            } => {
+                let balance = self.balance(asset);
+                let available = balance
+                    .available
+                    .checked_add(available)
+                    .ok_or(ProcessTransactionError::Overflow)?;
+                let held = balance
+                    .held
+                    .checked_add(held)
+                    .ok_or(ProcessTransactionError::Overflow)?;
+                self.balances.insert(asset, Balance { available, held });
+            }
+            StateChange::StatusChanged { status, .. } => self.status = status,
+            StateChange::LimitChanged { asset, limit, .. } => {
+                self.limits.insert(asset, limit);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Open, deepen or close the client's deficit in `asset` once every change caused by a
+    /// request with timestamp `at` has been applied. `transaction` changed the client's funds in
+    /// it, and `lowered` whether it took any of them down.
+    fn track_deficit(
+        &mut self,
+        asset: Asset,
+        transaction: TransactionId,
+        lowered: bool,
+        at: Option<Timestamp>,
+    ) {
+        let lowest = self.balance(asset).lowest();
+        let open = self
+            .deficits
+            .iter()
+            .rposition(|deficit| deficit.asset == asset && !deficit.recovered);
+
+        match open {
+            Some(index) => {
+                let deficit = &mut self.deficits[index];
+                if lowest >= MonetaryAmount::ZERO {
+                    deficit.recovered = true;
+                    deficit.until = at;
+                    return;
+                }
+                deficit.peak = deficit.peak.min(lowest);
+                if lowered && !deficit.transactions.contains(&transaction) {
+                    deficit.transactions.push(transaction);
+                }
+            }
+            None if lowest < MonetaryAmount::ZERO => self.deficits.push(Deficit {
+                asset,
+                peak: lowest,
+                transactions: vec![transaction],
+                since: at,
+                recovered: false,
+                until: None,
+            }),
+            None => {}
+        }
+    }
+}
+
+/// A client along with the transactions it owns, kept the way the exchange would keep them.
+#[cfg(test)]
+struct TestClient {
+    client: Client,
+    transactions: HashMap<TransactionId, TransactionInformation>,
+}
+
+#[cfg(test)]
+impl TestClient {
+    const ID: ClientId = ClientId(1);
+
+    fn new() -> Self {
+        Self {
+            client: Client::new(),
+            transactions: HashMap::new(),
+        }
+    }
+
+    fn process_monetary_request(
+        &mut self,
+        transaction_id: TransactionId,
+        transaction: MonetaryTransaction,
+    ) -> Result<Vec<StateChange>> {
+        let changes = self.client.process_monetary_request(
+            transaction_id,
+            transaction,
+            Asset::default(),
+            None,
+            &Fees::default(),
+            MonetaryAmount::ZERO,
+            &BTreeMap::new(),
+        )?;
+        self.apply_to_store(&changes);
+        Ok(changes)
+    }
+
+    fn process_claim(
+        &mut self,
+        transaction_id: TransactionId,
+        claim_type: ClaimType,
+    ) -> Result<Vec<StateChange>> {
+        self.process_partial_claim(transaction_id, claim_type, None)
+    }
+
+    fn process_partial_claim(
+        &mut self,
+        transaction_id: TransactionId,
+        claim_type: ClaimType,
+        amount: Option<MonetaryAmount>,
+    ) -> Result<Vec<StateChange>> {
+        let info = *self
+            .transactions
+            .get(&transaction_id)
+            .ok_or(ProcessTransactionError::TransactionNotFound)?;
+        let claim = ClaimRequest { claim_type, amount };
+        let changes = self.client.process_claim(
+            transaction_id,
+            &info,
+            claim,
+            &StandardClaimPolicy::new(),
+            DisputedFees::Keep,
+            &BTreeMap::new(),
+        )?;
+        self.apply_to_store(&changes);
+        Ok(changes)
+    }
+
+    fn apply_to_store(&mut self, changes: &[StateChange]) {
+        for change in changes {
+            match *change {
+                StateChange::TransactionRecorded {
+                    transaction,
+                    request,
+                    asset,
+                    fee,
+                    timestamp,
+                } => {
+                    let info = TransactionInformation {
+                        client: Self::ID,
+                        request,
+                        asset,
+                        fee,
+                        claim: ClaimState::default(),
+                        timestamp,
+                    };
+                    self.transactions.insert(transaction, info);
+                }
+                StateChange::ClaimChanged { transaction, claim } => {
+                    self.transactions.get_mut(&transaction).unwrap().claim = claim;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Client {
+    fn available(&self) -> MonetaryAmount {
+        self.balance(Asset::default()).available
+    }
+
+    fn held(&self) -> MonetaryAmount {
+        self.balance(Asset::default()).held
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+#[cfg(test)]
+impl std::ops::DerefMut for TestClient {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::{Decimal, dec};
+
+    #[test]
+    fn test_deposit_increases_available_balance() {
+        let mut client = TestClient::new();
+        let transaction_id = TransactionId(1);
+        let deposit_amount = dec!(1.234);
+
+        let result = client
+            .process_monetary_request(transaction_id, MonetaryTransaction::Deposit(deposit_amount));
+
+        assert!(result.is_ok());
+        assert_eq!(client.available(), deposit_amount);
+        assert_eq!(client.held(), Decimal::ZERO);
+        assert_eq!(client.status, AccountStatus::Active);
+        assert!(client.transactions.contains_key(&transaction_id));
+    }
+
+    #[test]
+    fn test_multiple_deposits_accumulate() {
+        let mut client = TestClient::new();
+        let first_deposit = dec!(0.5000);
+        let second_deposit = dec!(0.7500);
+
+        client
+            .process_monetary_request(
+                TransactionId(1),
+                MonetaryTransaction::Deposit(first_deposit),
+            )
+            .unwrap();
+
+        client
+            .process_monetary_request(
+                TransactionId(2),
+                MonetaryTransaction::Deposit(second_deposit),
+            )
+            .unwrap();
+
+        assert_eq!(client.available(), first_deposit + second_deposit);
+        assert_eq!(client.transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_withdrawal_decreases_available_balance() {
+        let mut client = TestClient::new();
+        let initial_deposit = dec!(1.0000);
+        let withdrawal_amount = dec!(0.3000);
+
+        client
+            .process_monetary_request(
+                TransactionId(1),
+                MonetaryTransaction::Deposit(initial_deposit),
+            )
+            .unwrap();
+
+        client
+            .process_monetary_request(
+                TransactionId(2),
+                MonetaryTransaction::Withdrawal(withdrawal_amount),
+            )
+            .unwrap();
+
+        assert_eq!(client.available(), initial_deposit - withdrawal_amount);
+        assert_eq!(client.transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_withdrawal_with_insufficient_funds_fails() {
+        let mut client = TestClient::new();
+        client.balances.insert(
+            Asset::default(),
+            Balance {
+                available: dec!(0.5),
+                held: Decimal::ZERO,
+            },
+        );
+
+        let withdrawal_request = client
+            .process_monetary_request(TransactionId(2), MonetaryTransaction::Withdrawal(dec!(1.0)));
+
+        assert!(matches!(
+            withdrawal_request.unwrap_err(),
+            ProcessTransactionError::InsufficientFunds { .. }
+        ));
+    }
+
+    #[test]
+    fn test_locked_client_cannot_process_transactions() {
+        let mut client = TestClient::new();
+        client.status = AccountStatus::Locked;
+
+        let deposit_result = client
+            .process_monetary_request(TransactionId(1), MonetaryTransaction::Deposit(dec!(10.00)));
+        assert!(matches!(
+            deposit_result.unwrap_err(),
+            ProcessTransactionError::ClientLocked
+        ));
+
+        let withdrawal_result = client.process_monetary_request(
+            TransactionId(2),
+            MonetaryTransaction::Withdrawal(dec!(10.00)),
+        );
+
+        assert!(matches!(
+            withdrawal_result.unwrap_err(),
+            ProcessTransactionError::ClientLocked
+        ));
+        assert_eq!(client.available(), Decimal::ZERO);
+        assert!(client.transactions.is_empty());
+    }
+
+    #[test]
+    fn test_zero_amount_deposit_and_withdrawal() {
+        let mut client = TestClient::new();
+        let deposit_result = client.process_monetary_request(
+            TransactionId(1),
+            MonetaryTransaction::Deposit(Decimal::ZERO),
+        );
+
+        assert!(deposit_result.is_ok());
+        assert_eq!(client.available(), Decimal::ZERO);
+
+        let withdrawal_result = client.process_monetary_request(
+            TransactionId(2),
+            MonetaryTransaction::Withdrawal(Decimal::ZERO),
+        );
+
+        assert!(withdrawal_result.is_ok());
+        assert_eq!(client.available(), Decimal::ZERO);
+    }
+}
+
+#[cfg(test)]
+mod claim_tests {
+    use super::*;
+    use rust_decimal::{Decimal, dec};
+
+    const TRANSACTION_ID: TransactionId = TransactionId(1);
+    const DEPOSIT_AMOUNT: MonetaryAmount = dec!(100.00);
+
+    fn create_client_with_deposit_transaction() -> TestClient {
+        let mut client = TestClient::new();
+        client
+            .process_monetary_request(TRANSACTION_ID, MonetaryTransaction::Deposit(DEPOSIT_AMOUNT))
+            .expect("Failed to process deposit");
+        client
+    }
+
+    #[test]
+    fn test_dispute_transaction() {
+        let mut client = create_client_with_deposit_transaction();
+
+        let dispute_result = client.process_claim(TRANSACTION_ID, ClaimType::Dispute);
+        assert!(dispute_result.is_ok());
+        assert_eq!(client.held(), DEPOSIT_AMOUNT);
+        assert_eq!(client.available(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_resolve_disputed_transaction() {
+        let mut client = create_client_with_deposit_transaction();
+
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+        assert_eq!(client.held(), DEPOSIT_AMOUNT);
+        assert_eq!(client.available(), Decimal::ZERO);
+
+        let resolve_result = client.process_claim(TRANSACTION_ID, ClaimType::Resolve);
+        assert!(resolve_result.is_ok());
+        assert_eq!(client.held(), Decimal::ZERO);
+        assert_eq!(client.available(), DEPOSIT_AMOUNT);
+    }
+
+    #[test]
+    fn test_chargeback_disputed_transaction() {
+        let mut client = create_client_with_deposit_transaction();
+
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+        assert_eq!(client.held(), DEPOSIT_AMOUNT);
+        assert_eq!(client.available(), Decimal::ZERO);
+        assert_eq!(client.status, AccountStatus::Active);
+
+        let chargeback_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
+        assert!(chargeback_result.is_ok());
+        assert_eq!(client.held(), Decimal::ZERO);
+        assert_eq!(client.available(), Decimal::ZERO);
+        assert_eq!(client.status, AccountStatus::Locked);
+    }
+
+    #[test]
+    fn test_resolve_or_chargeback_without_dispute_fails() {
+        let mut client = create_client_with_deposit_transaction();
+
+        let resolve_result = client.process_claim(TRANSACTION_ID, ClaimType::Resolve);
+        assert!(matches!(
+            resolve_result.unwrap_err(),
+            ProcessTransactionError::NoDisputeToResolve
+        ));
+
+        let chargeback_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
+        assert!(matches!(
+            chargeback_result.unwrap_err(),
+            ProcessTransactionError::NoDisputeToChargeback
+        ));
+    }
+
+    #[test]
+    fn test_dispute_on_disputed_transaction_fails() {
+        let mut client = create_client_with_deposit_transaction();
+
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+
+        let dispute_again_result = client.process_claim(TRANSACTION_ID, ClaimType::Dispute);
+        assert!(matches!(
+            dispute_again_result.unwrap_err(),
+            ProcessTransactionError::AlreadyDisputed
+        ));
+    }
+
+    #[test]
+    fn test_dispute_on_resolved_transaction() {
+        let mut client = create_client_with_deposit_transaction();
+
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Resolve)
+            .unwrap();
+
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+        assert_eq!(client.held(), DEPOSIT_AMOUNT);
+        assert_eq!(client.available(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_chargeback_on_resolved_or_undisputed_transaction_fails() {
+        let mut client = create_client_with_deposit_transaction();
+
+        let chargeback_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
+        assert!(matches!(
+            chargeback_result.unwrap_err(),
+            ProcessTransactionError::NoDisputeToChargeback
+        ));
+
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Resolve)
+            .unwrap();
+
+        let chargeback_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
+        assert!(matches!(
+            chargeback_result.unwrap_err(),
+            ProcessTransactionError::NoDisputeToChargeback
+        ));
+    }
+
+    #[test]
+    fn test_claim_on_chargebacked_transaction_fails() {
+        let mut client = create_client_with_deposit_transaction();
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Chargeback)
+            .unwrap();
+
+        let chargeback_again_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
+        assert!(matches!(
+            chargeback_again_result.unwrap_err(),
+            ProcessTransactionError::ClientLocked
+        ));
+
+        let resolve_result = client.process_claim(TRANSACTION_ID, ClaimType::Resolve);
+        assert!(matches!(
+            resolve_result.unwrap_err(),
+            ProcessTransactionError::ClientLocked
+        ));
+
+        let dispute_result = client.process_claim(TRANSACTION_ID, ClaimType::Dispute);
+        assert!(matches!(
+            dispute_result.unwrap_err(),
+            ProcessTransactionError::ClientLocked
+        ));
+    }
+
+    #[test]
+    fn test_partial_disputes_never_exceed_the_transaction() {
+        let mut client = create_client_with_deposit_transaction();
+
+        for amount in [dec!(30), dec!(50)] {
+            client
+                .process_partial_claim(TRANSACTION_ID, ClaimType::Dispute, Some(amount))
+                .unwrap();
+        }
+        assert_eq!(client.held(), dec!(80));
+        assert_eq!(client.available(), dec!(20));
+
+        let too_much =
+            client.process_partial_claim(TRANSACTION_ID, ClaimType::Dispute, Some(dec!(21)));
+        assert!(matches!(
+            too_much.unwrap_err(),
+            ProcessTransactionError::DisputeExceedsTransaction
+        ));
+
+        // Without an amount, whatever is left is disputed
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+        assert_eq!(client.held(), DEPOSIT_AMOUNT);
+        assert_eq!(
+            client.transactions[&TRANSACTION_ID].claim,
+            ClaimState {
+                disputed: DEPOSIT_AMOUNT,
+                charged_back: dec!(0),
+            }
+        );
+
+        let nothing_left =
+            client.process_partial_claim(TRANSACTION_ID, ClaimType::Dispute, Some(dec!(1)));
+        assert!(matches!(
+            nothing_left.unwrap_err(),
+            ProcessTransactionError::AlreadyDisputed
+        ));
+    }
+
+    #[test]
+    fn test_dispute_of_zero_deposit() {
+        let mut client = TestClient::new();
+        client
+            .process_monetary_request(TRANSACTION_ID, MonetaryTransaction::Deposit(Decimal::ZERO))
+            .unwrap();
+
+        // It was never disputed, so it isn't already disputed either
+        assert!(matches!(
+            client
+                .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+                .unwrap_err(),
+            ProcessTransactionError::NothingToDispute
+        ));
+        assert_eq!(
+            client.transactions[&TRANSACTION_ID].claim,
+            ClaimState::default()
+        );
+    }
+
+    #[test]
+    fn test_partial_resolve_and_chargeback() {
+        let mut client = create_client_with_deposit_transaction();
+        client
+            .process_partial_claim(TRANSACTION_ID, ClaimType::Dispute, Some(dec!(40)))
+            .unwrap();
+
+        let too_much =
+            client.process_partial_claim(TRANSACTION_ID, ClaimType::Resolve, Some(dec!(41)));
+        assert!(matches!(
+            too_much.unwrap_err(),
+            ProcessTransactionError::ClaimExceedsDispute
+        ));
+        client
+            .process_partial_claim(TRANSACTION_ID, ClaimType::Resolve, Some(dec!(15)))
+            .unwrap();
+        assert_eq!(client.held(), dec!(25));
+        assert_eq!(client.available(), dec!(75));
+
+        let too_much =
+            client.process_partial_claim(TRANSACTION_ID, ClaimType::Chargeback, Some(dec!(26)));
+        assert!(matches!(
+            too_much.unwrap_err(),
+            ProcessTransactionError::ClaimExceedsDispute
+        ));
+        client
+            .process_partial_claim(TRANSACTION_ID, ClaimType::Chargeback, Some(dec!(10)))
+            .unwrap();
+        assert_eq!(client.held(), dec!(15));
+        assert_eq!(client.available(), dec!(75));
+        assert_eq!(client.status, AccountStatus::Locked);
+        assert_eq!(
+            client.transactions[&TRANSACTION_ID].claim,
+            ClaimState {
+                disputed: dec!(15),
+                charged_back: dec!(10),
+            }
+        );
+
+        // What's been charged back can't be disputed again
+        client.status = AccountStatus::Active;
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Resolve)
+            .unwrap();
+        client
+            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+            .unwrap();
+        assert_eq!(client.held(), dec!(90));
+        assert_eq!(client.available(), dec!(0));
+    }
+}
+
+#[cfg(test)]
+mod exchange_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+    const OTHER_CLIENT: ClientId = ClientId(2);
+
+    #[test]
+    fn test_client_view_reflects_balances() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(5.0)).unwrap(),
+            )
+            .unwrap();
+        exchange
+            .process_transaction(
+                TransactionRequest::withdrawal(CLIENT, TransactionId(2), dec!(2.0)).unwrap(),
+            )
+            .unwrap();
+
+        let view = exchange.client(CLIENT).unwrap();
+        assert_eq!(view.id(), CLIENT);
+        assert_eq!(view.available(), dec!(3.0));
+        assert_eq!(view.held(), dec!(0));
+        assert_eq!(view.total(), dec!(3.0));
+        assert!(!view.is_locked());
+
+        assert!(exchange.client(OTHER_CLIENT).is_none());
+        assert_eq!(exchange.clients().count(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_transaction_id_fails() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(5.0)).unwrap(),
+            )
+            .unwrap();
+
+        let result = exchange.process_transaction(
+            TransactionRequest::deposit(OTHER_CLIENT, TransactionId(1), dec!(5.0)).unwrap(),
+        );
+
+        assert!(matches!(
+            result.unwrap_err(),
+            ProcessTransactionError::DuplicateTransaction
+        ));
+        assert!(exchange.client(OTHER_CLIENT).is_none());
+    }
+
+    #[test]
+    fn test_claim_on_other_clients_transaction_fails() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(5.0)).unwrap(),
+            )
+            .unwrap();
+
+        let result = exchange.process_transaction(TransactionRequest::claim(
+            OTHER_CLIENT,
+            TransactionId(1),
+            ClaimType::Dispute,
+        ));
+
+        assert!(matches!(
+            result.unwrap_err(),
+            ProcessTransactionError::Unauthorized
+        ));
+        assert_eq!(exchange.client(CLIENT).unwrap().held(), dec!(0));
+    }
+}
+
+#[cfg(test)]
+mod event_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+
+    fn changes(exchange: &Exchange) -> Vec<StateChange> {
+        exchange
+            .event_log()
+            .unwrap()
+            .events()
+            .iter()
+            .filter_map(|event| match &event.kind {
+                EventKind::Changed(change) => Some(change.clone()),
+                EventKind::Accepted(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dispute_and_resolve_are_recorded() {
+        let mut exchange = Exchange::with_event_log();
+        let deposit = TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(5)).unwrap();
+        exchange.process_transaction(deposit).unwrap();
+        exchange
+            .process_transaction(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(1),
+                ClaimType::Dispute,
+            ))
+            .unwrap();
+        exchange
+            .process_transaction(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(1),
+                ClaimType::Resolve,
+            ))
+            .unwrap();
+
+        let events = exchange.event_log().unwrap().events();
+        assert_eq!(events[0].kind, EventKind::Accepted(deposit));
+        assert!(
+            events
+                .iter()
+                .enumerate()
+                .all(|(i, event)| event.seq == i as u64)
+        );
+
+        assert_eq!(
+            changes(&exchange),
+            vec![
+                StateChange::ClientCreated,
+                StateChange::BalanceChanged {
+                    transaction: TransactionId(1),
+                    asset: Asset::default(),
+                    available: dec!(5),
+                    held: dec!(0),
+                },
+                StateChange::TransactionRecorded {
+                    transaction: TransactionId(1),
+                    request: MonetaryTransaction::Deposit(dec!(5)),
+                    asset: Asset::default(),
+                    fee: dec!(0),
+                    timestamp: None,
+                },
+                StateChange::BalanceChanged {
+                    transaction: TransactionId(1),
+                    asset: Asset::default(),
+                    available: dec!(-5),
+                    held: dec!(5),
+                },
+                StateChange::ClaimChanged {
+                    transaction: TransactionId(1),
+                    claim: ClaimState {
+                        disputed: dec!(5),
+                        charged_back: dec!(0),
+                    },
+                },
+                StateChange::BalanceChanged {
+                    transaction: TransactionId(1),
+                    asset: Asset::default(),
+                    available: dec!(5),
+                    held: dec!(-5),
+                },
+                StateChange::ClaimChanged {
+                    transaction: TransactionId(1),
+                    claim: ClaimState::default(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejected_request_only_records_client_creation() {
+        let mut exchange = Exchange::with_event_log();
+        let withdrawal = TransactionRequest::withdrawal(CLIENT, TransactionId(1), dec!(5)).unwrap();
+
+        assert!(exchange.process_transaction(withdrawal).is_err());
+
+        let events = exchange.event_log().unwrap().events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].kind,
+            EventKind::Changed(StateChange::ClientCreated)
+        );
+    }
+
+    #[test]
+    fn test_replay_rebuilds_exchange() {
+        let mut exchange = Exchange::with_event_log();
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(5)).unwrap(),
+            )
+            .unwrap();
+        exchange
+            .process_transaction(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(1),
+                ClaimType::Dispute,
+            ))
+            .unwrap();
+
+        let mut replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+
+        let view = replayed.client(CLIENT).unwrap();
+        assert_eq!(view.available(), dec!(0));
+        assert_eq!(view.held(), dec!(5));
+
+        // Ownership and claim state are rebuilt too, and the replayed exchange keeps recording
+        let result = replayed.process_transaction(
+            TransactionRequest::deposit(ClientId(2), TransactionId(1), dec!(1)).unwrap(),
+        );
+        assert!(matches!(
+            result,
+            Err(ProcessTransactionError::DuplicateTransaction)
+        ));
+        replayed
+            .process_transaction(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(1),
+                ClaimType::Chargeback,
+            ))
+            .unwrap();
+        assert!(replayed.client(CLIENT).unwrap().is_locked());
+        assert!(replayed.event_log().unwrap().len() > exchange.event_log().unwrap().len());
+    }
+
+    #[test]
+    fn test_replay_fails_for_unknown_client() {
+        let mut log = EventLog::new();
+        let locked = StateChange::StatusChanged {
+            transaction: TransactionId(1),
+            status: AccountStatus::Locked,
+            operator: None,
+            reason: ReasonCode::CHARGEBACK,
+        };
+        log.append(CLIENT, EventKind::Changed(locked));
+
+        assert!(matches!(
+            Exchange::replay(log),
+            Err(ProcessTransactionError::ClientNotFound)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+
+    fn save(exchange: &Exchange) -> Vec<u8> {
+        let mut snapshot = Vec::new();
+        exchange.save_snapshot(&mut snapshot).unwrap();
+        snapshot
+    }
+
+    #[test]
+    fn test_snapshot_round_trip_keeps_state() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(5.25)).unwrap(),
+            )
+            .unwrap();
+        exchange
+            .process_transaction(
+                TransactionRequest::withdrawal(CLIENT, TransactionId(2), dec!(1)).unwrap(),
+            )
+            .unwrap();
+        exchange
+            .process_transaction(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(1),
+                ClaimType::Dispute,
+            ))
+            .unwrap();
+
+        let snapshot = save(&exchange);
+        let mut loaded = Exchange::load_snapshot(snapshot.as_slice()).unwrap();
+
+        assert_eq!(
+            loaded.client(CLIENT).unwrap().snapshots(),
+            exchange.client(CLIENT).unwrap().snapshots()
+        );
+        // Saving the same state gives the same file
+        assert_eq!(save(&loaded), snapshot);
+
+        // The dispute and transaction ownership carry over
+        let result = loaded.process_transaction(
+            TransactionRequest::deposit(ClientId(2), TransactionId(2), dec!(1)).unwrap(),
+        );
+        assert!(matches!(
+            result,
+            Err(ProcessTransactionError::DuplicateTransaction)
+        ));
+        loaded
+            .process_transaction(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(1),
+                ClaimType::Resolve,
+            ))
+            .unwrap();
+        assert_eq!(loaded.client(CLIENT).unwrap().available(), dec!(4.25));
+    }
+
+    #[test]
+    fn test_snapshot_with_unsupported_version_fails() {
+        let snapshot = br#"{"version":999,"something":"else"}"#;
+
+        assert!(matches!(
+            Exchange::load_snapshot(snapshot.as_slice()),
+            Err(SnapshotError::UnsupportedVersion {
+                found: 999,
+                expected: SNAPSHOT_VERSION
+            })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_snapshot_fails() {
+        assert!(matches!(
+            Exchange::load_snapshot(b"not json".as_slice()),
+            Err(SnapshotError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_inconsistent_snapshot_fails() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(5.25)).unwrap(),
+            )
+            .unwrap();
+        let snapshot = serde_json::from_slice::<serde_json::Value>(&save(&exchange)).unwrap();
+
+        let edits: [fn(&mut serde_json::Value); 6] = [
+            |snapshot| snapshot["clients"][0]["transactions"][0]["amount"] = "-5.25".into(),
+            |snapshot| snapshot["clients"][0]["balances"][0]["available"] = "5.25001".into(),
+            |snapshot| snapshot["clients"][0]["balances"][0]["held"] = "-1".into(),
+            |snapshot| snapshot["owners"][0]["client"] = 2.into(),
+            |snapshot| snapshot["clients"][0]["recent"] = serde_json::json!([2]),
+            |snapshot| snapshot["clients"][0]["overdue"] = serde_json::json!([2]),
+        ];
+        for edit in edits {
+            let mut edited = snapshot.clone();
+            edit(&mut edited);
+            let edited = serde_json::to_vec(&edited).unwrap();
+            assert!(
+                matches!(
+                    Exchange::load_snapshot(edited.as_slice()),
+                    Err(SnapshotError::Inconsistent(_))
+                ),
+                "{}",
+                String::from_utf8_lossy(&edited)
+            );
+        }
+
+        // Amounts with trailing zeros past 4 decimal places are still exact
+        let mut edited = snapshot.clone();
+        edited["clients"][0]["balances"][0]["available"] = "5.250000".into();
+        let edited = serde_json::to_vec(&edited).unwrap();
+        Exchange::load_snapshot(edited.as_slice()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod dispute_window_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+    const OTHER_CLIENT: ClientId = ClientId(2);
+
+    fn windowed(transactions: usize) -> Exchange {
+        let mut exchange = Exchange::with_event_log();
+        exchange.set_dispute_window(DisputeWindow::Transactions(
+            NonZeroUsize::new(transactions).unwrap(),
+        ));
+        exchange
+    }
+
+    fn deposit(exchange: &mut Exchange, client: ClientId, transaction: u32) -> Result<()> {
+        exchange.process_transaction(
+            TransactionRequest::deposit(client, TransactionId(transaction), dec!(1)).unwrap(),
+        )
+    }
+
+    fn claim(
+        exchange: &mut Exchange,
+        client: ClientId,
+        transaction: u32,
+        claim: ClaimType,
+    ) -> Result<()> {
+        exchange.process_transaction(TransactionRequest::claim(
+            client,
+            TransactionId(transaction),
+            claim,
+        ))
+    }
+
+    #[test]
+    fn test_dispute_outside_window_fails() {
+        let mut exchange = windowed(2);
+        for transaction in 1..=3 {
+            deposit(&mut exchange, CLIENT, transaction).unwrap();
+        }
+
+        assert!(matches!(
+            claim(&mut exchange, CLIENT, 1, ClaimType::Dispute),
+            Err(ProcessTransactionError::DisputeWindowExpired)
+        ));
+        claim(&mut exchange, CLIENT, 2, ClaimType::Dispute).unwrap();
+
+        // The expired transaction's ID and owner are still known
+        assert!(matches!(
+            claim(&mut exchange, OTHER_CLIENT, 1, ClaimType::Dispute),
+            Err(ProcessTransactionError::Unauthorized)
+        ));
+        assert!(matches!(
+            deposit(&mut exchange, OTHER_CLIENT, 1),
+            Err(ProcessTransactionError::DuplicateTransaction)
+        ));
+    }
+
+    #[test]
+    fn test_window_is_per_client() {
+        let mut exchange = windowed(1);
+        deposit(&mut exchange, CLIENT, 1).unwrap();
+        deposit(&mut exchange, OTHER_CLIENT, 2).unwrap();
+
+        claim(&mut exchange, CLIENT, 1, ClaimType::Dispute).unwrap();
+    }
+
+    #[test]
+    fn test_disputed_transaction_expires_once_settled() {
+        let mut exchange = windowed(1);
+        deposit(&mut exchange, CLIENT, 1).unwrap();
+        claim(&mut exchange, CLIENT, 1, ClaimType::Dispute).unwrap();
+        deposit(&mut exchange, CLIENT, 2).unwrap();
+
+        // Held funds can still be released after the transaction leaves the window
+        claim(&mut exchange, CLIENT, 1, ClaimType::Resolve).unwrap();
+        assert_eq!(exchange.client(CLIENT).unwrap().available(), dec!(2));
+
+        assert!(matches!(
+            claim(&mut exchange, CLIENT, 1, ClaimType::Dispute),
+            Err(ProcessTransactionError::DisputeWindowExpired)
+        ));
+    }
+
+    #[test]
+    fn test_expiry_is_replayed() {
+        let mut exchange = windowed(1);
+        deposit(&mut exchange, CLIENT, 1).unwrap();
+        deposit(&mut exchange, CLIENT, 2).unwrap();
+
+        let mut replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+
+        assert!(matches!(
+            claim(&mut replayed, CLIENT, 1, ClaimType::Dispute),
+            Err(ProcessTransactionError::DisputeWindowExpired)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_window() {
+        let mut exchange = windowed(1);
+        deposit(&mut exchange, CLIENT, 1).unwrap();
+        deposit(&mut exchange, CLIENT, 2).unwrap();
+
+        let mut snapshot = Vec::new();
+        exchange.save_snapshot(&mut snapshot).unwrap();
+        let mut loaded = Exchange::load_snapshot(snapshot.as_slice()).unwrap();
+        loaded.set_dispute_window(DisputeWindow::Transactions(NonZeroUsize::MIN));
+
+        assert!(matches!(
+            claim(&mut loaded, CLIENT, 1, ClaimType::Dispute),
+            Err(ProcessTransactionError::DisputeWindowExpired)
+        ));
+        // The transactions within the window carry over, so the next deposit expires them
+        deposit(&mut loaded, CLIENT, 3).unwrap();
+        assert!(matches!(
+            claim(&mut loaded, CLIENT, 2, ClaimType::Dispute),
+            Err(ProcessTransactionError::DisputeWindowExpired)
+        ));
+    }
+
+    #[test]
+    fn test_dispute_outside_window_of_time_fails() {
+        let mut exchange = Exchange::new();
+        exchange.set_dispute_window(DisputeWindow::Duration(std::time::Duration::from_secs(60)));
+        let at = |seconds: i64| Timestamp::from_millis(seconds * 1000);
+
+        for (transaction, seconds) in [(1, 0), (2, 30)] {
+            exchange
+                .process_transaction(
+                    TransactionRequest::deposit(CLIENT, TransactionId(transaction), dec!(1))
+                        .unwrap()
+                        .with_timestamp(at(seconds)),
+                )
+                .unwrap();
+        }
+
+        let dispute = |transaction, seconds| {
+            TransactionRequest::claim(CLIENT, TransactionId(transaction), ClaimType::Dispute)
+                .with_timestamp(at(seconds))
+        };
+        assert!(matches!(
+            exchange.process_transaction(dispute(1, 61)),
+            Err(ProcessTransactionError::DisputeWindowExpired)
+        ));
+        exchange.process_transaction(dispute(2, 61)).unwrap();
+
+        // Transactions that have left the window are expired as time moves on
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(CLIENT, TransactionId(3), dec!(1))
+                    .unwrap()
+                    .with_timestamp(at(120)),
+            )
+            .unwrap();
+        assert_eq!(
+            exchange
+                .transactions
+                .expired_owner(TransactionId(1))
+                .unwrap(),
+            Some(CLIENT)
+        );
+        // Still disputed, so kept until it's resolved
+        assert_eq!(
+            exchange
+                .transactions
+                .expired_owner(TransactionId(2))
+                .unwrap(),
+            None
+        );
+    }
+}
+
+#[cfg(test)]
+mod transfer_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const SENDER: ClientId = ClientId(1);
+    const RECEIVER: ClientId = ClientId(2);
+
+    fn funded() -> Exchange {
+        let mut exchange = Exchange::with_event_log();
+        for (client, transaction) in [(SENDER, 1), (RECEIVER, 2)] {
+            exchange
+                .process_transaction(
+                    TransactionRequest::deposit(client, TransactionId(transaction), dec!(5))
+                        .unwrap(),
+                )
+                .unwrap();
+        }
+        exchange
+    }
+
+    fn transfer(
+        exchange: &mut Exchange,
+        destination: ClientId,
+        amount: MonetaryAmount,
+    ) -> Result<()> {
+        exchange.process_transaction(
+            TransactionRequest::transfer(SENDER, TransactionId(3), destination, amount).unwrap(),
+        )
+    }
+
+    fn balances(exchange: &Exchange) -> Vec<(ClientId, MonetaryAmount)> {
+        let mut balances = exchange
+            .clients()
+            .map(|client| (client.id(), client.available()))
+            .collect::<Vec<_>>();
+        balances.sort();
+        balances
+    }
+
+    #[test]
+    fn test_transfer_moves_funds() {
+        let mut exchange = funded();
+        transfer(&mut exchange, RECEIVER, dec!(2)).unwrap();
+
+        assert_eq!(
+            balances(&exchange),
+            vec![(SENDER, dec!(3)), (RECEIVER, dec!(7))]
+        );
+
+        // The credit is recorded against the destination, so replaying gives the same balances
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        assert_eq!(balances(&replayed), balances(&exchange));
+
+        let mut snapshot = Vec::new();
+        exchange.save_snapshot(&mut snapshot).unwrap();
+        let loaded = Exchange::load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            loaded.transactions.get(TransactionId(3)).unwrap(),
+            exchange.transactions.get(TransactionId(3)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_failed_transfer_changes_neither_client() {
+        let mut exchange = funded();
+
+        assert!(matches!(
+            transfer(&mut exchange, RECEIVER, dec!(6)),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+        assert!(matches!(
+            transfer(&mut exchange, ClientId(3), dec!(1)),
+            Err(ProcessTransactionError::DestinationNotFound)
+        ));
+
+        exchange.clients.get_mut(&RECEIVER).unwrap().status = AccountStatus::Locked;
+        assert!(matches!(
+            transfer(&mut exchange, RECEIVER, dec!(1)),
+            Err(ProcessTransactionError::DestinationLocked)
+        ));
+
+        exchange
+            .clients
+            .get_mut(&RECEIVER)
+            .unwrap()
+            .balances
+            .insert(
+                Asset::default(),
+                Balance {
+                    available: MonetaryAmount::MAX,
+                    held: MonetaryAmount::ZERO,
+                },
+            );
+        exchange.clients.get_mut(&RECEIVER).unwrap().status = AccountStatus::Active;
+        assert!(matches!(
+            transfer(&mut exchange, RECEIVER, dec!(1)),
+            Err(ProcessTransactionError::Overflow)
+        ));
+
+        assert_eq!(
+            balances(&exchange),
+            vec![(SENDER, dec!(5)), (RECEIVER, MonetaryAmount::MAX)]
+        );
+        // None of the attempts took the transaction ID
+        assert!(
+            exchange
+                .transactions
+                .get(TransactionId(3))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_transfer_cannot_be_disputed() {
+        let mut exchange = funded();
+        transfer(&mut exchange, RECEIVER, dec!(2)).unwrap();
+
+        let claim = |claim_type| TransactionRequest::claim(SENDER, TransactionId(3), claim_type);
+        assert!(matches!(
+            exchange.process_transaction(claim(ClaimType::Dispute)),
+            Err(ProcessTransactionError::NotDisputable)
+        ));
+        assert!(matches!(
+            exchange.process_transaction(claim(ClaimType::Resolve)),
+            Err(ProcessTransactionError::NoDisputeToResolve)
+        ));
+        assert_eq!(exchange.client(SENDER).unwrap().held(), dec!(0));
+    }
+}
+
+#[cfg(test)]
+mod asset_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+    const OTHER_CLIENT: ClientId = ClientId(2);
+
+    fn btc() -> Asset {
+        "BTC".parse().unwrap()
+    }
+
+    fn deposit(
+        client: ClientId,
+        transaction: u32,
+        amount: MonetaryAmount,
+        asset: Asset,
+    ) -> TransactionRequest {
+        TransactionRequest::deposit(client, TransactionId(transaction), amount)
+            .unwrap()
+            .with_asset(asset)
+    }
+
+    fn exchange() -> Exchange {
+        let mut exchange = Exchange::with_event_log();
+        exchange
+            .process_transaction(deposit(CLIENT, 1, dec!(10), Asset::default()))
+            .unwrap();
+        exchange
+            .process_transaction(deposit(CLIENT, 2, dec!(2), btc()))
+            .unwrap();
+        exchange
+            .process_transaction(deposit(OTHER_CLIENT, 3, dec!(1), btc()))
+            .unwrap();
+        exchange
+    }
+
+    #[test]
+    fn test_balances_are_kept_per_asset() {
+        let mut exchange = exchange();
+
+        let withdrawal = TransactionRequest::withdrawal(CLIENT, TransactionId(4), dec!(3))
+            .unwrap()
+            .with_asset(btc());
+        assert!(matches!(
+            exchange.process_transaction(withdrawal),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+
+        let transfer =
+            TransactionRequest::transfer(CLIENT, TransactionId(5), OTHER_CLIENT, dec!(2))
+                .unwrap()
+                .with_asset(btc());
+        exchange.process_transaction(transfer).unwrap();
+
+        let client = exchange.client(CLIENT).unwrap();
+        assert_eq!(client.available(), dec!(10));
+        assert_eq!(client.balance(btc()).available, dec!(0));
+        assert_eq!(
+            client.assets().collect::<Vec<_>>(),
+            vec![Asset::default(), btc()]
+        );
+
+        let other_client = exchange.client(OTHER_CLIENT).unwrap();
+        assert_eq!(other_client.balance(btc()).total(), dec!(3));
+        assert_eq!(other_client.assets().collect::<Vec<_>>(), vec![btc()]);
+    }
+
+    #[test]
+    fn test_claims_apply_in_the_asset_of_the_transaction() {
+        let mut exchange = exchange();
+
+        // The asset given with a claim is ignored
+        let dispute = TransactionRequest::claim(CLIENT, TransactionId(2), ClaimType::Dispute)
+            .with_asset("USD".parse().unwrap());
+        exchange.process_transaction(dispute).unwrap();
+
+        let client = exchange.client(CLIENT).unwrap();
+        assert_eq!(
+            client.balance(btc()),
+            Balance {
+                available: dec!(0),
+                held: dec!(2)
+            }
+        );
+        assert_eq!(client.available(), dec!(10));
+        assert_eq!(client.assets().count(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_and_replay_keep_assets() {
+        let exchange = exchange();
+        let snapshots = |exchange: &Exchange| {
+            let mut snapshots = exchange
+                .clients()
+                .flat_map(|client| client.snapshots())
+                .collect::<Vec<_>>();
+            snapshots.sort_by_key(|snapshot| (snapshot.client_id, snapshot.asset));
+            snapshots
+        };
+
+        let mut saved = Vec::new();
+        exchange.save_snapshot(&mut saved).unwrap();
+        let loaded = Exchange::load_snapshot(saved.as_slice()).unwrap();
+        assert_eq!(snapshots(&loaded), snapshots(&exchange));
+        assert_eq!(
+            loaded
+                .transactions
+                .get(TransactionId(2))
+                .unwrap()
+                .unwrap()
+                .asset,
+            btc()
+        );
+
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        assert_eq!(snapshots(&replayed), snapshots(&exchange));
+    }
+
+    #[test]
+    fn test_client_without_balances_has_empty_default_snapshot() {
+        let mut exchange = Exchange::new();
+        let withdrawal = TransactionRequest::withdrawal(CLIENT, TransactionId(1), dec!(1))
+            .unwrap()
+            .with_asset(btc());
+        assert!(exchange.process_transaction(withdrawal).is_err());
+
+        let snapshots = exchange.client(CLIENT).unwrap().snapshots();
+        assert_eq!(snapshots.len(), 1);
+        assert!(snapshots[0].asset.is_default());
+        assert_eq!(snapshots[0].total, dec!(0));
+    }
+}
+
+#[cfg(test)]
+mod conversion_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+
+    fn usd() -> Asset {
+        "USD".parse().unwrap()
+    }
+
+    fn eur() -> Asset {
+        "EUR".parse().unwrap()
+    }
+
+    fn convert(transaction: u32, amount: MonetaryAmount) -> TransactionRequest {
+        TransactionRequest::convert(CLIENT, TransactionId(transaction), usd(), eur(), amount)
+            .unwrap()
+    }
+
+    fn exchange() -> Exchange {
+        let mut rates = RateTable::new().with_max_age(std::time::Duration::from_secs(60));
+        rates.insert(usd(), eur(), dec!(0.92345), Timestamp::from_millis(0));
+
+        let mut exchange = Exchange::with_event_log();
+        exchange.set_rate_table(rates);
+        let deposit = TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(10))
+            .unwrap()
+            .with_asset(usd());
+        exchange.process_transaction(deposit).unwrap();
+        exchange
+    }
+
+    #[test]
+    fn test_convert_debits_one_asset_and_credits_another() {
+        let mut exchange = exchange();
+        exchange.process_transaction(convert(2, dec!(1))).unwrap();
+        exchange.process_transaction(convert(3, dec!(2.5))).unwrap();
+
+        let client = exchange.client(CLIENT).unwrap();
+        assert_eq!(client.balance(usd()).available, dec!(6.5));
+        // 0.92345 is rounded to even, then 2.308625 to the nearest
+        assert_eq!(client.balance(eur()).available, dec!(0.9234) + dec!(2.3086));
+
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        assert_eq!(
+            replayed.client(CLIENT).unwrap().snapshots(),
+            exchange.client(CLIENT).unwrap().snapshots()
+        );
+    }
+
+    #[test]
+    fn test_failed_convert_changes_neither_asset() {
+        let mut exchange = exchange();
+
+        assert!(matches!(
+            exchange.process_transaction(convert(2, dec!(11))),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+
+        let unknown =
+            TransactionRequest::convert(CLIENT, TransactionId(3), eur(), usd(), dec!(1)).unwrap();
+        assert!(matches!(
+            exchange.process_transaction(unknown),
+            Err(ProcessTransactionError::UnknownPair)
+        ));
+
+        let stale = convert(4, dec!(1)).with_timestamp(Timestamp::from_millis(60_001));
+        assert!(matches!(
+            exchange.process_transaction(stale),
+            Err(ProcessTransactionError::StaleRate)
+        ));
+
+        let client = exchange.client(CLIENT).unwrap();
+        assert_eq!(client.balance(usd()).available, dec!(10));
+        assert_eq!(client.assets().collect::<Vec<_>>(), vec![usd()]);
+
+        // The rejected conversions didn't take their IDs
+        exchange.process_transaction(convert(4, dec!(1))).unwrap();
+    }
+
+    #[test]
+    fn test_convert_into_nothing_is_refused() {
+        let mut exchange = exchange();
+        let mut rates = RateTable::new();
+        rates.insert(usd(), eur(), dec!(0.0004), Timestamp::from_millis(0));
+        exchange.set_rate_table(rates);
+
+        // 0.1 USD converts into 0.00004 EUR, which rounds to nothing
+        assert!(matches!(
+            exchange.process_transaction(convert(2, dec!(0.1))),
+            Err(ProcessTransactionError::ConversionTooSmall)
+        ));
+        let client = exchange.client(CLIENT).unwrap();
+        assert_eq!(client.balance(usd()).available, dec!(10));
+
+        exchange.process_transaction(convert(2, dec!(0.2))).unwrap();
+        let client = exchange.client(CLIENT).unwrap();
+        assert_eq!(client.balance(eur()).available, dec!(0.0001));
+    }
+
+    #[test]
+    fn test_convert_cannot_be_disputed() {
+        let mut exchange = exchange();
+        exchange.process_transaction(convert(2, dec!(1))).unwrap();
+
+        assert!(matches!(
+            exchange.process_transaction(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(2),
+                ClaimType::Dispute
+            )),
+            Err(ProcessTransactionError::NotDisputable)
+        ));
+    }
+
+    #[test]
+    fn test_sharded_exchange_keeps_rate_table() {
+        let mut sharded = crate::ShardedExchange::new(exchange(), NonZeroUsize::new(2).unwrap());
+        sharded.submit(convert(2, dec!(1)));
+        let exchange = sharded.finish().unwrap();
+
+        let client = exchange.client(CLIENT).unwrap();
+        assert_eq!(client.balance(eur()).available, dec!(0.9234));
+
+        // And so does the exchange put back together
+        let mut exchange = exchange;
+        exchange.process_transaction(convert(3, dec!(1))).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod fee_tests {
+    use super::*;
+    use crate::fees::Fee;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+    const VIP: ClientId = ClientId(2);
+
+    fn schedule() -> FeeSchedule {
+        let standard = Fees {
+            deposit: Fee {
+                flat: dec!(0),
+                percent: dec!(1),
+            },
+            withdrawal: Fee {
+                flat: dec!(0.5),
+                percent: dec!(0),
+            },
+        };
+        FeeSchedule::new()
+            .with_tier(FeeSchedule::DEFAULT_TIER, standard)
+            .with_tier("vip", Fees::default())
+            .with_client_tier(VIP, "vip")
+    }
+
+    fn exchange(disputed_fees: DisputedFees) -> Exchange {
+        let mut exchange = Exchange::with_event_log();
+        exchange.set_fee_schedule(schedule().with_disputed_fees(disputed_fees));
+        for (client, transaction) in [(CLIENT, 1), (VIP, 2)] {
+            let deposit =
+                TransactionRequest::deposit(client, TransactionId(transaction), dec!(10)).unwrap();
+            exchange.process_transaction(deposit).unwrap();
+        }
+        exchange
+    }
+
+    fn withdraw(exchange: &mut Exchange, amount: MonetaryAmount) -> Result<()> {
+        exchange.process_transaction(
+            TransactionRequest::withdrawal(CLIENT, TransactionId(3), amount).unwrap(),
+        )
+    }
+
+    fn claim(exchange: &mut Exchange, transaction: u32, claim_type: ClaimType) {
+        exchange
+            .process_transaction(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(transaction),
+                claim_type,
+            ))
+            .unwrap();
+    }
+
+    fn house(exchange: &Exchange) -> MonetaryAmount {
+        exchange
+            .house_balances()
+            .map(|(_, balance)| balance.fees)
+            .sum()
+    }
+
+    #[test]
+    fn test_fees_are_collected_by_the_house() {
+        let mut exchange = exchange(DisputedFees::Keep);
+
+        // The withdrawal fee has to be covered too
+        assert!(matches!(
+            withdraw(&mut exchange, dec!(9.6)),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+        withdraw(&mut exchange, dec!(9.4)).unwrap();
+
+        assert_eq!(exchange.client(CLIENT).unwrap().available(), dec!(0));
+        assert_eq!(exchange.client(VIP).unwrap().available(), dec!(10));
+        assert_eq!(
+            exchange.house_balances().collect::<Vec<_>>(),
+            vec![(
+                Asset::default(),
+                HouseBalance {
+                    fees: dec!(0.6),
+                    ..HouseBalance::default()
+                }
+            )]
+        );
+
+        // A deposit smaller than its fee is refused
+        let schedule = FeeSchedule::new().with_tier(
+            FeeSchedule::DEFAULT_TIER,
+            Fees {
+                deposit: Fee {
+                    flat: dec!(1),
+                    percent: dec!(0),
+                },
+                ..Fees::default()
+            },
+        );
+        exchange.set_fee_schedule(schedule);
+        let deposit = TransactionRequest::deposit(CLIENT, TransactionId(4), dec!(0.5)).unwrap();
+        assert!(matches!(
+            exchange.process_transaction(deposit),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_disputed_deposit_holds_what_was_credited() {
+        for (disputed_fees, house_after) in [
+            (DisputedFees::Keep, dec!(0.1)),
+            (DisputedFees::Refund, dec!(0)),
+        ] {
+            let mut exchange = exchange(disputed_fees);
+            claim(&mut exchange, 1, ClaimType::Dispute);
+            let client = exchange.client(CLIENT).unwrap();
+            assert_eq!(client.available(), dec!(0));
+            assert_eq!(client.held(), dec!(9.9));
+
+            claim(&mut exchange, 1, ClaimType::Chargeback);
+            assert_eq!(exchange.client(CLIENT).unwrap().total(), dec!(0));
+            assert_eq!(house(&exchange), house_after, "{:?}", disputed_fees);
+        }
+    }
+
+    #[test]
+    fn test_chargeback_of_withdrawal_refunds_fee() {
+        for (disputed_fees, available, house_after) in [
+            (DisputedFees::Keep, dec!(4.4), dec!(0.6)),
+            (DisputedFees::Refund, dec!(4.9), dec!(0.1)),
+        ] {
+            let mut exchange = exchange(disputed_fees);
+            withdraw(&mut exchange, dec!(5)).unwrap();
+            claim(&mut exchange, 3, ClaimType::Dispute);
+            claim(&mut exchange, 3, ClaimType::Chargeback);
+
+            let client = exchange.client(CLIENT).unwrap();
+            assert_eq!(
+                client.available(),
+                available + dec!(5),
+                "{:?}",
+                disputed_fees
+            );
+            assert_eq!(house(&exchange), house_after, "{:?}", disputed_fees);
+        }
+    }
+
+    #[test]
+    fn test_fee_is_refunded_by_the_last_partial_chargeback() {
+        let mut exchange = exchange(DisputedFees::Refund);
+        withdraw(&mut exchange, dec!(5)).unwrap();
+        let mut partial_claim = |claim_type, amount| {
+            let claim =
+                TransactionRequest::partial_claim(CLIENT, TransactionId(3), claim_type, amount);
+            exchange.process_transaction(claim.unwrap()).unwrap();
+        };
+        partial_claim(ClaimType::Dispute, dec!(5));
+        partial_claim(ClaimType::Chargeback, dec!(2));
+
+        // Only the amount charged back so far, not the fee
+        assert_eq!(exchange.client(CLIENT).unwrap().available(), dec!(6.4));
+        assert_eq!(house(&exchange), dec!(0.6));
+
+        exchange.clients.get_mut(&CLIENT).unwrap().status = AccountStatus::Active;
+        claim(&mut exchange, 3, ClaimType::Chargeback);
+        assert_eq!(exchange.client(CLIENT).unwrap().available(), dec!(9.9));
+        assert_eq!(house(&exchange), dec!(0.1));
+    }
+
+    #[test]
+    fn test_snapshot_replay_and_shards_keep_fees() {
+        let mut exchange = exchange(DisputedFees::Refund);
+        withdraw(&mut exchange, dec!(5)).unwrap();
+
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        assert_eq!(house(&replayed), dec!(0.6));
+
+        let mut saved = Vec::new();
+        exchange.save_snapshot(&mut saved).unwrap();
+        let mut loaded = Exchange::load_snapshot(saved.as_slice()).unwrap();
+        assert_eq!(house(&loaded), dec!(0.6));
+
+        // The fee recorded with the withdrawal is refunded even though the schedule wasn't saved
+        loaded.set_fee_schedule(FeeSchedule::new().with_disputed_fees(DisputedFees::Refund));
+        claim(&mut loaded, 3, ClaimType::Dispute);
+        claim(&mut loaded, 3, ClaimType::Chargeback);
+        assert_eq!(loaded.client(CLIENT).unwrap().available(), dec!(9.9));
+        assert_eq!(house(&loaded), dec!(0.1));
+
+        let mut sharded = crate::ShardedExchange::new(exchange, NonZeroUsize::new(2).unwrap());
+        for client in [CLIENT, VIP] {
+            let deposit = TransactionRequest::deposit(
+                client,
+                TransactionId(10 + u32::from(client.0)),
+                dec!(100),
+            );
+            sharded.submit(deposit.unwrap());
+        }
+        let exchange = sharded.finish().unwrap();
+        assert_eq!(house(&exchange), dec!(1.6));
+    }
+}
+
+#[cfg(test)]
+mod house_tests {
+    use super::*;
+    use crate::fees::Fee;
+    use rust_decimal::dec;
+
+    fn exchange(disputed_fees: DisputedFees) -> Exchange {
+        let fees = Fees {
+            deposit: Fee {
+                flat: dec!(0),
+                percent: dec!(1),
+            },
+            withdrawal: Fee {
+                flat: dec!(0.5),
+                percent: dec!(0),
+            },
+        };
+        let mut exchange = Exchange::with_event_log();
+        exchange.set_fee_schedule(
+            FeeSchedule::new()
+                .with_tier(FeeSchedule::DEFAULT_TIER, fees)
+                .with_disputed_fees(disputed_fees),
+        );
+        exchange
+    }
+
+    fn process(exchange: &mut Exchange, request: Result<TransactionRequest>) {
+        exchange.process_transaction(request.unwrap()).unwrap();
+    }
+
+    fn charge_back(exchange: &mut Exchange, client: ClientId, transaction: u32) {
+        for claim_type in [ClaimType::Dispute, ClaimType::Chargeback] {
+            let claim = TransactionRequest::claim(client, TransactionId(transaction), claim_type);
+            process(exchange, Ok(claim));
+        }
+    }
+
+    /// What the clients and the house hold between them in the default asset.
+    fn holdings(exchange: &Exchange) -> MonetaryAmount {
+        let clients = exchange
+            .clients()
+            .map(|client| client.total())
+            .sum::<MonetaryAmount>();
+        let house = exchange
+            .house_balances()
+            .map(|(_, balance)| balance.total().unwrap())
+            .sum::<MonetaryAmount>();
+        clients + house
+    }
+
+    fn report(exchange: &Exchange) -> String {
+        let mut output = Vec::new();
+        exchange.write_house_report(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_chargebacks_are_balanced_by_the_house() {
+        for (disputed_fees, fees, chargebacks) in [
+            (DisputedFees::Keep, dec!(2), dec!(89)),
+            (DisputedFees::Refund, dec!(0.5), dec!(90)),
+        ] {
+            let mut exchange = exchange(disputed_fees);
+            process(
+                &mut exchange,
+                TransactionRequest::deposit(ClientId(1), TransactionId(1), dec!(100)),
+            );
+            process(
+                &mut exchange,
+                TransactionRequest::deposit(ClientId(2), TransactionId(2), dec!(50)),
+            );
+            process(
+                &mut exchange,
+                TransactionRequest::withdrawal(ClientId(2), TransactionId(3), dec!(10)),
+            );
+            charge_back(&mut exchange, ClientId(1), 1);
+            charge_back(&mut exchange, ClientId(2), 3);
+
+            let (_, house) = exchange.house_balances().next().unwrap();
+            assert_eq!(house.fees, fees, "{:?}", disputed_fees);
+            assert_eq!(house.chargebacks, chargebacks, "{:?}", disputed_fees);
+            // Chargebacks only move funds between the clients and the house
+            assert_eq!(holdings(&exchange), dec!(140), "{:?}", disputed_fees);
+
+            let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+            assert_eq!(
+                replayed.house_balances().collect::<Vec<_>>(),
+                exchange.house_balances().collect::<Vec<_>>()
+            );
+
+            let mut saved = Vec::new();
+            exchange.save_snapshot(&mut saved).unwrap();
+            let loaded = Exchange::load_snapshot(saved.as_slice()).unwrap();
+            assert_eq!(report(&loaded), report(&exchange));
+        }
+    }
+
+    #[test]
+    fn test_house_report() {
+        let usd = "USD".parse::<Asset>().unwrap();
+        let eur = "EUR".parse::<Asset>().unwrap();
+        let mut rates = RateTable::new();
+        rates.insert(usd, eur, dec!(0.9), Timestamp::from_millis(0));
+
+        let mut exchange = exchange(DisputedFees::Keep);
+        exchange.set_rate_table(rates);
+        process(
+            &mut exchange,
+            TransactionRequest::deposit(ClientId(1), TransactionId(1), dec!(10))
+                .map(|request| request.with_asset(usd)),
+        );
+        process(
+            &mut exchange,
+            TransactionRequest::convert(ClientId(1), TransactionId(2), usd, eur, dec!(4)),
+        );
+        process(
+            &mut exchange,
+            TransactionRequest::deposit(ClientId(2), TransactionId(3), dec!(5)),
+        );
+
+        assert_eq!(
+            report(&exchange),
+            "asset,clients,fees,chargebacks,conversions,house,holdings\n\
+             ,4.95,0.05,0,0,0.05,5.00\n\
+             EUR,3.6,0,0,-3.6,-3.6,0.0\n\
+             USD,5.90,0.10,0,4,4.10,10.00\n"
+        );
+        assert_eq!(
+            report(&Exchange::new()),
+            "asset,clients,fees,chargebacks,conversions,house,holdings\n"
+        );
+    }
+
+    #[test]
+    fn test_house_overflow_merging_shards() {
+        let (mut shards, unsharded) = Exchange::new().into_shards(2, |client| client.0 as usize);
+        for shard in &mut shards {
+            shard.house.insert(
+                Asset::default(),
+                HouseBalance {
+                    fees: MonetaryAmount::MAX - dec!(1),
+                    ..HouseBalance::default()
+                },
+            );
+        }
+        // Each shard's account fits, but they don't fit together
+        assert!(matches!(
+            Exchange::from_shards(shards, unsharded),
+            Err(ProcessError::Overflow(_))
+        ));
+
+        let mut exchange = Exchange::new();
+        exchange.house.insert(
+            Asset::default(),
+            HouseBalance {
+                fees: MonetaryAmount::MAX,
+                chargebacks: MonetaryAmount::MAX,
+                conversions: MonetaryAmount::ZERO,
+            },
+        );
+        assert!(matches!(
+            exchange.house.values().next().unwrap().total(),
+            Err(ProcessTransactionError::Overflow)
+        ));
+        assert!(matches!(
+            exchange.write_house_report(Vec::new()),
+            Err(ProcessError::Overflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_fee_overflow_changes_nothing() {
+        let fees = Fees {
+            deposit: Fee {
+                flat: dec!(30000000000000000000000000000),
+                percent: dec!(0),
+            },
+            withdrawal: Fee::default(),
+        };
+        let mut exchange = Exchange::with_event_log();
+        exchange.set_fee_schedule(FeeSchedule::new().with_tier(FeeSchedule::DEFAULT_TIER, fees));
+        let amount = dec!(70000000000000000000000000000);
+        for client in [1, 2] {
+            process(
+                &mut exchange,
+                TransactionRequest::deposit(ClientId(client), TransactionId(client.into()), amount),
+            );
+        }
+
+        // The third fee would overflow the house's fees account
+        let deposit = TransactionRequest::deposit(ClientId(3), TransactionId(3), amount).unwrap();
+        assert!(matches!(
+            exchange.process_transaction(deposit),
+            Err(ProcessTransactionError::Overflow)
+        ));
+        assert_eq!(exchange.client(ClientId(3)).unwrap().total(), dec!(0));
 
-        assert_eq!(client.available, first_deposit + second_deposit);
-        assert_eq!(client.transactions.len(), 2);
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        assert_eq!(
+            replayed.client(ClientId(3)).unwrap().snapshots(),
+            exchange.client(ClientId(3)).unwrap().snapshots()
+        );
     }
 
     #[test]
-    fn test_withdrawal_decreases_available_balance() {
-        let mut client: Client = Client::new();
-        let initial_deposit = dec!(1.0000);
-        let withdrawal_amount = dec!(0.3000);
+    fn test_house_overflow_changes_nothing() {
+        let usd = "USD".parse::<Asset>().unwrap();
+        let eur = "EUR".parse::<Asset>().unwrap();
+        let mut rates = RateTable::new();
+        rates.insert(usd, eur, dec!(1), Timestamp::from_millis(0));
 
-        client
-            .process_monetary_request(
-                TransactionId(1),
-                MonetaryTransaction::Deposit(initial_deposit),
-            )
-            .unwrap();
+        let mut exchange = Exchange::with_event_log();
+        exchange.set_rate_table(rates);
+        let amount = dec!(50000000000000000000000000000);
+        for client in [1, 2] {
+            process(
+                &mut exchange,
+                TransactionRequest::deposit(ClientId(client), TransactionId(client.into()), amount)
+                    .map(|request| request.with_asset(usd)),
+            );
+        }
+        process(
+            &mut exchange,
+            TransactionRequest::convert(ClientId(1), TransactionId(3), usd, eur, amount),
+        );
 
-        client
-            .process_monetary_request(
+        // Paying out the second conversion would overflow the house's EUR account
+        let convert =
+            TransactionRequest::convert(ClientId(2), TransactionId(4), usd, eur, amount).unwrap();
+        assert!(matches!(
+            exchange.process_transaction(convert),
+            Err(ProcessTransactionError::Overflow)
+        ));
+        let client = exchange.client(ClientId(2)).unwrap();
+        assert_eq!(client.balance(usd).available, amount);
+        assert_eq!(client.balance(eur).available, dec!(0));
+
+        // Taking back the second deposit would overflow the house's chargebacks account
+        exchange.house.entry(usd).or_default().chargebacks = MonetaryAmount::MAX;
+        process(
+            &mut exchange,
+            Ok(TransactionRequest::claim(
+                ClientId(2),
                 TransactionId(2),
-                MonetaryTransaction::Withdrawal(withdrawal_amount),
-            )
-            .unwrap();
+                ClaimType::Dispute,
+            )),
+        );
+        let chargeback =
+            TransactionRequest::claim(ClientId(2), TransactionId(2), ClaimType::Chargeback);
+        assert!(matches!(
+            exchange.process_transaction(chargeback),
+            Err(ProcessTransactionError::Overflow)
+        ));
+        let client = exchange.client(ClientId(2)).unwrap();
+        assert_eq!(client.balance(usd).held, amount);
+        assert_eq!(client.status(), AccountStatus::Active);
 
-        assert_eq!(client.available, initial_deposit - withdrawal_amount);
-        assert_eq!(client.transactions.len(), 2);
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        for client in [ClientId(1), ClientId(2)] {
+            assert_eq!(
+                replayed.client(client).unwrap().snapshots(),
+                exchange.client(client).unwrap().snapshots()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod admin_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+    const OPERATOR: OperatorId = OperatorId(7);
+
+    fn exchange() -> Exchange {
+        let mut exchange = Exchange::with_event_log();
+        exchange.set_operators([OPERATOR]);
+        let deposit = TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(10)).unwrap();
+        exchange.process_transaction(deposit).unwrap();
+        exchange
+    }
+
+    fn admin(exchange: &mut Exchange, transaction: u32, action: AdminAction) -> Result<()> {
+        exchange.process_transaction(TransactionRequest::admin(
+            CLIENT,
+            TransactionId(transaction),
+            action,
+            OPERATOR,
+            "risk".parse().unwrap(),
+        ))
+    }
+
+    fn deposit(exchange: &mut Exchange, transaction: u32) -> Result<()> {
+        exchange.process_transaction(
+            TransactionRequest::deposit(CLIENT, TransactionId(transaction), dec!(1)).unwrap(),
+        )
+    }
+
+    fn status(exchange: &Exchange) -> AccountStatus {
+        exchange.client(CLIENT).unwrap().status()
     }
 
     #[test]
-    fn test_withdrawal_with_insufficient_funds_fails() {
-        let mut client = Client::new();
-        client.available = dec!(0.5);
+    fn test_unlock_after_chargeback() {
+        let mut exchange = exchange();
+        for claim_type in [ClaimType::Dispute, ClaimType::Chargeback] {
+            let claim = TransactionRequest::claim(CLIENT, TransactionId(1), claim_type);
+            exchange.process_transaction(claim).unwrap();
+        }
+        assert_eq!(status(&exchange), AccountStatus::Locked);
+        assert!(matches!(
+            deposit(&mut exchange, 2),
+            Err(ProcessTransactionError::ClientLocked)
+        ));
 
-        let withdrawal_request = client
-            .process_monetary_request(TransactionId(2), MonetaryTransaction::Withdrawal(dec!(1.0)));
+        let unauthorized = TransactionRequest::admin(
+            CLIENT,
+            TransactionId(100),
+            AdminAction::Unlock,
+            OperatorId(8),
+            "risk".parse().unwrap(),
+        );
+        assert!(matches!(
+            exchange.process_transaction(unauthorized),
+            Err(ProcessTransactionError::UnauthorizedOperator)
+        ));
+
+        admin(&mut exchange, 100, AdminAction::Unlock).unwrap();
+        assert_eq!(status(&exchange), AccountStatus::Active);
+        assert!(!exchange.client(CLIENT).unwrap().is_locked());
+        deposit(&mut exchange, 2).unwrap();
 
         assert!(matches!(
-            withdrawal_request.unwrap_err(),
-            ProcessTransactionError::InsufficientFunds
+            admin(&mut exchange, 101, AdminAction::Unlock),
+            Err(ProcessTransactionError::NotLocked)
         ));
+
+        let changes = exchange
+            .event_log()
+            .unwrap()
+            .events()
+            .iter()
+            .filter_map(|event| match event.kind {
+                EventKind::Changed(StateChange::StatusChanged {
+                    status,
+                    operator,
+                    reason,
+                    ..
+                }) => Some((status, operator, reason.to_string())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                (AccountStatus::Locked, None, "chargeback".to_string()),
+                (AccountStatus::Active, Some(OPERATOR), "risk".to_string()),
+            ]
+        );
     }
 
     #[test]
-    fn test_locked_client_cannot_process_transactions() {
-        let mut client = Client::new();
-        client.locked = true;
+    fn test_freeze_and_close() {
+        let mut exchange = exchange();
+        admin(&mut exchange, 100, AdminAction::Freeze).unwrap();
+        assert!(matches!(
+            deposit(&mut exchange, 2),
+            Err(ProcessTransactionError::ClientFrozen)
+        ));
+        assert!(matches!(
+            admin(&mut exchange, 101, AdminAction::Freeze),
+            Err(ProcessTransactionError::AlreadyFrozen)
+        ));
 
-        let deposit_result = client
-            .process_monetary_request(TransactionId(1), MonetaryTransaction::Deposit(dec!(10.00)));
+        // Only an empty account can be closed
         assert!(matches!(
-            deposit_result.unwrap_err(),
-            ProcessTransactionError::ClientLocked
+            admin(&mut exchange, 102, AdminAction::Close),
+            Err(ProcessTransactionError::BalanceNotZero)
         ));
+        admin(&mut exchange, 103, AdminAction::Unlock).unwrap();
+        let withdrawal = TransactionRequest::withdrawal(CLIENT, TransactionId(2), dec!(10));
+        exchange.process_transaction(withdrawal.unwrap()).unwrap();
+        admin(&mut exchange, 104, AdminAction::Close).unwrap();
 
-        let withdrawal_result = client.process_monetary_request(
-            TransactionId(2),
-            MonetaryTransaction::Withdrawal(dec!(10.00)),
-        );
+        assert!(matches!(
+            deposit(&mut exchange, 3),
+            Err(ProcessTransactionError::ClientClosed)
+        ));
+        assert!(matches!(
+            admin(&mut exchange, 105, AdminAction::Unlock),
+            Err(ProcessTransactionError::ClientClosed)
+        ));
 
+        let other = TransactionRequest::deposit(ClientId(2), TransactionId(4), dec!(1)).unwrap();
+        exchange.process_transaction(other).unwrap();
+        let transfer = TransactionRequest::transfer(ClientId(2), TransactionId(5), CLIENT, dec!(1));
         assert!(matches!(
-            withdrawal_result.unwrap_err(),
-            ProcessTransactionError::ClientLocked
+            exchange.process_transaction(transfer.unwrap()),
+            Err(ProcessTransactionError::DestinationLocked)
         ));
-        assert_eq!(client.available, Decimal::ZERO);
-        assert!(client.transactions.is_empty());
     }
 
     #[test]
-    fn test_zero_amount_deposit_and_withdrawal() {
-        let mut client = Client::new();
-        let deposit_result = client.process_monetary_request(
-            TransactionId(1),
-            MonetaryTransaction::Deposit(Decimal::ZERO),
-        );
+    fn test_status_is_kept_by_snapshot_replay_and_shards() {
+        let mut exchange = exchange();
+        admin(&mut exchange, 100, AdminAction::Freeze).unwrap();
 
-        assert!(deposit_result.is_ok());
-        assert_eq!(client.available, Decimal::ZERO);
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        assert_eq!(status(&replayed), AccountStatus::Frozen);
 
-        let withdrawal_result = client.process_monetary_request(
-            TransactionId(2),
-            MonetaryTransaction::Withdrawal(Decimal::ZERO),
-        );
+        let mut saved = Vec::new();
+        exchange.save_snapshot(&mut saved).unwrap();
+        let loaded = Exchange::load_snapshot(saved.as_slice()).unwrap();
+        assert_eq!(status(&loaded), AccountStatus::Frozen);
 
-        assert!(withdrawal_result.is_ok());
-        assert_eq!(client.available, Decimal::ZERO);
+        // Operators are shared with the shards
+        let mut sharded = crate::ShardedExchange::new(exchange, NonZeroUsize::new(2).unwrap());
+        sharded.submit(TransactionRequest::admin(
+            CLIENT,
+            TransactionId(101),
+            AdminAction::Unlock,
+            OPERATOR,
+            "risk".parse().unwrap(),
+        ));
+        assert!(sharded.next_result().unwrap().is_ok());
+        assert_eq!(status(&sharded.finish().unwrap()), AccountStatus::Active);
+    }
+
+    #[test]
+    fn test_audit_lists_status_changes() {
+        let mut exchange = exchange();
+        let freeze = TransactionRequest::admin(
+            CLIENT,
+            TransactionId(100),
+            AdminAction::Freeze,
+            OPERATOR,
+            "fraud".parse().unwrap(),
+        )
+        .with_timestamp(Timestamp::from_millis(1000));
+        exchange.process_transaction(freeze).unwrap();
+        admin(&mut exchange, 101, AdminAction::Unlock).unwrap();
+
+        let mut audit = Vec::new();
+        exchange
+            .event_log()
+            .unwrap()
+            .write_audit(&mut audit)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(audit).unwrap(),
+            "seq,client,tx,status,operator,reason,timestamp,asset,limit\n\
+             5,1,100,frozen,7,fraud,1970-01-01T00:00:01.000Z,,\n\
+             7,1,101,active,7,risk,,,\n"
+        );
     }
 }
 
 #[cfg(test)]
-mod claim_tests {
+mod claim_policy_tests {
     use super::*;
-    use rust_decimal::{Decimal, dec};
+    use rust_decimal::dec;
 
-    const TRANSACTION_ID: TransactionId = TransactionId(1);
-    const DEPOSIT_AMOUNT: MonetaryAmount = dec!(100.00);
+    const CLIENT: ClientId = ClientId(1);
 
-    fn create_client_with_deposit_transaction() -> Client {
-        let mut client = Client::new();
-        client
-            .process_monetary_request(TRANSACTION_ID, MonetaryTransaction::Deposit(DEPOSIT_AMOUNT))
-            .expect("Failed to process deposit");
-        client
+    /// An exchange where the client has deposited 10 and withdrawn 6.
+    fn exchange(policy: StandardClaimPolicy) -> Exchange {
+        let mut exchange = Exchange::new();
+        exchange.set_claim_policy(policy);
+        let requests = [
+            TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(10)),
+            TransactionRequest::withdrawal(CLIENT, TransactionId(2), dec!(6)),
+        ];
+        for request in requests {
+            exchange.process_transaction(request.unwrap()).unwrap();
+        }
+        exchange
     }
 
-    #[test]
-    fn test_dispute_transaction() {
-        let mut client = create_client_with_deposit_transaction();
+    fn claim(exchange: &mut Exchange, transaction: u32, claim_type: ClaimType) -> Result<()> {
+        exchange.process_transaction(TransactionRequest::claim(
+            CLIENT,
+            TransactionId(transaction),
+            claim_type,
+        ))
+    }
 
-        let dispute_result = client.process_claim(TRANSACTION_ID, ClaimType::Dispute);
-        assert!(dispute_result.is_ok());
-        assert_eq!(client.held, DEPOSIT_AMOUNT);
-        assert_eq!(client.available, Decimal::ZERO);
+    fn balance(exchange: &Exchange) -> Balance {
+        exchange.client(CLIENT).unwrap().balance(Asset::default())
+    }
+
+    fn chargebacks(exchange: &Exchange) -> MonetaryAmount {
+        exchange
+            .house_balances()
+            .map(|(_, balance)| balance.chargebacks)
+            .sum()
     }
 
     #[test]
-    fn test_resolve_disputed_transaction() {
-        let mut client = create_client_with_deposit_transaction();
+    fn test_disputed_withdrawal_holds_funds() {
+        let mut exchange =
+            exchange(StandardClaimPolicy::new().with_hold_disputed_withdrawals(true));
 
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
-            .unwrap();
-        assert_eq!(client.held, DEPOSIT_AMOUNT);
-        assert_eq!(client.available, Decimal::ZERO);
+        claim(&mut exchange, 2, ClaimType::Dispute).unwrap();
+        assert_eq!(
+            balance(&exchange),
+            Balance {
+                available: dec!(4),
+                held: dec!(6),
+            }
+        );
+        // The clients and the house still add up to what was deposited less what was withdrawn
+        assert_eq!(chargebacks(&exchange), dec!(-6));
 
-        let resolve_result = client.process_claim(TRANSACTION_ID, ClaimType::Resolve);
-        assert!(resolve_result.is_ok());
-        assert_eq!(client.held, Decimal::ZERO);
-        assert_eq!(client.available, DEPOSIT_AMOUNT);
+        claim(&mut exchange, 2, ClaimType::Chargeback).unwrap();
+        assert_eq!(
+            balance(&exchange),
+            Balance {
+                available: dec!(10),
+                held: dec!(0),
+            }
+        );
+        assert_eq!(chargebacks(&exchange), dec!(-6));
+        assert!(exchange.client(CLIENT).unwrap().is_locked());
     }
 
     #[test]
-    fn test_chargeback_disputed_transaction() {
-        let mut client = create_client_with_deposit_transaction();
+    fn test_chargeback_without_locking() {
+        let mut exchange = exchange(StandardClaimPolicy::new().with_lock_on_chargeback(false));
 
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
-            .unwrap();
-        assert_eq!(client.held, DEPOSIT_AMOUNT);
-        assert_eq!(client.available, Decimal::ZERO);
-        assert!(!client.locked);
+        for claim_type in [ClaimType::Dispute, ClaimType::Chargeback] {
+            claim(&mut exchange, 1, claim_type).unwrap();
+        }
+        assert_eq!(
+            exchange.client(CLIENT).unwrap().status(),
+            AccountStatus::Active
+        );
+        assert_eq!(balance(&exchange).total(), dec!(-6));
 
-        let chargeback_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
-        assert!(chargeback_result.is_ok());
-        assert_eq!(client.held, Decimal::ZERO);
-        assert_eq!(client.available, Decimal::ZERO);
-        assert!(client.locked);
+        let deposit = TransactionRequest::deposit(CLIENT, TransactionId(3), dec!(6)).unwrap();
+        exchange.process_transaction(deposit).unwrap();
     }
 
     #[test]
-    fn test_resolve_or_chargeback_without_dispute_fails() {
-        let mut client = create_client_with_deposit_transaction();
+    fn test_disputes_refused_when_available_would_go_negative() {
+        let policy = StandardClaimPolicy::new().with_allow_negative_available(false);
+        let mut exchange = exchange(policy);
 
-        let resolve_result = client.process_claim(TRANSACTION_ID, ClaimType::Resolve);
         assert!(matches!(
-            resolve_result.unwrap_err(),
-            ProcessTransactionError::InvalidOperation(_)
+            claim(&mut exchange, 1, ClaimType::Dispute),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
         ));
+        let partial = TransactionRequest::partial_claim(
+            CLIENT,
+            TransactionId(1),
+            ClaimType::Dispute,
+            dec!(4),
+        );
+        exchange.process_transaction(partial.unwrap()).unwrap();
+        assert_eq!(
+            balance(&exchange),
+            Balance {
+                available: dec!(0),
+                held: dec!(4),
+            }
+        );
 
-        let chargeback_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
+        // Each shard uses the same policy
+        let mut sharded = crate::ShardedExchange::new(exchange, NonZeroUsize::new(2).unwrap());
+        let resolve = TransactionRequest::claim(CLIENT, TransactionId(1), ClaimType::Resolve);
+        let dispute = TransactionRequest::claim(CLIENT, TransactionId(1), ClaimType::Dispute);
+        sharded.submit(resolve);
+        sharded.submit(dispute);
+        assert!(sharded.next_result().unwrap().is_ok());
         assert!(matches!(
-            chargeback_result.unwrap_err(),
-            ProcessTransactionError::InvalidOperation(_)
+            sharded.next_result().unwrap(),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
         ));
     }
+}
+
+#[cfg(test)]
+mod deficit_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+
+    fn at(millis: i64) -> Timestamp {
+        Timestamp::from_millis(millis)
+    }
+
+    /// The client spends most of a deposit, which is then disputed, taking them into deficit.
+    fn requests() -> Vec<TransactionRequest> {
+        let deposit = |tx, amount| TransactionRequest::deposit(CLIENT, TransactionId(tx), amount);
+        let claim = |claim_type| TransactionRequest::claim(CLIENT, TransactionId(1), claim_type);
+        vec![
+            deposit(1, dec!(10)).unwrap().with_timestamp(at(1000)),
+            TransactionRequest::withdrawal(CLIENT, TransactionId(2), dec!(8))
+                .unwrap()
+                .with_timestamp(at(2000)),
+            claim(ClaimType::Dispute).with_timestamp(at(3000)),
+            deposit(3, dec!(3)).unwrap().with_timestamp(at(4000)),
+        ]
+    }
+
+    fn exchange() -> Exchange {
+        let mut exchange = Exchange::with_event_log();
+        for request in requests() {
+            exchange.process_transaction(request).unwrap();
+        }
+        exchange
+    }
+
+    fn deficits(exchange: &Exchange) -> Vec<Deficit> {
+        exchange.client(CLIENT).unwrap().deficits().to_vec()
+    }
+
+    fn report(exchange: &Exchange) -> String {
+        let mut report = Vec::new();
+        exchange.write_collections_report(&mut report).unwrap();
+        String::from_utf8(report).unwrap()
+    }
 
     #[test]
-    fn test_dispute_on_disputed_transaction_fails() {
-        let mut client = create_client_with_deposit_transaction();
+    fn test_deficit_is_tracked_until_it_recovers() {
+        let mut exchange = exchange();
 
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
+        let open = Deficit {
+            asset: Asset::default(),
+            peak: dec!(-8),
+            transactions: vec![TransactionId(1)],
+            since: Some(at(3000)),
+            recovered: false,
+            until: None,
+        };
+        assert_eq!(deficits(&exchange), std::slice::from_ref(&open));
+        assert_eq!(exchange.client(CLIENT).unwrap().available(), dec!(-5));
+        assert_eq!(
+            open.duration(exchange.latest_timestamp()),
+            Some(std::time::Duration::from_secs(1))
+        );
+
+        let resolve = TransactionRequest::claim(CLIENT, TransactionId(1), ClaimType::Resolve);
+        exchange
+            .process_transaction(resolve.with_timestamp(at(6000)))
             .unwrap();
+        let recovered = Deficit {
+            recovered: true,
+            until: Some(at(6000)),
+            ..open
+        };
+        assert_eq!(deficits(&exchange), std::slice::from_ref(&recovered));
+        // Measured to when it ended, however much later it is now
+        assert_eq!(
+            recovered.duration(Some(at(60_000))),
+            Some(std::time::Duration::from_secs(3))
+        );
+    }
 
-        let dispute_again_result = client.process_claim(TRANSACTION_ID, ClaimType::Dispute);
+    #[test]
+    fn test_each_deficit_records_the_transactions_that_caused_it() {
+        let mut exchange = exchange();
+
+        // A withdrawal can't take a client further into deficit
+        let withdrawal = TransactionRequest::withdrawal(CLIENT, TransactionId(4), dec!(1));
         assert!(matches!(
-            dispute_again_result.unwrap_err(),
-            ProcessTransactionError::InvalidOperation(_)
+            exchange.process_transaction(withdrawal.unwrap()),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
         ));
+
+        let requests = [
+            TransactionRequest::partial_claim(
+                CLIENT,
+                TransactionId(1),
+                ClaimType::Resolve,
+                dec!(7),
+            ),
+            TransactionRequest::deposit(CLIENT, TransactionId(5), dec!(2)),
+            TransactionRequest::withdrawal(CLIENT, TransactionId(6), dec!(4)),
+            Ok(TransactionRequest::claim(
+                CLIENT,
+                TransactionId(3),
+                ClaimType::Dispute,
+            )),
+        ];
+        for request in requests {
+            exchange.process_transaction(request.unwrap()).unwrap();
+        }
+
+        let deficits = deficits(&exchange);
+        assert_eq!(deficits.len(), 2);
+        assert!(deficits[0].recovered);
+        // Without a timestamp, when it ended isn't known
+        assert_eq!(deficits[0].until, None);
+        assert_eq!(deficits[1].transactions, [TransactionId(3)]);
+        assert_eq!(deficits[1].peak, dec!(-3));
+        assert_eq!(deficits[1].duration(exchange.latest_timestamp()), None);
+
+        assert_eq!(
+            report(&exchange),
+            concat!(
+                "client,asset,available,total,peak,since,until,duration_ms,recovered,transactions\n",
+                "1,,-3,3,-8,1970-01-01T00:00:03.000Z,,,true,1\n",
+                "1,,-3,3,-3,,,,false,3\n",
+            )
+        );
     }
 
     #[test]
-    fn test_dispute_on_resolved_transaction() {
-        let mut client = create_client_with_deposit_transaction();
+    fn test_deficits_survive_replay_and_snapshots() {
+        let exchange = exchange();
 
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
-            .unwrap();
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Resolve)
-            .unwrap();
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        assert_eq!(deficits(&replayed), deficits(&exchange));
+        assert_eq!(replayed.latest_timestamp(), Some(at(4000)));
 
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
-            .unwrap();
-        assert_eq!(client.held, DEPOSIT_AMOUNT);
-        assert_eq!(client.available, Decimal::ZERO);
+        let mut snapshot = Vec::new();
+        exchange.save_snapshot(&mut snapshot).unwrap();
+        let loaded = Exchange::load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(report(&loaded), report(&exchange));
+    }
+}
+
+#[cfg(test)]
+mod credit_limit_tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    const CLIENT: ClientId = ClientId(1);
+    const OPERATOR: OperatorId = OperatorId(7);
+
+    fn exchange() -> Exchange {
+        let mut exchange = Exchange::with_event_log();
+        exchange.set_operators([OPERATOR]);
+        exchange.set_credit_limits(CreditLimits::new().with_limit(
+            CLIENT,
+            Asset::default(),
+            dec!(100),
+        ));
+        let deposit = TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(10)).unwrap();
+        exchange.process_transaction(deposit).unwrap();
+        exchange
+    }
+
+    fn withdraw(exchange: &mut Exchange, transaction: u32, amount: MonetaryAmount) -> Result<()> {
+        exchange.process_transaction(
+            TransactionRequest::withdrawal(CLIENT, TransactionId(transaction), amount).unwrap(),
+        )
+    }
+
+    fn set_limit(exchange: &mut Exchange, transaction: u32, limit: MonetaryAmount) -> Result<()> {
+        exchange.process_transaction(
+            TransactionRequest::set_limit(
+                CLIENT,
+                TransactionId(transaction),
+                limit,
+                OPERATOR,
+                "overdraft".parse().unwrap(),
+            )
+            .unwrap(),
+        )
+    }
+
+    fn snapshots(exchange: &Exchange) -> Vec<ClientSnapshot> {
+        let mut snapshots = Vec::new();
+        exchange.write_balances(&mut snapshots).unwrap();
+        snapshots
     }
 
     #[test]
-    fn test_chargeback_on_resolved_or_undisputed_transaction_fails() {
-        let mut client = create_client_with_deposit_transaction();
+    fn test_withdrawals_down_to_minus_the_limit() {
+        let mut exchange = exchange();
+
+        withdraw(&mut exchange, 2, dec!(60)).unwrap();
+        withdraw(&mut exchange, 3, dec!(50)).unwrap();
+        let client = exchange.client(CLIENT).unwrap();
+        assert_eq!(client.available(), dec!(-100));
+        assert_eq!(client.headroom(Asset::default()), dec!(0));
 
-        let chargeback_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
         assert!(matches!(
-            chargeback_result.unwrap_err(),
-            ProcessTransactionError::InvalidOperation(_)
+            withdraw(&mut exchange, 4, dec!(0.0001)),
+            Err(ProcessTransactionError::InsufficientFunds {
+                requested,
+                available,
+            }) if requested == dec!(0.0001) && available == dec!(0)
         ));
 
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
-            .unwrap();
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Resolve)
-            .unwrap();
-
-        let chargeback_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
+        // Other clients and assets have no limit
+        let other = TransactionRequest::withdrawal(ClientId(2), TransactionId(5), dec!(1));
         assert!(matches!(
-            chargeback_result.unwrap_err(),
-            ProcessTransactionError::InvalidOperation(_)
+            exchange.process_transaction(other.unwrap()),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+        let btc = TransactionRequest::withdrawal(CLIENT, TransactionId(6), dec!(1))
+            .unwrap()
+            .with_asset("BTC".parse().unwrap());
+        assert!(matches!(
+            exchange.process_transaction(btc),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
         ));
     }
 
     #[test]
-    fn test_claim_on_chargebacked_transaction_fails() {
-        let mut client = create_client_with_deposit_transaction();
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Dispute)
-            .unwrap();
-        client
-            .process_claim(TRANSACTION_ID, ClaimType::Chargeback)
-            .unwrap();
+    fn test_rejected_withdrawal_reports_requested_and_available() {
+        let mut exchange = Exchange::new();
+        let deposit = TransactionRequest::deposit(CLIENT, TransactionId(1), dec!(10)).unwrap();
+        exchange.process_transaction(deposit).unwrap();
 
-        let chargeback_again_result = client.process_claim(TRANSACTION_ID, ClaimType::Chargeback);
+        let error = withdraw(&mut exchange, 2, dec!(12.5)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Insufficient funds: 12.5 requested, 10 available"
+        );
+    }
+
+    #[test]
+    fn test_operator_limit_overrides_configured_limit() {
+        let mut exchange = exchange();
+
+        let unauthorized = TransactionRequest::set_limit(
+            CLIENT,
+            TransactionId(2),
+            dec!(1000),
+            OperatorId(8),
+            "overdraft".parse().unwrap(),
+        );
         assert!(matches!(
-            chargeback_again_result.unwrap_err(),
-            ProcessTransactionError::ClientLocked
+            exchange.process_transaction(unauthorized.unwrap()),
+            Err(ProcessTransactionError::UnauthorizedOperator)
         ));
 
-        let resolve_result = client.process_claim(TRANSACTION_ID, ClaimType::Resolve);
+        set_limit(&mut exchange, 3, dec!(0)).unwrap();
+        assert_eq!(
+            exchange
+                .client(CLIENT)
+                .unwrap()
+                .credit_limit(Asset::default()),
+            dec!(0)
+        );
         assert!(matches!(
-            resolve_result.unwrap_err(),
-            ProcessTransactionError::ClientLocked
+            withdraw(&mut exchange, 4, dec!(11)),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
         ));
 
-        let dispute_result = client.process_claim(TRANSACTION_ID, ClaimType::Dispute);
+        set_limit(&mut exchange, 5, dec!(20)).unwrap();
+        withdraw(&mut exchange, 6, dec!(30)).unwrap();
+        assert_eq!(
+            snapshots(&exchange),
+            [ClientSnapshot {
+                client_id: CLIENT,
+                asset: Asset::default(),
+                available: dec!(-20),
+                held: dec!(0),
+                total: dec!(-20),
+                locked: false,
+                headroom: Some(dec!(0)),
+            }]
+        );
+
+        // Every change is audited, including the one that took the overdraft away
+        let mut audit = Vec::new();
+        exchange
+            .event_log()
+            .unwrap()
+            .write_audit(&mut audit)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(audit).unwrap(),
+            "seq,client,tx,status,operator,reason,timestamp,asset,limit\n\
+             5,1,3,,7,overdraft,,,0\n\
+             7,1,5,,7,overdraft,,,20\n"
+        );
+    }
+
+    #[test]
+    fn test_headroom_only_given_with_a_limit() {
+        let mut exchange = exchange();
+        let deposit = TransactionRequest::deposit(ClientId(2), TransactionId(2), dec!(5)).unwrap();
+        exchange.process_transaction(deposit).unwrap();
+
+        let headroom = snapshots(&exchange)
+            .iter()
+            .map(|snapshot| snapshot.headroom)
+            .collect::<Vec<_>>();
+        assert_eq!(headroom, [Some(dec!(110)), Some(dec!(5))]);
+
+        exchange.set_credit_limits(CreditLimits::new());
+        assert!(
+            snapshots(&exchange)
+                .iter()
+                .all(|snapshot| snapshot.headroom.is_none())
+        );
+    }
+
+    #[test]
+    fn test_operator_limits_survive_replay_and_snapshots() {
+        let mut exchange = exchange();
+        set_limit(&mut exchange, 2, dec!(50)).unwrap();
+        withdraw(&mut exchange, 3, dec!(40)).unwrap();
+
+        // Replay only knows the limits operators set, not the configured ones
+        let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+        assert_eq!(snapshots(&replayed), snapshots(&exchange));
+
+        let mut snapshot = Vec::new();
+        exchange.save_snapshot(&mut snapshot).unwrap();
+        let mut loaded = Exchange::load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(snapshots(&loaded), snapshots(&exchange));
+        withdraw(&mut loaded, 4, dec!(20)).unwrap();
         assert!(matches!(
-            dispute_result.unwrap_err(),
-            ProcessTransactionError::ClientLocked
+            withdraw(&mut loaded, 5, dec!(0.0001)),
+            Err(ProcessTransactionError::InsufficientFunds { .. })
         ));
     }
 }
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    error::{FeeScheduleError, ProcessTransactionError, Result},
+    types::{ClientId, MonetaryAmount, MonetaryTransaction},
+};
+
+/// Fees charged on deposits and withdrawals, which are collected into the exchange's house
+/// account. Set with [`Exchange::set_fee_schedule`](crate::Exchange::set_fee_schedule).
+///
+/// Clients are charged the fees of their tier, or of the [`FeeSchedule::DEFAULT_TIER`] if they
+/// haven't been given one. Transfers and conversions are free.
+///
+/// ```
+/// use rust_decimal::dec;
+/// use transaction_processor::{ClientId, FeeSchedule, Fees};
+///
+/// let config = r#"{
+///     "tiers": {
+///         "default": {"deposit": {"percent": "0.5"}, "withdrawal": {"flat": "1"}},
+///         "vip": {"withdrawal": {"flat": "0.25"}}
+///     },
+///     "clients": {"7": "vip"},
+///     "disputed_fees": "refund"
+/// }"#;
+/// let fees = FeeSchedule::from_json(config.as_bytes()).unwrap();
+///
+/// assert_eq!(fees.fees(ClientId(1)).deposit.percent, dec!(0.5));
+/// assert_eq!(fees.fees(ClientId(7)).withdrawal.flat, dec!(0.25));
+/// assert_eq!(fees.fees(ClientId(7)).deposit, Default::default());
+/// ```
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    #[serde(default)]
+    tiers: HashMap<String, Fees>,
+    /// The tier of each client that isn't in the default tier.
+    #[serde(default)]
+    clients: HashMap<ClientId, String>,
+    #[serde(default)]
+    disputed_fees: DisputedFees,
+}
+
+/// The fees charged to a tier of clients.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fees {
+    /// Taken out of the amount deposited.
+    #[serde(default)]
+    pub deposit: Fee,
+    /// Taken from the client's available funds on top of the amount withdrawn.
+    #[serde(default)]
+    pub withdrawal: Fee,
+}
+
+/// A flat fee plus a percentage of the amount of a transaction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fee {
+    /// Charged in the asset of the transaction, whatever the asset.
+    #[serde(default)]
+    pub flat: MonetaryAmount,
+    #[serde(default)]
+    pub percent: Decimal,
+}
+
+/// What happens to the fee on a transaction that's charged back.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisputedFees {
+    /// The house keeps the fee.
+    #[default]
+    Keep,
+    /// The house gives the fee up: back to the client for a withdrawal, and along with the rest
+    /// of a deposit for a deposit.
+    Refund,
+}
+
+const NO_FEES: Fees = Fees {
+    deposit: Fee {
+        flat: Decimal::ZERO,
+        percent: Decimal::ZERO,
+    },
+    withdrawal: Fee {
+        flat: Decimal::ZERO,
+        percent: Decimal::ZERO,
+    },
+};
+
+impl FeeSchedule {
+    /// The tier of clients that haven't been given one.
+    pub const DEFAULT_TIER: &str = "default";
+
+    /// A schedule without any fees.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a schedule from a JSON object with optional `tiers`, mapping tier names to their
+    /// [`Fees`], `clients`, mapping client IDs to tier names, and `disputed_fees`, either `keep`
+    /// or `refund`.
+    pub fn from_json<R: std::io::Read>(rdr: R) -> std::result::Result<Self, FeeScheduleError> {
+        let schedule = serde_json::from_reader::<_, FeeSchedule>(rdr)?;
+
+        for (name, fees) in &schedule.tiers {
+            for fee in [fees.deposit, fees.withdrawal] {
+                if fee.flat.is_sign_negative() || fee.percent.is_sign_negative() {
+                    return Err(FeeScheduleError::Invalid(format!(
+                        "tier {:?} has a negative fee",
+                        name
+                    )));
+                }
+            }
+        }
+        for (client, tier) in &schedule.clients {
+            if !schedule.tiers.contains_key(tier) {
+                return Err(FeeScheduleError::Invalid(format!(
+                    "client {} is in tier {:?}, which doesn't exist",
+                    client, tier
+                )));
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Charge the clients in tier `name` `fees`.
+    pub fn with_tier(mut self, name: impl Into<String>, fees: Fees) -> Self {
+        self.tiers.insert(name.into(), fees);
+        self
+    }
+
+    /// Put `client` in tier `name`. A client in a tier that doesn't exist isn't charged anything.
+    pub fn with_client_tier(mut self, client: ClientId, name: impl Into<String>) -> Self {
+        self.clients.insert(client, name.into());
+        self
+    }
+
+    pub fn with_disputed_fees(mut self, disputed_fees: DisputedFees) -> Self {
+        self.disputed_fees = disputed_fees;
+        self
+    }
+
+    /// The fees charged to `client`.
+    pub fn fees(&self, client: ClientId) -> &Fees {
+        let tier = self
+            .clients
+            .get(&client)
+            .map_or(Self::DEFAULT_TIER, String::as_str);
+        self.tiers.get(tier).unwrap_or(&NO_FEES)
+    }
+
+    pub fn disputed_fees(&self) -> DisputedFees {
+        self.disputed_fees
+    }
+}
+
+impl Fees {
+    /// The fee charged on `transaction`, rounded to 4 decimal places like the amounts of requests.
+    pub fn fee(&self, transaction: MonetaryTransaction) -> Result<MonetaryAmount> {
+        match transaction {
+            MonetaryTransaction::Deposit(amount) => self.deposit.of(amount),
+            MonetaryTransaction::Withdrawal(amount) => self.withdrawal.of(amount),
+            MonetaryTransaction::Transfer { .. } | MonetaryTransaction::Convert { .. } => {
+                Ok(MonetaryAmount::ZERO)
+            }
+        }
+    }
+}
+
+impl Fee {
+    fn of(&self, amount: MonetaryAmount) -> Result<MonetaryAmount> {
+        amount
+            .checked_mul(self.percent)
+            .map(|fee| fee / Decimal::ONE_HUNDRED)
+            .and_then(|fee| fee.checked_add(self.flat))
+            .map(|fee| fee.round_dp(4))
+            .ok_or(ProcessTransactionError::Overflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_fee_rounds_like_amounts() {
+        let fees = Fees {
+            deposit: Fee {
+                flat: dec!(0.1),
+                percent: dec!(0.5),
+            },
+            withdrawal: Fee::default(),
+        };
+
+        // 0.5% of 1.0001 is 0.0050005
+        assert_eq!(
+            fees.fee(MonetaryTransaction::Deposit(dec!(1.0001)))
+                .unwrap(),
+            dec!(0.1050)
+        );
+        assert_eq!(
+            fees.fee(MonetaryTransaction::Withdrawal(dec!(100)))
+                .unwrap(),
+            dec!(0)
+        );
+    }
+
+    #[test]
+    fn test_clients_without_a_tier_are_in_the_default_tier() {
+        let standard = Fees {
+            withdrawal: Fee {
+                flat: dec!(1),
+                percent: dec!(0),
+            },
+            ..Fees::default()
+        };
+        let schedule = FeeSchedule::new()
+            .with_tier(FeeSchedule::DEFAULT_TIER, standard)
+            .with_tier("vip", Fees::default())
+            .with_client_tier(ClientId(2), "vip")
+            .with_client_tier(ClientId(3), "missing");
+
+        assert_eq!(*schedule.fees(ClientId(1)), standard);
+        assert_eq!(*schedule.fees(ClientId(2)), Fees::default());
+        assert_eq!(*schedule.fees(ClientId(3)), Fees::default());
+        assert_eq!(schedule.disputed_fees(), DisputedFees::Keep);
+    }
+
+    #[test]
+    fn test_invalid_schedules() {
+        for config in [
+            r#"{"tiers": {"default": {"deposit": {"flat": "-1"}}}}"#,
+            r#"{"clients": {"1": "vip"}}"#,
+        ] {
+            assert!(
+                matches!(
+                    FeeSchedule::from_json(config.as_bytes()),
+                    Err(FeeScheduleError::Invalid(_))
+                ),
+                "{}",
+                config
+            );
+        }
+
+        assert!(matches!(
+            FeeSchedule::from_json(r#"{"tier": {}}"#.as_bytes()),
+            Err(FeeScheduleError::Json(_))
+        ));
+    }
+}
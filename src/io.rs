@@ -1,10 +1,17 @@
+use csv::{ByteRecord, ReaderBuilder, StringRecord, WriterBuilder};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    error::{ProcessTransactionError, Result},
+    error::{
+        BadRow, ErrorCategory, ErrorContext, InputPosition, MalformedRecord, ProcessError,
+        ProcessTransactionError, RowError,
+    },
+    json,
     types::{
-        ClaimType, ClientId, MonetaryAmount, MonetaryTransaction, RequestType, TransactionId,
-        TransactionRequest,
+        AdminAction, Asset, ClaimRequest, ClaimType, ClientId, MonetaryAmount, MonetaryTransaction,
+        OperatorId, ReasonCode, RequestType, Timestamp, TransactionId, TransactionRequest,
+        validate_admin, validate_amount, validate_claim_amount, validate_destination,
+        validate_target_asset,
     },
 };
 
@@ -19,6 +26,18 @@ pub struct CsvRecord {
     #[serde(rename = "tx")]
     transaction: TransactionId,
     amount: Option<MonetaryAmount>,
+    #[serde(default, alias = "currency")]
+    asset: Option<Asset>,
+    #[serde(default)]
+    destination: Option<ClientId>,
+    #[serde(default, alias = "to_currency")]
+    to_asset: Option<Asset>,
+    #[serde(default)]
+    operator: Option<OperatorId>,
+    #[serde(default)]
+    reason: Option<ReasonCode>,
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,29 +45,23 @@ pub struct CsvRecord {
 enum CsvTransactionType {
     Deposit,
     Withdrawal,
+    Transfer,
+    Convert,
     Dispute,
     Resolve,
     Chargeback,
-}
-
-fn validate_amount(amount: Option<MonetaryAmount>) -> Result<MonetaryAmount> {
-    let amount = amount.ok_or(ProcessTransactionError::InvalidData(
-        "Amount is required for this transaction",
-    ))?;
-
-    if amount.is_sign_positive() {
-        Ok(amount.round_dp(4))
-    } else {
-        Err(ProcessTransactionError::InvalidData(
-            "Amount must be positive",
-        ))
-    }
+    Unlock,
+    Freeze,
+    Close,
+    #[serde(rename = "set_limit")]
+    SetLimit,
 }
 
 impl TryFrom<CsvRecord> for TransactionRequest {
     type Error = ProcessTransactionError;
 
     fn try_from(record: CsvRecord) -> std::result::Result<Self, Self::Error> {
+        let asset = record.asset.unwrap_or_default();
         let request = match record.transaction_type {
             CsvTransactionType::Deposit => RequestType::Monetary(MonetaryTransaction::Deposit(
                 validate_amount(record.amount)?,
@@ -56,65 +69,353 @@ impl TryFrom<CsvRecord> for TransactionRequest {
             CsvTransactionType::Withdrawal => RequestType::Monetary(
                 MonetaryTransaction::Withdrawal(validate_amount(record.amount)?),
             ),
-            CsvTransactionType::Dispute => RequestType::Claim(ClaimType::Dispute),
-            CsvTransactionType::Resolve => RequestType::Claim(ClaimType::Resolve),
-            CsvTransactionType::Chargeback => RequestType::Claim(ClaimType::Chargeback),
+            CsvTransactionType::Transfer => RequestType::Monetary(MonetaryTransaction::Transfer {
+                amount: validate_amount(record.amount)?,
+                destination: validate_destination(record.client, record.destination)?,
+            }),
+            CsvTransactionType::Convert => RequestType::Monetary(MonetaryTransaction::Convert {
+                amount: validate_amount(record.amount)?,
+                to: validate_target_asset(asset, record.to_asset)?,
+            }),
+            CsvTransactionType::Dispute => RequestType::Claim(ClaimRequest {
+                claim_type: ClaimType::Dispute,
+                amount: validate_claim_amount(record.amount)?,
+            }),
+            CsvTransactionType::Resolve => RequestType::Claim(ClaimRequest {
+                claim_type: ClaimType::Resolve,
+                amount: validate_claim_amount(record.amount)?,
+            }),
+            CsvTransactionType::Chargeback => RequestType::Claim(ClaimRequest {
+                claim_type: ClaimType::Chargeback,
+                amount: validate_claim_amount(record.amount)?,
+            }),
+            CsvTransactionType::Unlock => RequestType::Admin(validate_admin(
+                AdminAction::Unlock,
+                record.operator,
+                record.reason,
+            )?),
+            CsvTransactionType::Freeze => RequestType::Admin(validate_admin(
+                AdminAction::Freeze,
+                record.operator,
+                record.reason,
+            )?),
+            CsvTransactionType::Close => RequestType::Admin(validate_admin(
+                AdminAction::Close,
+                record.operator,
+                record.reason,
+            )?),
+            CsvTransactionType::SetLimit => RequestType::Admin(validate_admin(
+                AdminAction::SetLimit(validate_amount(record.amount)?),
+                record.operator,
+                record.reason,
+            )?),
         };
 
         Ok(TransactionRequest {
             client: record.client,
             transaction: record.transaction,
             request_type: request,
+            asset,
+            timestamp: record.timestamp,
         })
     }
 }
 
+/// Format of the transactions being read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// Newline-delimited JSON, one object per transaction.
+    JsonLines,
+}
+
+impl InputFormat {
+    /// Infer the format from a file extension, returning `None` if it isn't recognised.
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "csv" => Some(InputFormat::Csv),
+            "jsonl" | "ndjson" => Some(InputFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// A row read from the input, along with the request it was converted to.
+pub struct InputRow {
+    pub raw: RawRow,
+    pub position: Option<InputPosition>,
+    pub request: std::result::Result<TransactionRequest, RowError>,
+}
+
+/// A row as it was read from the input, kept so that it can be written back out if it's rejected.
+pub enum RawRow {
+    Csv {
+        record: StringRecord,
+        columns: CsvColumns,
+    },
+    Json(String),
+}
+
+impl RawRow {
+    /// The original fields of the row, as far as they can be recovered.
+    pub fn fields(&self) -> RawFields {
+        match self {
+            RawRow::Csv { record, columns } => {
+                let field = |index: Option<usize>| {
+                    index
+                        .and_then(|index| record.get(index))
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                RawFields {
+                    transaction_type: field(columns.transaction_type),
+                    client: field(columns.client),
+                    tx: field(columns.tx),
+                    amount: field(columns.amount),
+                    asset: field(columns.asset),
+                    destination: field(columns.destination),
+                    to_asset: field(columns.to_asset),
+                    operator: field(columns.operator),
+                    reason: field(columns.reason),
+                    timestamp: field(columns.timestamp),
+                }
+            }
+            RawRow::Json(line) => json::raw_fields(line),
+        }
+    }
+}
+
+/// The original fields of a row, as strings.
+#[derive(Debug, Default)]
+pub struct RawFields {
+    pub transaction_type: String,
+    pub client: String,
+    pub tx: String,
+    pub amount: String,
+    pub asset: String,
+    pub destination: String,
+    pub to_asset: String,
+    pub operator: String,
+    pub reason: String,
+    pub timestamp: String,
+}
+
+impl RawFields {
+    /// Whatever context can be recovered from the row without fully deserializing it.
+    pub fn context(&self, position: Option<InputPosition>) -> ErrorContext {
+        ErrorContext {
+            position,
+            transaction: self.tx.parse().ok().map(TransactionId),
+            client: self.client.parse().ok().map(ClientId),
+            request_type: None,
+        }
+    }
+}
+
+/// Indices of the known columns in a CSV header.
+#[derive(Debug, Clone, Copy)]
+pub struct CsvColumns {
+    transaction_type: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    asset: Option<usize>,
+    destination: Option<usize>,
+    to_asset: Option<usize>,
+    operator: Option<usize>,
+    reason: Option<usize>,
+    timestamp: Option<usize>,
+}
+
+impl CsvColumns {
+    fn new(headers: &StringRecord) -> Self {
+        let position = |name: &str| headers.iter().position(|header| header == name);
+        Self {
+            transaction_type: position("type"),
+            client: position("client"),
+            tx: position("tx"),
+            amount: position("amount"),
+            asset: position("asset").or_else(|| position("currency")),
+            destination: position("destination"),
+            to_asset: position("to_asset").or_else(|| position("to_currency")),
+            operator: position("operator"),
+            reason: position("reason"),
+            timestamp: position("timestamp"),
+        }
+    }
+}
+
+pub type InputRows<'a> = Box<dyn Iterator<Item = std::result::Result<InputRow, ProcessError>> + 'a>;
+
+/// Read transactions from `rdr` in the given format.
+///
+/// Rows that can't be converted into a request are still yielded, with the error in
+/// [`InputRow::request`]. Only failures to read the input are returned as a [`ProcessError`].
+pub fn read_transactions<'a, R: std::io::Read + 'a>(
+    rdr: R,
+    format: InputFormat,
+) -> std::result::Result<InputRows<'a>, ProcessError> {
+    match format {
+        InputFormat::Csv => read_csv(rdr),
+        InputFormat::JsonLines => Ok(Box::new(json::JsonLinesReader::new(rdr))),
+    }
+}
+
+fn read_csv<'a, R: std::io::Read + 'a>(rdr: R) -> std::result::Result<InputRows<'a>, ProcessError> {
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(rdr);
+
+    let headers = rdr.headers()?.clone();
+    let columns = CsvColumns::new(&headers);
+
+    // Rows are read as bytes so that their fields can still be recovered when they aren't valid
+    // UTF-8 or have the wrong number of fields
+    let mut bytes = ByteRecord::new();
+    Ok(Box::new(std::iter::from_fn(move || {
+        let result = rdr.read_byte_record(&mut bytes);
+        let position = bytes.position().map(InputPosition::from);
+        match result {
+            Ok(false) => None,
+            Ok(true) => Some(Ok(match StringRecord::from_byte_record(bytes.clone()) {
+                Ok(mut record) => {
+                    // Trimmed again to include Unicode whitespace, as the string records read by
+                    // the reader are
+                    record.trim();
+                    let request = record
+                        .deserialize::<CsvRecord>(Some(&headers))
+                        .map_err(|e| RowError::Malformed(MalformedRecord::Csv(e)))
+                        .and_then(|record| {
+                            TransactionRequest::try_from(record).map_err(RowError::Invalid)
+                        });
+                    InputRow {
+                        position,
+                        raw: RawRow::Csv { record, columns },
+                        request,
+                    }
+                }
+                Err(e) => {
+                    // Deserializing every field as a string reports which one isn't UTF-8
+                    let error = bytes
+                        .deserialize::<Vec<String>>(None)
+                        .err()
+                        .unwrap_or_else(|| csv::Error::from(std::io::Error::other(e)));
+                    InputRow {
+                        position,
+                        raw: RawRow::Csv {
+                            record: lossy_record(&bytes),
+                            columns,
+                        },
+                        request: Err(RowError::Malformed(MalformedRecord::Csv(error))),
+                    }
+                }
+            })),
+            Err(e) if e.is_io_error() => Some(Err(e.into())),
+            Err(e) => Some(Ok(InputRow {
+                position: e.position().map(InputPosition::from).or(position),
+                raw: RawRow::Csv {
+                    record: lossy_record(&bytes),
+                    columns,
+                },
+                request: Err(RowError::Malformed(MalformedRecord::Csv(e))),
+            })),
+        }
+    })))
+}
+
+/// The fields of a row that couldn't be read as a string record, with any invalid UTF-8 replaced.
+fn lossy_record(bytes: &ByteRecord) -> StringRecord {
+    let mut record = bytes
+        .iter()
+        .map(String::from_utf8_lossy)
+        .collect::<StringRecord>();
+    record.trim();
+    record
+}
+
+const DEAD_LETTER_HEADERS: [&str; 14] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "asset",
+    "destination",
+    "to_asset",
+    "operator",
+    "reason",
+    "timestamp",
+    "line",
+    "code",
+    "category",
+    "message",
+];
+
+/// A row that was not applied to the exchange.
+///
+/// The original fields are kept as they were read so that the row can be corrected and fed back
+/// through the processor, which ignores the extra columns.
 #[derive(Debug, Serialize)]
-pub struct OutputCsvRecord {
-    #[serde(rename = "client")]
-    pub client_id: ClientId,
-    pub available: MonetaryAmount,
-    pub held: MonetaryAmount,
-    pub total: MonetaryAmount,
-    pub locked: bool,
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rust_decimal::{Decimal, dec};
-
-    #[test]
-    fn test_validate_amount() {
-        // Standard valid decimal
-        assert_eq!(
-            validate_amount(Some(dec!(100.1234))).unwrap(),
-            dec!(100.1234)
-        );
-        // Valid decimal with more than 4 decimal places has rounding applied using banker's rounding
-        assert_eq!(
-            validate_amount(Some(dec!(100.12345))).unwrap(),
-            dec!(100.1234)
-        );
-        assert_eq!(
-            validate_amount(Some(dec!(100.12343))).unwrap(),
-            dec!(100.1234)
-        );
-        assert_eq!(
-            validate_amount(Some(dec!(100.12346))).unwrap(),
-            dec!(100.1235)
-        );
-        assert_eq!(validate_amount(Some(dec!(0.00001))).unwrap(), dec!(0.0000));
-
-        // Zero is accepted as valid
-        assert_eq!(validate_amount(Some(dec!(0.0))).unwrap(), Decimal::ZERO);
-        // Negative zero is treated as zero
-        assert_eq!(validate_amount(Some(dec!(-0))).unwrap(), Decimal::ZERO);
-
-        // Negative amounts are invalid
-        assert!(validate_amount(Some(dec!(-100.0))).is_err());
-
-        // None is invalid
-        assert!(validate_amount(None).is_err());
+struct DeadLetterRecord<'a> {
+    #[serde(rename = "type")]
+    transaction_type: &'a str,
+    client: &'a str,
+    tx: &'a str,
+    amount: &'a str,
+    asset: &'a str,
+    destination: &'a str,
+    to_asset: &'a str,
+    operator: &'a str,
+    reason: &'a str,
+    timestamp: &'a str,
+    line: Option<u64>,
+    code: &'static str,
+    category: ErrorCategory,
+    message: String,
+}
+
+/// Writes rows that were not applied to the exchange to a CSV.
+pub struct DeadLetterWriter<W: std::io::Write> {
+    wtr: csv::Writer<W>,
+    is_empty: bool,
+}
+
+impl<W: std::io::Write> DeadLetterWriter<W> {
+    pub fn new(wtr: W) -> Self {
+        Self {
+            wtr: WriterBuilder::new().has_headers(true).from_writer(wtr),
+            is_empty: true,
+        }
+    }
+
+    pub fn write(&mut self, fields: &RawFields, bad_row: &BadRow) -> csv::Result<()> {
+        let error = bad_row.error();
+        self.is_empty = false;
+        self.wtr.serialize(DeadLetterRecord {
+            transaction_type: &fields.transaction_type,
+            client: &fields.client,
+            tx: &fields.tx,
+            amount: &fields.amount,
+            asset: &fields.asset,
+            destination: &fields.destination,
+            to_asset: &fields.to_asset,
+            operator: &fields.operator,
+            reason: &fields.reason,
+            timestamp: &fields.timestamp,
+            line: bad_row.context().position.map(|position| position.line),
+            code: error.code(),
+            category: error.category(),
+            message: error.message(),
+        })
+    }
+
+    pub fn finish(mut self) -> csv::Result<()> {
+        // Ensure headers are written even if no rows were rejected
+        if self.is_empty {
+            self.wtr.write_record(DEAD_LETTER_HEADERS)?;
+        }
+        self.wtr.flush()?;
+        Ok(())
     }
 }
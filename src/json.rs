@@ -0,0 +1,605 @@
+use std::io::BufRead;
+
+use serde::Deserialize;
+
+use crate::{
+    error::{InputPosition, MalformedRecord, ProcessError, ProcessTransactionError, RowError},
+    io::{InputRow, RawFields, RawRow},
+    types::{
+        AdminAction, Asset, ClaimRequest, ClaimType, ClientId, MonetaryAmount, MonetaryTransaction,
+        OperatorId, ReasonCode, RequestType, Timestamp, TransactionId, TransactionRequest,
+        validate_admin, validate_amount, validate_claim_amount, validate_destination,
+        validate_target_asset,
+    },
+};
+
+/// A transaction as read from JSON.
+///
+/// Unlike CSV, JSON supports internally tagged enums, so only deposits, withdrawals, transfers,
+/// conversions, claims and limit changes carry an amount. It's still optional here so that a
+/// missing amount is reported the same way as for CSV, and likewise for a transfer's destination,
+/// a conversion's target asset and the operator and reason of an administrative request. A
+/// claim's amount is optional either way.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonRecord {
+    Deposit {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(default)]
+        amount: Option<MonetaryAmount>,
+        #[serde(default, alias = "currency")]
+        asset: Option<Asset>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    Withdrawal {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(default)]
+        amount: Option<MonetaryAmount>,
+        #[serde(default, alias = "currency")]
+        asset: Option<Asset>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    Transfer {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(default)]
+        amount: Option<MonetaryAmount>,
+        #[serde(default, alias = "currency")]
+        asset: Option<Asset>,
+        #[serde(default)]
+        destination: Option<ClientId>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    Convert {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(default)]
+        amount: Option<MonetaryAmount>,
+        #[serde(default, alias = "currency")]
+        asset: Option<Asset>,
+        #[serde(default, alias = "to_currency")]
+        to_asset: Option<Asset>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    Dispute {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(default)]
+        amount: Option<MonetaryAmount>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    Resolve {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(default)]
+        amount: Option<MonetaryAmount>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    Chargeback {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(default)]
+        amount: Option<MonetaryAmount>,
+        #[serde(default)]
+        timestamp: Option<Timestamp>,
+    },
+    Unlock {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(flatten)]
+        admin: JsonAdmin,
+    },
+    Freeze {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(flatten)]
+        admin: JsonAdmin,
+    },
+    Close {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(flatten)]
+        admin: JsonAdmin,
+    },
+    #[serde(rename = "set_limit")]
+    SetLimit {
+        client: ClientId,
+        tx: TransactionId,
+        #[serde(default)]
+        amount: Option<MonetaryAmount>,
+        #[serde(default, alias = "currency")]
+        asset: Option<Asset>,
+        #[serde(flatten)]
+        admin: JsonAdmin,
+    },
+}
+
+/// The fields shared by every administrative request.
+#[derive(Debug, Deserialize)]
+struct JsonAdmin {
+    #[serde(default)]
+    operator: Option<OperatorId>,
+    #[serde(default)]
+    reason: Option<ReasonCode>,
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+}
+
+impl JsonAdmin {
+    fn into_request(
+        self,
+        action: AdminAction,
+    ) -> Result<(RequestType, Option<Timestamp>), ProcessTransactionError> {
+        let admin = validate_admin(action, self.operator, self.reason)?;
+        Ok((RequestType::Admin(admin), self.timestamp))
+    }
+}
+
+impl TryFrom<JsonRecord> for TransactionRequest {
+    type Error = ProcessTransactionError;
+
+    fn try_from(record: JsonRecord) -> Result<Self, Self::Error> {
+        let (client, transaction, request_type, asset, timestamp) = match record {
+            JsonRecord::Deposit {
+                client,
+                tx,
+                amount,
+                asset,
+                timestamp,
+            } => (
+                client,
+                tx,
+                RequestType::Monetary(MonetaryTransaction::Deposit(validate_amount(amount)?)),
+                asset,
+                timestamp,
+            ),
+            JsonRecord::Withdrawal {
+                client,
+                tx,
+                amount,
+                asset,
+                timestamp,
+            } => (
+                client,
+                tx,
+                RequestType::Monetary(MonetaryTransaction::Withdrawal(validate_amount(amount)?)),
+                asset,
+                timestamp,
+            ),
+            JsonRecord::Transfer {
+                client,
+                tx,
+                amount,
+                asset,
+                destination,
+                timestamp,
+            } => (
+                client,
+                tx,
+                RequestType::Monetary(MonetaryTransaction::Transfer {
+                    amount: validate_amount(amount)?,
+                    destination: validate_destination(client, destination)?,
+                }),
+                asset,
+                timestamp,
+            ),
+            JsonRecord::Convert {
+                client,
+                tx,
+                amount,
+                asset,
+                to_asset,
+                timestamp,
+            } => (
+                client,
+                tx,
+                RequestType::Monetary(MonetaryTransaction::Convert {
+                    amount: validate_amount(amount)?,
+                    to: validate_target_asset(asset.unwrap_or_default(), to_asset)?,
+                }),
+                asset,
+                timestamp,
+            ),
+            JsonRecord::Dispute {
+                client,
+                tx,
+                amount,
+                timestamp,
+            } => (
+                client,
+                tx,
+                RequestType::Claim(ClaimRequest {
+                    claim_type: ClaimType::Dispute,
+                    amount: validate_claim_amount(amount)?,
+                }),
+                None,
+                timestamp,
+            ),
+            JsonRecord::Resolve {
+                client,
+                tx,
+                amount,
+                timestamp,
+            } => (
+                client,
+                tx,
+                RequestType::Claim(ClaimRequest {
+                    claim_type: ClaimType::Resolve,
+                    amount: validate_claim_amount(amount)?,
+                }),
+                None,
+                timestamp,
+            ),
+            JsonRecord::Chargeback {
+                client,
+                tx,
+                amount,
+                timestamp,
+            } => (
+                client,
+                tx,
+                RequestType::Claim(ClaimRequest {
+                    claim_type: ClaimType::Chargeback,
+                    amount: validate_claim_amount(amount)?,
+                }),
+                None,
+                timestamp,
+            ),
+            JsonRecord::Unlock { client, tx, admin } => {
+                let (request_type, timestamp) = admin.into_request(AdminAction::Unlock)?;
+                (client, tx, request_type, None, timestamp)
+            }
+            JsonRecord::Freeze { client, tx, admin } => {
+                let (request_type, timestamp) = admin.into_request(AdminAction::Freeze)?;
+                (client, tx, request_type, None, timestamp)
+            }
+            JsonRecord::Close { client, tx, admin } => {
+                let (request_type, timestamp) = admin.into_request(AdminAction::Close)?;
+                (client, tx, request_type, None, timestamp)
+            }
+            JsonRecord::SetLimit {
+                client,
+                tx,
+                amount,
+                asset,
+                admin,
+            } => {
+                let action = AdminAction::SetLimit(validate_amount(amount)?);
+                let (request_type, timestamp) = admin.into_request(action)?;
+                (client, tx, request_type, asset, timestamp)
+            }
+        };
+
+        Ok(TransactionRequest {
+            client,
+            transaction,
+            request_type,
+            asset: asset.unwrap_or_default(),
+            timestamp,
+        })
+    }
+}
+
+/// Reads newline-delimited JSON transactions, skipping blank lines.
+pub struct JsonLinesReader<R> {
+    rdr: std::io::BufReader<R>,
+    position: InputPosition,
+}
+
+impl<R: std::io::Read> JsonLinesReader<R> {
+    pub fn new(rdr: R) -> Self {
+        Self {
+            rdr: std::io::BufReader::new(rdr),
+            position: InputPosition { byte: 0, line: 1 },
+        }
+    }
+}
+
+impl<R: std::io::Read> Iterator for JsonLinesReader<R> {
+    type Item = Result<InputRow, ProcessError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Read as bytes, so that a line that isn't UTF-8 is a malformed row like any other
+            let mut line = Vec::new();
+            let bytes_read = match self.rdr.read_until(b'\n', &mut line) {
+                Ok(0) => return None,
+                Ok(bytes_read) => bytes_read,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            let position = self.position;
+            self.position.byte += bytes_read as u64;
+            self.position.line += 1;
+
+            let line = line.trim_ascii();
+            if line.is_empty() {
+                continue;
+            }
+
+            let request = serde_json::from_slice::<JsonRecord>(line)
+                .map_err(|e| RowError::Malformed(MalformedRecord::Json(e)))
+                .and_then(|record| TransactionRequest::try_from(record).map_err(RowError::Invalid));
+
+            return Some(Ok(InputRow {
+                raw: RawRow::Json(String::from_utf8_lossy(line).into_owned()),
+                position: Some(position),
+                request,
+            }));
+        }
+    }
+}
+
+/// Recover the original fields from a JSON line, leaving any that can't be found empty.
+pub fn raw_fields(line: &str) -> RawFields {
+    let Ok(serde_json::Value::Object(object)) = serde_json::from_str(line) else {
+        return RawFields::default();
+    };
+
+    let field = |name: &str| match object.get(name) {
+        Some(serde_json::Value::String(value)) => value.clone(),
+        Some(serde_json::Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    };
+
+    RawFields {
+        transaction_type: field("type"),
+        client: field("client"),
+        tx: field("tx"),
+        amount: field("amount"),
+        asset: match field("asset") {
+            asset if asset.is_empty() => field("currency"),
+            asset => asset,
+        },
+        destination: field("destination"),
+        to_asset: match field("to_asset") {
+            to_asset if to_asset.is_empty() => field("to_currency"),
+            to_asset => to_asset,
+        },
+        operator: field("operator"),
+        reason: field("reason"),
+        timestamp: field("timestamp"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    fn read_one(input: &str) -> InputRow {
+        JsonLinesReader::new(input.as_bytes())
+            .next()
+            .expect("Expected a row")
+            .expect("Failed to read row")
+    }
+
+    #[test]
+    fn test_deserialize_deposit() {
+        let row = read_one(r#"{"type": "deposit", "client": 1, "tx": 2, "amount": 1.23456}"#);
+
+        let request = row.request.unwrap();
+        assert_eq!(request.client, ClientId(1));
+        assert_eq!(request.transaction, TransactionId(2));
+        assert!(matches!(
+            request.request_type,
+            RequestType::Monetary(MonetaryTransaction::Deposit(amount)) if amount == dec!(1.2346)
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_amount_as_string() {
+        let row = read_one(r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "0.1"}"#);
+
+        assert!(matches!(
+            row.request.unwrap().request_type,
+            RequestType::Monetary(MonetaryTransaction::Withdrawal(amount)) if amount == dec!(0.1)
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_claim_without_amount() {
+        let row = read_one(r#"{"type": "dispute", "client": 1, "tx": 2}"#);
+
+        assert!(matches!(
+            row.request.unwrap().request_type,
+            RequestType::Claim(ClaimRequest {
+                claim_type: ClaimType::Dispute,
+                amount: None,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_partial_claim() {
+        let row = read_one(r#"{"type": "chargeback", "client": 1, "tx": 2, "amount": "0.5"}"#);
+        assert!(matches!(
+            row.request.unwrap().request_type,
+            RequestType::Claim(ClaimRequest {
+                claim_type: ClaimType::Chargeback,
+                amount: Some(amount),
+            }) if amount == dec!(0.5)
+        ));
+
+        let row = read_one(r#"{"type": "resolve", "client": 1, "tx": 2, "amount": "0"}"#);
+        assert!(matches!(
+            row.request,
+            Err(RowError::Invalid(ProcessTransactionError::ZeroClaimAmount))
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_timestamp() {
+        let row = read_one(r#"{"type": "dispute", "client": 1, "tx": 2, "timestamp": 1000}"#);
+        assert_eq!(
+            row.request.unwrap().timestamp(),
+            Some(Timestamp::from_millis(1000))
+        );
+
+        let row = read_one(
+            r#"{"type": "deposit", "client": 1, "tx": 2, "amount": 1, "timestamp": "1970-01-01T00:00:01Z"}"#,
+        );
+        assert_eq!(
+            row.request.unwrap().timestamp(),
+            Some(Timestamp::from_millis(1000))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_transfer() {
+        let row = read_one(
+            r#"{"type": "transfer", "client": 1, "tx": 2, "amount": 1.5, "destination": 3}"#,
+        );
+        assert!(matches!(
+            row.request.unwrap().request_type,
+            RequestType::Monetary(MonetaryTransaction::Transfer { amount, destination })
+                if amount == dec!(1.5) && destination == ClientId(3)
+        ));
+
+        let row = read_one(r#"{"type": "transfer", "client": 1, "tx": 2, "amount": 1.5}"#);
+        assert!(matches!(
+            row.request.unwrap_err(),
+            RowError::Invalid(ProcessTransactionError::MissingDestination)
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_asset() {
+        let row =
+            read_one(r#"{"type": "deposit", "client": 1, "tx": 2, "amount": 1, "asset": "BTC"}"#);
+        assert_eq!(row.request.unwrap().asset().as_str(), "BTC");
+
+        let row = read_one(
+            r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": 1, "currency": "EUR"}"#,
+        );
+        assert_eq!(row.raw.fields().asset, "EUR");
+        assert_eq!(row.request.unwrap().asset().as_str(), "EUR");
+
+        let row = read_one(r#"{"type": "deposit", "client": 1, "tx": 2, "amount": 1}"#);
+        assert!(row.request.unwrap().asset().is_default());
+    }
+
+    #[test]
+    fn test_deserialize_convert() {
+        let row = read_one(
+            r#"{"type": "convert", "client": 1, "tx": 2, "amount": 1.5, "asset": "USD", "to_asset": "EUR"}"#,
+        );
+        let request = row.request.unwrap();
+        assert_eq!(request.asset().as_str(), "USD");
+        assert!(matches!(
+            request.request_type,
+            RequestType::Monetary(MonetaryTransaction::Convert { amount, to })
+                if amount == dec!(1.5) && to.as_str() == "EUR"
+        ));
+
+        let row = read_one(
+            r#"{"type": "convert", "client": 1, "tx": 2, "amount": 1.5, "currency": "USD", "to_currency": "USD"}"#,
+        );
+        assert_eq!(row.raw.fields().to_asset, "USD");
+        assert!(matches!(
+            row.request.unwrap_err(),
+            RowError::Invalid(ProcessTransactionError::ConvertToSameAsset)
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_admin() {
+        let row = read_one(
+            r#"{"type": "unlock", "client": 1, "tx": 2, "operator": 7, "reason": "risk_cleared", "timestamp": 1000}"#,
+        );
+        let request = row.request.unwrap();
+        assert_eq!(request.timestamp(), Some(Timestamp::from_millis(1000)));
+        assert!(matches!(
+            request.request_type,
+            RequestType::Admin(admin)
+                if admin.action == AdminAction::Unlock
+                    && admin.operator == OperatorId(7)
+                    && admin.reason.as_str() == "risk_cleared"
+        ));
+
+        let row = read_one(
+            r#"{"type": "set_limit", "client": 1, "tx": 3, "amount": "500", "currency": "EUR", "operator": 7, "reason": "overdraft"}"#,
+        );
+        let request = row.request.unwrap();
+        assert_eq!(request.asset().as_str(), "EUR");
+        assert!(matches!(
+            request.request_type,
+            RequestType::Admin(admin) if admin.action == AdminAction::SetLimit(dec!(500))
+        ));
+
+        let row = read_one(r#"{"type": "freeze", "client": 1, "tx": 2, "reason": "fraud"}"#);
+        assert_eq!(row.raw.fields().reason, "fraud");
+        assert!(matches!(
+            row.request.unwrap_err(),
+            RowError::Invalid(ProcessTransactionError::MissingOperator)
+        ));
+    }
+
+    #[test]
+    fn test_missing_amount_is_invalid() {
+        let row = read_one(r#"{"type": "deposit", "client": 1, "tx": 2}"#);
+
+        assert!(matches!(
+            row.request.unwrap_err(),
+            RowError::Invalid(ProcessTransactionError::MissingAmount)
+        ));
+    }
+
+    #[test]
+    fn test_unknown_type_is_malformed() {
+        let row = read_one(r#"{"type": "bogus", "client": 1, "tx": 2, "amount": 1.0}"#);
+
+        assert!(matches!(row.request.unwrap_err(), RowError::Malformed(_)));
+
+        let fields = row.raw.fields();
+        assert_eq!(fields.transaction_type, "bogus");
+        assert_eq!(fields.client, "1");
+        assert_eq!(fields.tx, "2");
+        assert_eq!(fields.amount, "1.0");
+    }
+
+    #[test]
+    fn test_invalid_utf8_is_malformed() {
+        let mut input =
+            b"{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"asset\": \"\xff\"}\n".to_vec();
+        input.extend_from_slice(
+            b"{\"type\": \"deposit\", \"client\": 1, \"tx\": 2, \"amount\": 1}\n",
+        );
+
+        let rows = JsonLinesReader::new(input.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(matches!(
+            rows[0].request,
+            Err(RowError::Malformed(MalformedRecord::Json(_)))
+        ));
+        assert_eq!(rows[0].position, Some(InputPosition { byte: 0, line: 1 }));
+        assert!(rows[1].request.is_ok());
+        assert_eq!(rows[1].position.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_positions_skip_blank_lines() {
+        let input = "{\"type\": \"dispute\", \"client\": 1, \"tx\": 2}\n\n{\"type\": \"resolve\", \"client\": 1, \"tx\": 2}\n";
+
+        let positions = JsonLinesReader::new(input.as_bytes())
+            .map(|row| row.unwrap().position.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            positions,
+            vec![
+                InputPosition { byte: 0, line: 1 },
+                InputPosition { byte: 43, line: 3 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use csv::ReaderBuilder;
+use serde::Deserialize;
+
+use crate::{
+    error::CreditLimitsError,
+    types::{Asset, ClientId, MonetaryAmount},
+};
+
+/// The credit limits clients have agreed with the exchange, which let their withdrawals take
+/// their available funds below zero, down to minus their limit. Set with
+/// [`Exchange::set_credit_limits`](crate::Exchange::set_credit_limits).
+///
+/// Clients without a limit in an asset have a limit of zero. An operator can give a client a
+/// different limit with a `set_limit` request, which takes precedence over these.
+///
+/// ```
+/// use rust_decimal::dec;
+/// use transaction_processor::{Asset, ClientId, CreditLimits};
+///
+/// let limits = "client,asset,limit\n7,,500\n7,BTC,0.5\n";
+/// let limits = CreditLimits::from_csv(limits.as_bytes()).unwrap();
+///
+/// assert_eq!(limits.limit(ClientId(7), Asset::default()), Some(dec!(500)));
+/// assert_eq!(limits.limit(ClientId(7), "BTC".parse().unwrap()), Some(dec!(0.5)));
+/// assert_eq!(limits.limit(ClientId(8), Asset::default()), None);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct CreditLimits {
+    limits: HashMap<(ClientId, Asset), MonetaryAmount>,
+}
+
+/// A row of a credit limits file.
+#[derive(Debug, Deserialize)]
+struct LimitRecord {
+    client: ClientId,
+    #[serde(default, alias = "currency")]
+    asset: Option<Asset>,
+    #[serde(alias = "credit_limit")]
+    limit: MonetaryAmount,
+}
+
+impl CreditLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read limits from a CSV with the columns `client`, `limit` and, optionally, `asset`, which
+    /// is the default asset when it's empty or missing. A client can have a row for each asset.
+    pub fn from_csv<R: std::io::Read>(rdr: R) -> std::result::Result<Self, CreditLimitsError> {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(rdr);
+
+        let headers = rdr.headers()?.clone();
+        let mut limits = CreditLimits::new();
+        for record in rdr.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |position| position.line());
+            let record = record.deserialize::<LimitRecord>(Some(&headers))?;
+            let invalid = |message: String| CreditLimitsError::Invalid { line, message };
+
+            if record.limit.is_sign_negative() {
+                return Err(invalid(format!(
+                    "limit must not be negative, found {}",
+                    record.limit
+                )));
+            }
+            let asset = record.asset.unwrap_or_default();
+            if limits.limit(record.client, asset).is_some() {
+                return Err(invalid(format!(
+                    "client {} already has a limit in asset {:?}",
+                    record.client,
+                    asset.as_str()
+                )));
+            }
+            limits = limits.with_limit(record.client, asset, record.limit);
+        }
+        Ok(limits)
+    }
+
+    /// Let `client`'s withdrawals in `asset` take their available funds down to `-limit`. The
+    /// limit is rounded to 4 decimal places like the amounts of requests.
+    pub fn with_limit(mut self, client: ClientId, asset: Asset, limit: MonetaryAmount) -> Self {
+        self.limits.insert((client, asset), limit.round_dp(4));
+        self
+    }
+
+    /// `client`'s limit in `asset`, if they've been given one.
+    pub fn limit(&self, client: ClientId, asset: Asset) -> Option<MonetaryAmount> {
+        self.limits.get(&(client, asset)).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_limits_without_an_asset_are_in_the_default_asset() {
+        let input = "client, limit\n1, 100\n2, 0.12345\n";
+        let limits = CreditLimits::from_csv(input.as_bytes()).unwrap();
+
+        assert_eq!(limits.limit(ClientId(1), Asset::default()), Some(dec!(100)));
+        assert_eq!(
+            limits.limit(ClientId(2), Asset::default()),
+            Some(dec!(0.1234))
+        );
+        assert_eq!(limits.limit(ClientId(1), "BTC".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_invalid_limits() {
+        for input in ["client,limit\n1,-5\n", "client,asset,limit\n1,,5\n1,,6\n"] {
+            assert!(
+                matches!(
+                    CreditLimits::from_csv(input.as_bytes()),
+                    Err(CreditLimitsError::Invalid { .. })
+                ),
+                "{}",
+                input
+            );
+        }
+
+        assert!(matches!(
+            CreditLimits::from_csv("client,limit\n1,lots\n".as_bytes()),
+            Err(CreditLimitsError::Csv(_))
+        ));
+    }
+}
@@ -1,14 +1,310 @@
-use transaction_processor::process;
+use std::{num::NonZeroUsize, time::Duration};
+
+use transaction_processor::{
+    CreditLimits, DisputeWindow, Exchange, FeeSchedule, InputFormat, OperatorId, OutputFormat,
+    Processor, RateTable, StandardClaimPolicy, Timestamp, TimestampOrder, TransactionStore,
+    balance_sink,
+};
+
+const USAGE: &str = "Usage: cargo run -- /path/to/file.csv [--input-format csv|jsonl] [--output-format csv|json|jsonl] [--dead-letter /path/to/rejected.csv] [--workers N] [--load-state /path/to/state.json] [--save-state /path/to/state.json] [--spill-dir /path/to/dir] [--memory-budget MiB] [--dispute-window N | --dispute-window-ms MS] [--out-of-order reject|reorder] [--tolerance MS] [--as-of TIMESTAMP] [--rates /path/to/rates.csv] [--max-rate-age-ms MS] [--fees /path/to/fees.json] [--claim-policy /path/to/policy.json] [--credit-limits /path/to/limits.csv] [--house-report /path/to/house.csv] [--collections-report /path/to/collections.csv] [--operators ID,ID,...] [--audit /path/to/audit.csv]";
+
+/// Memory kept for transactions when spilling to disk, unless `--memory-budget` is given.
+const DEFAULT_MEMORY_BUDGET_MIB: usize = 256;
+
+struct Args {
+    input: String,
+    input_format: Option<InputFormat>,
+    output_format: OutputFormat,
+    dead_letter: Option<String>,
+    workers: Option<NonZeroUsize>,
+    load_state: Option<String>,
+    save_state: Option<String>,
+    spill_dir: Option<String>,
+    /// In bytes.
+    memory_budget: usize,
+    dispute_window: Option<DisputeWindow>,
+    timestamp_order: TimestampOrder,
+    as_of: Option<Timestamp>,
+    rates: Option<String>,
+    max_rate_age: Option<Duration>,
+    fees: Option<String>,
+    claim_policy: Option<String>,
+    credit_limits: Option<String>,
+    house_report: Option<String>,
+    collections_report: Option<String>,
+    operators: Vec<OperatorId>,
+    audit: Option<String>,
+}
+
+fn parse_input_format(format: &str) -> Option<InputFormat> {
+    match format {
+        "csv" => Some(InputFormat::Csv),
+        "jsonl" => Some(InputFormat::JsonLines),
+        _ => None,
+    }
+}
+
+fn parse_output_format(format: &str) -> Option<OutputFormat> {
+    match format {
+        "csv" => Some(OutputFormat::Csv),
+        "json" => Some(OutputFormat::Json),
+        "jsonl" => Some(OutputFormat::JsonLines),
+        _ => None,
+    }
+}
+
+fn parse_args() -> Option<Args> {
+    let mut input = None;
+    let mut input_format = None;
+    let mut output_format = OutputFormat::default();
+    let mut dead_letter = None;
+    let mut workers = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut spill_dir = None;
+    let mut memory_budget = None;
+    let mut dispute_window = None;
+    let mut out_of_order = None;
+    let mut tolerance = Duration::ZERO;
+    let mut as_of = None;
+    let mut rates = None;
+    let mut max_rate_age = None;
+    let mut fees = None;
+    let mut claim_policy = None;
+    let mut credit_limits = None;
+    let mut house_report = None;
+    let mut collections_report = None;
+    let mut operators = Vec::new();
+    let mut audit = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input-format" => input_format = Some(parse_input_format(&args.next()?)?),
+            "--output-format" => output_format = parse_output_format(&args.next()?)?,
+            "--dead-letter" => dead_letter = Some(args.next()?),
+            "--workers" => workers = Some(args.next()?.parse().ok()?),
+            "--load-state" => load_state = Some(args.next()?),
+            "--save-state" => save_state = Some(args.next()?),
+            "--spill-dir" => spill_dir = Some(args.next()?),
+            "--memory-budget" => memory_budget = Some(args.next()?.parse().ok()?),
+            "--dispute-window" => {
+                dispute_window = Some(DisputeWindow::Transactions(args.next()?.parse().ok()?))
+            }
+            "--dispute-window-ms" => {
+                let millis = args.next()?.parse().ok()?;
+                dispute_window = Some(DisputeWindow::Duration(Duration::from_millis(millis)))
+            }
+            "--out-of-order" => out_of_order = Some(args.next()?),
+            "--tolerance" => tolerance = Duration::from_millis(args.next()?.parse().ok()?),
+            "--as-of" => as_of = Some(args.next()?.parse().ok()?),
+            "--rates" => rates = Some(args.next()?),
+            "--max-rate-age-ms" => {
+                max_rate_age = Some(Duration::from_millis(args.next()?.parse().ok()?))
+            }
+            "--fees" => fees = Some(args.next()?),
+            "--claim-policy" => claim_policy = Some(args.next()?),
+            "--credit-limits" => credit_limits = Some(args.next()?),
+            "--house-report" => house_report = Some(args.next()?),
+            "--collections-report" => collections_report = Some(args.next()?),
+            "--operators" => {
+                for operator in args.next()?.split(',') {
+                    operators.push(OperatorId(operator.trim().parse().ok()?));
+                }
+            }
+            "--audit" => audit = Some(args.next()?),
+            _ if input.is_none() => input = Some(arg),
+            _ => return None,
+        }
+    }
+
+    // Only used when spilling, and in MiB
+    if memory_budget.is_some() && spill_dir.is_none() {
+        return None;
+    }
+    // Balances as of a point in time are rebuilt from an event log starting from nothing
+    if as_of.is_some() && load_state.is_some() {
+        return None;
+    }
+    let memory_budget = memory_budget
+        .unwrap_or(DEFAULT_MEMORY_BUDGET_MIB)
+        .checked_mul(1024 * 1024)?;
+
+    let timestamp_order = match out_of_order.as_deref() {
+        None => TimestampOrder::Input,
+        Some("reject") => TimestampOrder::Reject { tolerance },
+        Some("reorder") => TimestampOrder::Reorder { tolerance },
+        Some(_) => return None,
+    };
+
+    Some(Args {
+        input: input?,
+        input_format,
+        output_format,
+        dead_letter,
+        workers,
+        load_state,
+        save_state,
+        spill_dir,
+        memory_budget,
+        dispute_window,
+        timestamp_order,
+        as_of,
+        rates,
+        max_rate_age,
+        fees,
+        claim_policy,
+        credit_limits,
+        house_report,
+        collections_report,
+        operators,
+        audit,
+    })
+}
 
 fn main() {
-    if std::env::args().len() != 2 {
-        eprintln!("Usage: cargo run -- /path/to/file.csv");
+    let Some(args) = parse_args() else {
+        eprintln!("{}", USAGE);
         std::process::exit(1);
+    };
+
+    // Exit only once the exchange is dropped, so that its transaction storage is removed
+    if let Err(message) = run(args) {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let reader = std::fs::File::open(&args.input)
+        .unwrap_or_else(|_| panic!("Failed to open file: {}", args.input));
+
+    let input_format = args
+        .input_format
+        .or_else(|| InputFormat::from_path(&args.input))
+        .unwrap_or_default();
+
+    let mut exchange = match &args.load_state {
+        Some(path) => {
+            let snapshot = std::fs::File::open(path)
+                .unwrap_or_else(|_| panic!("Failed to open file: {}", path));
+            Exchange::load_snapshot(snapshot)
+                .map_err(|e| format!("Failed to load state from {}: {}", path, e))?
+        }
+        // Balances as of a point in time are rebuilt from the event log, which has to start
+        // from an empty exchange
+        None if args.as_of.is_some() => Exchange::with_event_log(),
+        None => Exchange::new(),
+    };
+
+    if let Some(dir) = &args.spill_dir {
+        TransactionStore::spill_to_disk(dir, args.memory_budget)
+            .and_then(|store| exchange.set_transaction_store(store))
+            .map_err(|e| format!("Failed to create transaction storage in {}: {}", dir, e))?;
+    }
+
+    if let Some(window) = args.dispute_window {
+        exchange.set_dispute_window(window);
+    }
+
+    if let Some(path) = &args.rates {
+        let file =
+            std::fs::File::open(path).unwrap_or_else(|_| panic!("Failed to open file: {}", path));
+        let mut rates = RateTable::from_csv(file)
+            .map_err(|e| format!("Failed to load rates from {}: {}", path, e))?;
+        if let Some(max_age) = args.max_rate_age {
+            rates = rates.with_max_age(max_age);
+        }
+        exchange.set_rate_table(rates);
+    }
+
+    if let Some(path) = &args.fees {
+        let file =
+            std::fs::File::open(path).unwrap_or_else(|_| panic!("Failed to open file: {}", path));
+        let fees = FeeSchedule::from_json(file)
+            .map_err(|e| format!("Failed to load fees from {}: {}", path, e))?;
+        exchange.set_fee_schedule(fees);
+    }
+
+    if let Some(path) = &args.claim_policy {
+        let file =
+            std::fs::File::open(path).unwrap_or_else(|_| panic!("Failed to open file: {}", path));
+        let policy = StandardClaimPolicy::from_json(file)
+            .map_err(|e| format!("Failed to load claim policy from {}: {}", path, e))?;
+        exchange.set_claim_policy(policy);
+    }
+
+    if let Some(path) = &args.credit_limits {
+        let file =
+            std::fs::File::open(path).unwrap_or_else(|_| panic!("Failed to open file: {}", path));
+        let limits = CreditLimits::from_csv(file)
+            .map_err(|e| format!("Failed to load credit limits from {}: {}", path, e))?;
+        exchange.set_credit_limits(limits);
+    }
+
+    exchange.set_operators(args.operators.iter().copied());
+    if args.audit.is_some() {
+        exchange.start_event_log();
+    }
+
+    let mut processor = Processor::new()
+        .input_format(input_format)
+        .timestamp_order(args.timestamp_order);
+    if let Some(path) = &args.dead_letter {
+        let dead_letter = std::fs::File::create(path)
+            .unwrap_or_else(|_| panic!("Failed to create file: {}", path));
+        processor = processor.dead_letter(dead_letter);
+    }
+    if let Some(workers) = args.workers {
+        processor = processor.workers(workers);
     }
 
-    let file_path = std::env::args().nth(1).expect("No file path provided");
-    let reader = std::fs::File::open(&file_path)
-        .unwrap_or_else(|_| panic!("Failed to open file: {}", file_path));
+    processor
+        .process_exchange(reader, &mut exchange)
+        .map_err(|e| format!("Failed to process {}: {}", args.input, e))?;
+
+    let as_of = match args.as_of.zip(exchange.event_log()) {
+        Some((as_of, log)) => Some(
+            Exchange::replay(log.until(as_of))
+                .map_err(|e| format!("Failed to rebuild balances as of {}: {}", as_of, e))?,
+        ),
+        None => None,
+    };
+    let balances = as_of.as_ref().unwrap_or(&exchange);
+    balances
+        .write_balances(balance_sink(args.output_format, std::io::stdout()).as_mut())
+        .map_err(|e| format!("Failed to process {}: {}", args.input, e))?;
+
+    if let Some(path) = &args.house_report {
+        let report = std::fs::File::create(path)
+            .unwrap_or_else(|_| panic!("Failed to create file: {}", path));
+        balances
+            .write_house_report(report)
+            .map_err(|e| format!("Failed to write house report to {}: {}", path, e))?;
+    }
+
+    if let Some(path) = &args.collections_report {
+        let report = std::fs::File::create(path)
+            .unwrap_or_else(|_| panic!("Failed to create file: {}", path));
+        balances
+            .write_collections_report(report)
+            .map_err(|e| format!("Failed to write collections report to {}: {}", path, e))?;
+    }
+
+    if let Some((path, log)) = args.audit.as_ref().zip(exchange.event_log()) {
+        let audit = std::fs::File::create(path)
+            .unwrap_or_else(|_| panic!("Failed to create file: {}", path));
+        log.write_audit(audit)
+            .map_err(|e| format!("Failed to write audit log to {}: {}", path, e))?;
+    }
+
+    if let Some(path) = &args.save_state {
+        let snapshot = std::fs::File::create(path)
+            .unwrap_or_else(|_| panic!("Failed to create file: {}", path));
+        exchange
+            .save_snapshot(snapshot)
+            .map_err(|e| format!("Failed to save state to {}: {}", path, e))?;
+    }
 
-    process(reader, std::io::stdout());
+    Ok(())
 }
@@ -0,0 +1,184 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, VecDeque},
+    time::Duration,
+};
+
+use crate::{
+    error::{ProcessError, ProcessTransactionError, RowError},
+    io::{InputRow, InputRows},
+    types::Timestamp,
+};
+
+/// How rows are ordered by their timestamps before being applied, set with
+/// [`Processor::timestamp_order`](crate::Processor::timestamp_order).
+///
+/// Except with [`TimestampOrder::Input`], rows without a timestamp are rejected with
+/// [`ProcessTransactionError::MissingTimestamp`], and rows that can't be put in order are
+/// rejected with [`ProcessTransactionError::OutOfOrder`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampOrder {
+    /// Apply rows in input order, whatever their timestamps.
+    #[default]
+    Input,
+    /// Apply rows in input order, rejecting any row timestamped more than `tolerance` before the
+    /// latest row so far.
+    Reject { tolerance: Duration },
+    /// Hold rows back until a row timestamped `tolerance` after them has been read, and apply
+    /// them in timestamp order. Rows timestamped before a row that has already been applied are
+    /// rejected.
+    Reorder { tolerance: Duration },
+}
+
+/// Order `rows` as set by `order`. Rows that can't be converted into a request are passed through
+/// as soon as they're read.
+pub(crate) fn order_rows<'a>(rows: InputRows<'a>, order: TimestampOrder) -> InputRows<'a> {
+    match order {
+        TimestampOrder::Input => rows,
+        TimestampOrder::Reject { tolerance } => {
+            let mut latest = None::<Timestamp>;
+            Box::new(rows.map(move |row| {
+                let row = row?;
+                let timestamp = match &row.request {
+                    Ok(request) => request.timestamp(),
+                    Err(_) => return Ok(row),
+                };
+
+                Ok(match timestamp {
+                    None => reject(row, ProcessTransactionError::MissingTimestamp),
+                    Some(timestamp)
+                        if latest
+                            .is_some_and(|latest| timestamp < latest.saturating_sub(tolerance)) =>
+                    {
+                        reject(row, ProcessTransactionError::OutOfOrder)
+                    }
+                    Some(timestamp) => {
+                        latest = latest.max(Some(timestamp));
+                        row
+                    }
+                })
+            }))
+        }
+        TimestampOrder::Reorder { tolerance } => Box::new(Reorder {
+            rows,
+            tolerance,
+            held: BinaryHeap::new(),
+            ready: VecDeque::new(),
+            latest: None,
+            released: None,
+            read: 0,
+            done: false,
+        }),
+    }
+}
+
+fn reject(row: InputRow, error: ProcessTransactionError) -> InputRow {
+    InputRow {
+        request: Err(RowError::Invalid(error)),
+        ..row
+    }
+}
+
+struct Reorder<'a> {
+    rows: InputRows<'a>,
+    tolerance: Duration,
+    /// Rows waiting to be applied, earliest first.
+    held: BinaryHeap<Reverse<Held>>,
+    /// Rows to pass through straight away.
+    ready: VecDeque<InputRow>,
+    /// Latest timestamp read so far.
+    latest: Option<Timestamp>,
+    /// Timestamp of the last row passed on.
+    released: Option<Timestamp>,
+    read: u64,
+    done: bool,
+}
+
+/// A row held back, ordered by timestamp and then by input order.
+struct Held {
+    timestamp: Timestamp,
+    read: u64,
+    row: InputRow,
+}
+
+impl Ord for Held {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.read).cmp(&(other.timestamp, other.read))
+    }
+}
+
+impl PartialOrd for Held {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Held {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Held {}
+
+impl Iterator for Reorder<'_> {
+    type Item = Result<InputRow, ProcessError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.ready.pop_front() {
+                return Some(Ok(row));
+            }
+
+            let watermark = self
+                .latest
+                .map(|latest| latest.saturating_sub(self.tolerance));
+            if let Some(Reverse(earliest)) = self.held.peek()
+                && (self.done || watermark.is_some_and(|watermark| earliest.timestamp <= watermark))
+            {
+                let Reverse(earliest) = self.held.pop()?;
+                self.released = Some(earliest.timestamp);
+                return Some(Ok(earliest.row));
+            }
+
+            if self.done {
+                return None;
+            }
+
+            let row = match self.rows.next() {
+                Some(Ok(row)) => row,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.done = true;
+                    continue;
+                }
+            };
+
+            let timestamp = match &row.request {
+                Ok(request) => request.timestamp(),
+                Err(_) => {
+                    self.ready.push_back(row);
+                    continue;
+                }
+            };
+            match timestamp {
+                None => self
+                    .ready
+                    .push_back(reject(row, ProcessTransactionError::MissingTimestamp)),
+                Some(timestamp) if self.released.is_some_and(|released| timestamp < released) => {
+                    self.ready
+                        .push_back(reject(row, ProcessTransactionError::OutOfOrder))
+                }
+                Some(timestamp) => {
+                    self.latest = self.latest.max(Some(timestamp));
+                    self.held.push(Reverse(Held {
+                        timestamp,
+                        read: self.read,
+                        row,
+                    }));
+                    self.read += 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use std::{collections::VecDeque, num::NonZeroUsize};
+
+use crate::{
+    error::{BadRow, ErrorCategory, ErrorContext, InputPosition, ProcessError, RowError},
+    exchange::Exchange,
+    io::{
+        DeadLetterWriter, InputFormat, InputRow, InputRows, RawFields, RawRow, read_transactions,
+    },
+    ordering::{TimestampOrder, order_rows},
+    sharded::ShardedExchange,
+    sink::{BalanceSink, OutputFormat, balance_sink},
+    types::RequestType,
+};
+
+/// Summary of a processing run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessReport {
+    /// Rows that were applied to the exchange.
+    pub accepted: u64,
+    /// Well-formed rows that the exchange refused, e.g. a withdrawal with insufficient funds.
+    pub rejected: u64,
+    /// Rows that could not be read or converted into a transaction request.
+    pub malformed: u64,
+}
+
+impl ProcessReport {
+    /// Total number of rows that were not applied to the exchange.
+    pub fn bad_rows(&self) -> u64 {
+        self.rejected + self.malformed
+    }
+
+    fn record_bad_row(&mut self, error: &RowError) {
+        if error.is_malformed() {
+            self.malformed += 1;
+        } else {
+            self.rejected += 1;
+        }
+    }
+}
+
+/// How rows that can't be applied to the exchange are handled.
+///
+/// The policy applies equally to rows that fail to deserialize, rows that fail validation and
+/// rows that the exchange rejects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IngestPolicy {
+    /// Stop processing at the first bad row.
+    Strict,
+    /// Skip bad rows and record them in the [`ProcessReport`].
+    ///
+    /// If `max_bad_rows` is set, processing is aborted once more than that many rows have been
+    /// skipped.
+    Lenient { max_bad_rows: Option<u64> },
+}
+
+impl Default for IngestPolicy {
+    fn default() -> Self {
+        IngestPolicy::Lenient { max_bad_rows: None }
+    }
+}
+
+/// Configurable entry point for processing a stream of transactions.
+#[derive(Default)]
+pub struct Processor<'a> {
+    ingest_policy: IngestPolicy,
+    input_format: InputFormat,
+    output_format: OutputFormat,
+    workers: Option<NonZeroUsize>,
+    timestamp_order: TimestampOrder,
+    dead_letter: Option<DeadLetterWriter<Box<dyn std::io::Write + 'a>>>,
+}
+
+impl<'a> Processor<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ingest_policy(mut self, ingest_policy: IngestPolicy) -> Self {
+        self.ingest_policy = ingest_policy;
+        self
+    }
+
+    pub fn input_format(mut self, input_format: InputFormat) -> Self {
+        self.input_format = input_format;
+        self
+    }
+
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.output_format = output_format;
+        self
+    }
+
+    /// Process clients concurrently on `workers` threads using a [`ShardedExchange`].
+    ///
+    /// The result is identical to processing sequentially, which is the default. The one
+    /// exception is that if processing stops at a bad row, requests read after it may already
+    /// have been applied to the exchange.
+    pub fn workers(mut self, workers: NonZeroUsize) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Apply rows in an order decided by their timestamps, rather than in input order.
+    pub fn timestamp_order(mut self, timestamp_order: TimestampOrder) -> Self {
+        self.timestamp_order = timestamp_order;
+        self
+    }
+
+    /// Write every row that isn't applied to the exchange, along with why, to `wtr` as CSV.
+    pub fn dead_letter<D: std::io::Write + 'a>(mut self, wtr: D) -> Self {
+        self.dead_letter = Some(DeadLetterWriter::new(Box::new(wtr)));
+        self
+    }
+
+    /// Process transactions from `rdr`, writing the final balances to `wtr` in the configured
+    /// [`OutputFormat`].
+    pub fn process<R: std::io::Read, W: std::io::Write>(
+        self,
+        rdr: R,
+        wtr: W,
+    ) -> Result<ProcessReport, ProcessError> {
+        let output_format = self.output_format;
+        self.process_into(rdr, balance_sink(output_format, wtr).as_mut())
+    }
+
+    /// Process transactions from `rdr`, passing the final balances to `sink`.
+    pub fn process_into<R: std::io::Read>(
+        self,
+        rdr: R,
+        sink: &mut dyn BalanceSink,
+    ) -> Result<ProcessReport, ProcessError> {
+        let mut exchange = Exchange::new();
+
+        let report = self.process_exchange(rdr, &mut exchange)?;
+
+        exchange.write_balances(sink)?;
+
+        Ok(report)
+    }
+
+    /// Apply transactions from `rdr` to an existing `exchange`, without writing any balances.
+    ///
+    /// This lets the caller choose how the exchange is created, e.g. with
+    /// [`Exchange::with_event_log`], and inspect it afterwards.
+    pub fn process_exchange<R: std::io::Read>(
+        mut self,
+        rdr: R,
+        exchange: &mut Exchange,
+    ) -> Result<ProcessReport, ProcessError> {
+        let mut report = ProcessReport::default();
+
+        let ingested = self.ingest(rdr, exchange, &mut report);
+
+        // Rows rejected before a strict abort are still worth keeping
+        if let Some(dead_letter) = self.dead_letter.take() {
+            dead_letter.finish()?;
+        }
+        ingested?;
+
+        Ok(report)
+    }
+
+    fn ingest<R: std::io::Read>(
+        &mut self,
+        rdr: R,
+        exchange: &mut Exchange,
+        report: &mut ProcessReport,
+    ) -> Result<(), ProcessError> {
+        if let Some(workers) = self.workers.filter(|workers| workers.get() > 1) {
+            return self.ingest_sharded(rdr, exchange, report, workers);
+        }
+
+        for row in self.read_rows(rdr)? {
+            let InputRow {
+                raw,
+                position,
+                request,
+            } = row?;
+
+            let request_type = request.as_ref().ok().map(|request| request.request_type);
+            let outcome = request.and_then(|request| {
+                exchange
+                    .process_transaction(request)
+                    .map_err(RowError::Rejected)
+            });
+
+            let row = PendingRow {
+                raw,
+                position,
+                request_type,
+            };
+            self.record_outcome(report, row, outcome)?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as [`Processor::ingest`], but with clients processed concurrently by a
+    /// [`ShardedExchange`]. Outcomes are still recorded in input order so that the report, dead
+    /// letter and ingest policy behave exactly as they would sequentially.
+    fn ingest_sharded<R: std::io::Read>(
+        &mut self,
+        rdr: R,
+        exchange: &mut Exchange,
+        report: &mut ProcessReport,
+        workers: NonZeroUsize,
+    ) -> Result<(), ProcessError> {
+        let mut sharded = ShardedExchange::new(std::mem::take(exchange), workers);
+        let ingested = self.ingest_sharded_rows(rdr, &mut sharded, report);
+
+        // The exchange is put back even if ingest stopped early, as it would be sequentially
+        *exchange = sharded.finish()?;
+
+        ingested
+    }
+
+    fn ingest_sharded_rows<R: std::io::Read>(
+        &mut self,
+        rdr: R,
+        sharded: &mut ShardedExchange,
+        report: &mut ProcessReport,
+    ) -> Result<(), ProcessError> {
+        let mut in_flight = VecDeque::new();
+
+        for row in self.read_rows(rdr)? {
+            let InputRow {
+                raw,
+                position,
+                request,
+            } = row?;
+
+            let request_type = request.as_ref().ok().map(|request| request.request_type);
+            let outcome = match request {
+                Ok(request) => {
+                    sharded.submit(request);
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            };
+
+            let row = PendingRow {
+                raw,
+                position,
+                request_type,
+            };
+            in_flight.push_back((row, outcome));
+
+            self.record_completed(&mut in_flight, report, || sharded.try_next_result())?;
+        }
+
+        self.record_completed(&mut in_flight, report, || sharded.next_result())
+    }
+
+    /// The rows of `rdr`, in the order they're to be applied.
+    fn read_rows<'r, R: std::io::Read + 'r>(&self, rdr: R) -> Result<InputRows<'r>, ProcessError> {
+        Ok(order_rows(
+            read_transactions(rdr, self.input_format)?,
+            self.timestamp_order,
+        ))
+    }
+
+    /// Record the outcome of rows from the front of `in_flight` for as long as they're known,
+    /// taking the outcomes of submitted requests from `next_result`.
+    fn record_completed(
+        &mut self,
+        in_flight: &mut VecDeque<(PendingRow, Option<Result<(), RowError>>)>,
+        report: &mut ProcessReport,
+        mut next_result: impl FnMut() -> Option<crate::error::Result<()>>,
+    ) -> Result<(), ProcessError> {
+        while let Some((_, outcome)) = in_flight.front_mut() {
+            let outcome = match outcome.take() {
+                Some(outcome) => outcome,
+                None => match next_result() {
+                    Some(result) => result.map_err(RowError::Rejected),
+                    None => break,
+                },
+            };
+
+            if let Some((row, _)) = in_flight.pop_front() {
+                self.record_outcome(report, row, outcome)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record_outcome(
+        &mut self,
+        report: &mut ProcessReport,
+        row: PendingRow,
+        outcome: Result<(), RowError>,
+    ) -> Result<(), ProcessError> {
+        match outcome {
+            Ok(()) => {
+                report.accepted += 1;
+                Ok(())
+            }
+            Err(e) => {
+                let fields = row.raw.fields();
+                let context = ErrorContext {
+                    request_type: row.request_type,
+                    ..fields.context(row.position)
+                };
+                self.handle_bad_row(report, &fields, BadRow::new(context, e))
+            }
+        }
+    }
+
+    fn handle_bad_row(
+        &mut self,
+        report: &mut ProcessReport,
+        fields: &RawFields,
+        bad_row: BadRow,
+    ) -> Result<(), ProcessError> {
+        if let Some(dead_letter) = self.dead_letter.as_mut() {
+            dead_letter.write(fields, &bad_row)?;
+        }
+
+        // The exchange can't be trusted to carry on if its storage failed, whatever the policy
+        if bad_row.error().category() == ErrorCategory::Storage {
+            return Err(ProcessError::BadRow(bad_row));
+        }
+
+        match self.ingest_policy {
+            IngestPolicy::Strict => Err(ProcessError::BadRow(bad_row)),
+            IngestPolicy::Lenient { max_bad_rows } => {
+                eprintln!("{}", bad_row);
+                report.record_bad_row(bad_row.error());
+
+                match max_bad_rows {
+                    Some(limit) if report.bad_rows() > limit => Err(ProcessError::TooManyBadRows {
+                        limit,
+                        last: bad_row,
+                    }),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// What's kept of a row while its outcome is decided, in case it's rejected.
+struct PendingRow {
+    raw: RawRow,
+    position: Option<InputPosition>,
+    request_type: Option<RequestType>,
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use csv::ReaderBuilder;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    error::{ProcessTransactionError, RateTableError, Result},
+    types::{Asset, Timestamp},
+};
+
+/// Exchange rates between pairs of assets, used to apply conversions. Set with
+/// [`Exchange::set_rate_table`](crate::Exchange::set_rate_table).
+///
+/// A pair can have several rates, each valid from its timestamp until the next one. Conversions
+/// use the rate valid at their timestamp, or the most recent rate if they don't have one.
+///
+/// ```
+/// use rust_decimal::dec;
+/// use transaction_processor::{RateTable, Timestamp};
+///
+/// let rates = "pair,rate,valid_from\nBTC/USD,60000,2024-05-01T00:00:00Z\n";
+/// let rates = RateTable::from_csv(rates.as_bytes()).unwrap();
+///
+/// let btc = "BTC".parse().unwrap();
+/// let usd = "USD".parse().unwrap();
+/// let at = "2024-05-02T00:00:00Z".parse::<Timestamp>().unwrap();
+/// assert_eq!(rates.rate(btc, usd, Some(at)).unwrap(), dec!(60000));
+/// assert!(rates.rate(usd, btc, Some(at)).is_err());
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct RateTable {
+    /// Rates for each pair, in ascending order of when they become valid.
+    rates: HashMap<(Asset, Asset), Vec<Rate>>,
+    max_age: Option<std::time::Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    valid_from: Timestamp,
+    rate: Decimal,
+}
+
+/// A row of a rate table file.
+#[derive(Debug, Deserialize)]
+struct RateRecord {
+    pair: String,
+    rate: Decimal,
+    #[serde(alias = "valid-from")]
+    valid_from: Timestamp,
+}
+
+impl RateTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a table from a CSV with the columns `pair`, `rate` and `valid_from`. A pair is
+    /// written `FROM/TO`, and its rate is the amount of `TO` that one unit of `FROM` converts
+    /// into. Converting the other way needs a row of its own.
+    pub fn from_csv<R: std::io::Read>(rdr: R) -> std::result::Result<Self, RateTableError> {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(rdr);
+
+        let headers = rdr.headers()?.clone();
+        let mut table = RateTable::new();
+        for record in rdr.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |position| position.line());
+            let record = record.deserialize::<RateRecord>(Some(&headers))?;
+            let invalid = |message: String| RateTableError::Invalid { line, message };
+
+            let (from, to) = record.pair.split_once('/').ok_or_else(|| {
+                invalid(format!("expected a pair FROM/TO, found {:?}", record.pair))
+            })?;
+            // An empty code would parse as the default asset
+            if from.is_empty() || to.is_empty() {
+                return Err(invalid(format!(
+                    "expected a pair FROM/TO, found {:?}",
+                    record.pair
+                )));
+            }
+            let from = from.parse().map_err(|e| invalid(format!("{}", e)))?;
+            let to = to.parse().map_err(|e| invalid(format!("{}", e)))?;
+            if from == to {
+                return Err(invalid(format!(
+                    "{:?} converts an asset to itself",
+                    record.pair
+                )));
+            }
+            if !record.rate.is_sign_positive() || record.rate.is_zero() {
+                return Err(invalid(format!(
+                    "rate must be positive, found {}",
+                    record.rate
+                )));
+            }
+            if !table.insert(from, to, record.rate, record.valid_from) {
+                return Err(invalid(format!(
+                    "{:?} already has a rate valid from {}",
+                    record.pair, record.valid_from
+                )));
+            }
+        }
+        Ok(table)
+    }
+
+    /// Convert `from` into `to` at `rate` from `valid_from` onwards. Returns false, leaving the
+    /// table unchanged, if the pair already has a rate valid from then.
+    pub fn insert(&mut self, from: Asset, to: Asset, rate: Decimal, valid_from: Timestamp) -> bool {
+        let rates = self.rates.entry((from, to)).or_default();
+        match rates.binary_search_by_key(&valid_from, |rate| rate.valid_from) {
+            Ok(_) => false,
+            Err(index) => {
+                rates.insert(index, Rate { valid_from, rate });
+                true
+            }
+        }
+    }
+
+    /// Refuse conversions whose rate became valid more than `max_age` before them. Conversions
+    /// without a timestamp are never refused.
+    pub fn with_max_age(mut self, max_age: std::time::Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// The rate converting `from` into `to` at `at`, or the most recent rate if `at` isn't known.
+    ///
+    /// Fails with [`ProcessTransactionError::UnknownPair`] if the pair has no rate valid by then,
+    /// and with [`ProcessTransactionError::StaleRate`] if the rate is older than the maximum age.
+    pub fn rate(&self, from: Asset, to: Asset, at: Option<Timestamp>) -> Result<Decimal> {
+        let rates = self
+            .rates
+            .get(&(from, to))
+            .ok_or(ProcessTransactionError::UnknownPair)?;
+
+        let Some(at) = at else {
+            let latest = rates.last().ok_or(ProcessTransactionError::UnknownPair)?;
+            return Ok(latest.rate);
+        };
+
+        let valid = rates.partition_point(|rate| rate.valid_from <= at);
+        let current = valid
+            .checked_sub(1)
+            .map(|index| rates[index])
+            .ok_or(ProcessTransactionError::UnknownPair)?;
+        if let Some(max_age) = self.max_age
+            && current.valid_from < at.saturating_sub(max_age)
+        {
+            return Err(ProcessTransactionError::StaleRate);
+        }
+        Ok(current.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    fn asset(code: &str) -> Asset {
+        code.parse().unwrap()
+    }
+
+    fn at(millis: i64) -> Option<Timestamp> {
+        Some(Timestamp::from_millis(millis))
+    }
+
+    #[test]
+    fn test_rate_valid_at_timestamp() {
+        let input = "pair,rate,valid_from\nEUR/USD,1.1,2000\nEUR/USD,1.05,1000\nUSD/EUR,0.9,1000\n";
+        let rates = RateTable::from_csv(input.as_bytes()).unwrap();
+        let (eur, usd) = (asset("EUR"), asset("USD"));
+
+        assert_eq!(rates.rate(eur, usd, at(1000)).unwrap(), dec!(1.05));
+        assert_eq!(rates.rate(eur, usd, at(1999)).unwrap(), dec!(1.05));
+        assert_eq!(rates.rate(eur, usd, at(5000)).unwrap(), dec!(1.1));
+        assert_eq!(rates.rate(eur, usd, None).unwrap(), dec!(1.1));
+        assert_eq!(rates.rate(usd, eur, at(5000)).unwrap(), dec!(0.9));
+
+        // Not valid yet, or never given
+        assert!(matches!(
+            rates.rate(eur, usd, at(999)),
+            Err(ProcessTransactionError::UnknownPair)
+        ));
+        assert!(matches!(
+            rates.rate(eur, asset("BTC"), None),
+            Err(ProcessTransactionError::UnknownPair)
+        ));
+    }
+
+    #[test]
+    fn test_stale_rate() {
+        let mut rates = RateTable::new().with_max_age(std::time::Duration::from_secs(1));
+        rates.insert(
+            asset("EUR"),
+            asset("USD"),
+            dec!(1.1),
+            Timestamp::from_millis(0),
+        );
+
+        assert!(rates.rate(asset("EUR"), asset("USD"), at(1000)).is_ok());
+        assert!(matches!(
+            rates.rate(asset("EUR"), asset("USD"), at(1001)),
+            Err(ProcessTransactionError::StaleRate)
+        ));
+        assert!(rates.rate(asset("EUR"), asset("USD"), None).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_rates() {
+        for input in [
+            "pair,rate,valid_from\nEURUSD,1.1,0\n",
+            "pair,rate,valid_from\n/USD,1.1,0\n",
+            "pair,rate,valid_from\nEUR/,1.1,0\n",
+            "pair,rate,valid_from\nEUR/EUR,1,0\n",
+            "pair,rate,valid_from\nEUR/USD,0,0\n",
+            "pair,rate,valid_from\nEUR/USD,1.1,0\nEUR/USD,1.2,0\n",
+        ] {
+            assert!(
+                matches!(
+                    RateTable::from_csv(input.as_bytes()),
+                    Err(RateTableError::Invalid { .. })
+                ),
+                "{}",
+                input
+            );
+        }
+
+        assert!(matches!(
+            RateTable::from_csv("pair,rate,valid_from\nEUR/USD,lots,0\n".as_bytes()),
+            Err(RateTableError::Csv(_))
+        ));
+    }
+}
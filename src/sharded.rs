@@ -0,0 +1,740 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread::JoinHandle,
+};
+
+use crate::{
+    claims::ClaimPolicy,
+    error::{ProcessError, Result},
+    event::EventLog,
+    exchange::{DisputeWindow, Exchange, HouseBalance, LentClient, Unsharded},
+    fees::FeeSchedule,
+    limits::CreditLimits,
+    rates::RateTable,
+    store::TransactionStore,
+    types::{
+        Asset, ClientId, MonetaryTransaction, OperatorId, RequestType, Timestamp, TransactionId,
+        TransactionRequest,
+    },
+};
+
+/// Number of requests sent to a worker at a time, so that the cost of waking it is shared.
+const BATCH_SIZE: usize = 256;
+
+/// Number of requests that can be submitted before their results are received, after which
+/// [`ShardedExchange::submit`] blocks.
+const MAX_IN_FLIGHT: u64 = 16 * 1024;
+
+/// Number of independently locked partitions in the transaction registry, to reduce contention.
+const REGISTRY_PARTITIONS: usize = 64;
+
+/// An [`Exchange`] split across worker threads by [`ClientId`].
+///
+/// Every request for a client is handled by the same worker, so each client's requests are applied
+/// in the order they were submitted while different clients are processed concurrently. Every
+/// worker shares the exchange's transaction store, and requests using the same transaction ID are
+/// ordered by a registry shared by every worker, so duplicate and ownership checks give exactly
+/// the same answers as a single [`Exchange`] processing the same requests.
+///
+/// A transfer between clients of different workers is applied by the sender's worker, which
+/// borrows the destination client from the other worker for the duration of the request. The
+/// other worker waits until it's given back, having applied every earlier request for the
+/// destination, so the transfer sees the destination exactly as a single [`Exchange`] would.
+///
+/// Dropping it without calling [`ShardedExchange::finish`] waits for the workers to apply every
+/// submitted request and stop, and discards the clients.
+///
+/// ```
+/// use std::num::NonZeroUsize;
+///
+/// use rust_decimal::dec;
+/// use transaction_processor::{
+///     ClientId, Exchange, ShardedExchange, TransactionId, TransactionRequest,
+/// };
+///
+/// let mut sharded = ShardedExchange::new(Exchange::new(), NonZeroUsize::new(4).unwrap());
+/// for client in 1..=8 {
+///     let deposit =
+///         TransactionRequest::deposit(ClientId(client), TransactionId(client.into()), dec!(1))
+///             .unwrap();
+///     sharded.submit(deposit);
+/// }
+///
+/// while let Some(result) = sharded.next_result() {
+///     result.unwrap();
+/// }
+///
+/// let exchange = sharded.finish().unwrap();
+/// assert_eq!(exchange.clients().count(), 8);
+/// ```
+pub struct ShardedExchange {
+    jobs: Vec<mpsc::Sender<Vec<Job>>>,
+    /// Requests for each worker that haven't been sent yet.
+    batches: Vec<Vec<Job>>,
+    results: mpsc::Receiver<Vec<Outcome>>,
+    workers: Vec<JoinHandle<Exchange>>,
+    registry: Arc<TransactionRegistry>,
+    transactions: Arc<TransactionStore>,
+    /// Events of the requests whose results have been returned, if events are being recorded.
+    events: Option<EventLog>,
+    dispute_window: Option<DisputeWindow>,
+    rates: Arc<RateTable>,
+    fees: Arc<FeeSchedule>,
+    operators: Arc<HashSet<OperatorId>>,
+    claim_policy: Arc<dyn ClaimPolicy>,
+    credit_limits: Arc<CreditLimits>,
+    /// The house account before the exchange was split between the workers.
+    house: BTreeMap<Asset, HouseBalance>,
+    /// The latest timestamp accepted before the exchange was split between the workers.
+    latest: Option<Timestamp>,
+    /// Outcomes received from the workers, indexed from `next_result`, that haven't been returned.
+    completed: VecDeque<Option<Outcome>>,
+    received: u64,
+    next_seq: u64,
+    next_result: u64,
+}
+
+enum Job {
+    /// Apply a request, first borrowing a client from another worker if the request needs it.
+    Request {
+        seq: u64,
+        request: TransactionRequest,
+        loan: Option<Loan>,
+    },
+    /// Lend a client to the worker applying a request, waiting until it's given back.
+    Lend {
+        client: ClientId,
+        lend: mpsc::Sender<LentClient>,
+        returned: mpsc::Receiver<LentClient>,
+    },
+}
+
+/// The borrowing side of a [`Job::Lend`].
+struct Loan {
+    client: ClientId,
+    lent: mpsc::Receiver<LentClient>,
+    give_back: mpsc::Sender<LentClient>,
+}
+
+struct Outcome {
+    seq: u64,
+    result: Result<()>,
+    /// Events recorded by the worker while applying the request.
+    events: Option<EventLog>,
+}
+
+impl ShardedExchange {
+    /// Start `workers` threads, sharing the clients of `exchange` between them.
+    pub fn new(exchange: Exchange, workers: NonZeroUsize) -> Self {
+        let worker_count = workers.get();
+        let (shards, unsharded) =
+            exchange.into_shards(worker_count, |client| shard_of(client, worker_count));
+
+        let registry = Arc::new(TransactionRegistry::new());
+        let (results_tx, results) = mpsc::channel();
+
+        let (jobs, workers) = shards
+            .into_iter()
+            .map(|shard| {
+                let (jobs_tx, jobs_rx) = mpsc::channel();
+                let registry = Arc::clone(&registry);
+                let results_tx = results_tx.clone();
+                let worker =
+                    std::thread::spawn(move || run_worker(shard, jobs_rx, results_tx, registry));
+                (jobs_tx, worker)
+            })
+            .unzip();
+
+        Self {
+            jobs,
+            batches: (0..worker_count).map(|_| Vec::new()).collect(),
+            results,
+            workers,
+            registry,
+            transactions: unsharded.transactions,
+            events: unsharded.events,
+            dispute_window: unsharded.dispute_window,
+            rates: unsharded.rates,
+            fees: unsharded.fees,
+            operators: unsharded.operators,
+            claim_policy: unsharded.claim_policy,
+            credit_limits: unsharded.credit_limits,
+            house: unsharded.house,
+            latest: unsharded.latest,
+            completed: VecDeque::new(),
+            received: 0,
+            next_seq: 0,
+            next_result: 0,
+        }
+    }
+
+    /// Queue a request, blocking if too many requests are waiting to be applied already.
+    pub fn submit(&mut self, request: TransactionRequest) {
+        while self.next_seq - self.received >= MAX_IN_FLIGHT {
+            self.flush();
+            self.receive();
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        // Reserve the transaction ID now so that later requests know to wait for this one
+        self.registry.reserve(request.transaction, seq);
+
+        let shard = shard_of(request.client, self.jobs.len());
+        let loan = self
+            .lender(&request)
+            .map(|(lender, client)| self.lend(lender, client));
+        self.batches[shard].push(Job::Request { seq, request, loan });
+        if self.batches[shard].len() >= BATCH_SIZE {
+            self.send(shard);
+        }
+    }
+
+    /// The worker holding the destination of a transfer, and the destination, if it isn't the
+    /// worker applying the transfer.
+    fn lender(&self, request: &TransactionRequest) -> Option<(usize, ClientId)> {
+        let RequestType::Monetary(MonetaryTransaction::Transfer { destination, .. }) =
+            request.request_type
+        else {
+            return None;
+        };
+        let lender = shard_of(destination, self.jobs.len());
+        (lender != shard_of(request.client, self.jobs.len())).then_some((lender, destination))
+    }
+
+    /// Queue a [`Job::Lend`] for `client`. It's sent straight away, as the borrowing worker
+    /// can't carry on until the lender reaches it.
+    fn lend(&mut self, lender: usize, client: ClientId) -> Loan {
+        let (lend, lent) = mpsc::channel();
+        let (give_back, returned) = mpsc::channel();
+        self.batches[lender].push(Job::Lend {
+            client,
+            lend,
+            returned,
+        });
+        self.send(lender);
+        Loan {
+            client,
+            lent,
+            give_back,
+        }
+    }
+
+    /// The result of the next request, in the order they were submitted, if it has completed.
+    ///
+    /// Requests are sent to the workers in batches, so a result may not become available until
+    /// more requests are submitted or [`ShardedExchange::next_result`] is called.
+    pub fn try_next_result(&mut self) -> Option<Result<()>> {
+        while let Ok(results) = self.results.try_recv() {
+            self.insert_results(results);
+        }
+        self.take_next_result()
+    }
+
+    /// The result of the next request, in the order they were submitted, waiting for it to
+    /// complete.
+    ///
+    /// Returns `None` once the result of every submitted request has been returned.
+    pub fn next_result(&mut self) -> Option<Result<()>> {
+        self.flush();
+
+        while self.next_result < self.next_seq {
+            if let Some(result) = self.take_next_result() {
+                return Some(result);
+            }
+            self.receive();
+        }
+
+        None
+    }
+
+    /// Send every request that hasn't been sent yet, so that the earliest outstanding request
+    /// is always with a worker before waiting on results.
+    fn flush(&mut self) {
+        for shard in 0..self.batches.len() {
+            if !self.batches[shard].is_empty() {
+                self.send(shard);
+            }
+        }
+    }
+
+    fn send(&mut self, shard: usize) {
+        let batch = std::mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH_SIZE));
+        self.jobs[shard]
+            .send(batch)
+            .expect("Worker thread exited unexpectedly");
+    }
+
+    fn receive(&mut self) {
+        let results = self
+            .results
+            .recv()
+            .expect("Worker thread exited unexpectedly");
+        self.insert_results(results);
+    }
+
+    fn insert_results(&mut self, outcomes: Vec<Outcome>) {
+        self.received += outcomes.len() as u64;
+        for outcome in outcomes {
+            let index = (outcome.seq - self.next_result) as usize;
+            if self.completed.len() <= index {
+                self.completed.resize_with(index + 1, || None);
+            }
+            self.completed[index] = Some(outcome);
+        }
+    }
+
+    /// Also moves the events of the request to the log, so that it's in submission order.
+    fn take_next_result(&mut self) -> Option<Result<()>> {
+        let outcome = self.completed.front_mut()?.take()?;
+        self.completed.pop_front();
+        self.next_result += 1;
+
+        if let (Some(log), Some(mut events)) = (self.events.as_mut(), outcome.events) {
+            log.append_log(&mut events);
+        }
+
+        Some(outcome.result)
+    }
+
+    /// Wait for every submitted request to be applied, then merge the shards back into a single
+    /// [`Exchange`]. Results that haven't been read are discarded.
+    ///
+    /// Fails with [`ProcessError::Overflow`] if the house account is too large once the shards'
+    /// entries are added up, where a single [`Exchange`] would have refused the request that
+    /// overflowed it.
+    pub fn finish(mut self) -> std::result::Result<Exchange, ProcessError> {
+        // Every outcome is taken so that no events are lost
+        while self.next_result().is_some() {}
+
+        // Closing the queues lets the workers exit once they're empty
+        self.jobs.clear();
+
+        let shards = std::mem::take(&mut self.workers)
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect::<Vec<_>>();
+
+        let unsharded = Unsharded {
+            transactions: Arc::clone(&self.transactions),
+            events: self.events.take(),
+            dispute_window: self.dispute_window,
+            rates: Arc::clone(&self.rates),
+            fees: Arc::clone(&self.fees),
+            operators: Arc::clone(&self.operators),
+            claim_policy: Arc::clone(&self.claim_policy),
+            credit_limits: Arc::clone(&self.credit_limits),
+            house: std::mem::take(&mut self.house),
+            latest: self.latest,
+        };
+
+        Exchange::from_shards(shards, unsharded)
+    }
+}
+
+/// Stops the workers of a sharded exchange that wasn't finished, discarding their clients.
+impl Drop for ShardedExchange {
+    fn drop(&mut self) {
+        // Every request is sent, as an earlier one a worker is waiting on, or the other side of a
+        // loan, could still be in a batch
+        for (jobs, batch) in self.jobs.iter().zip(&mut self.batches) {
+            if !batch.is_empty() {
+                let _ = jobs.send(std::mem::take(batch));
+            }
+        }
+        self.jobs.clear();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn shard_of(client: ClientId, shard_count: usize) -> usize {
+    usize::from(client.0) % shard_count
+}
+
+fn run_worker(
+    mut shard: Exchange,
+    jobs: mpsc::Receiver<Vec<Job>>,
+    results: mpsc::Sender<Vec<Outcome>>,
+    registry: Arc<TransactionRegistry>,
+) -> Exchange {
+    for batch in jobs {
+        let mut batch_results = Vec::with_capacity(batch.len());
+
+        for job in batch {
+            match job {
+                Job::Request { seq, request, loan } => {
+                    let transaction = request.transaction;
+
+                    // The other side of a loan only goes away if the sharded exchange is being
+                    // dropped, so the worker ends without applying anything else
+                    if let Some(loan) = &loan {
+                        let Ok(lent) = loan.lent.recv() else {
+                            return shard;
+                        };
+                        shard.restore_client(lent);
+                    }
+
+                    registry.wait(transaction, seq);
+                    let result = shard.process_transaction(request);
+                    registry.complete(transaction, seq);
+
+                    if let Some(loan) = loan
+                        && loan.give_back.send(shard.lend_client(loan.client)).is_err()
+                    {
+                        return shard;
+                    }
+
+                    batch_results.push(Outcome {
+                        seq,
+                        result,
+                        events: shard.take_events(),
+                    });
+                }
+                Job::Lend {
+                    client,
+                    lend,
+                    returned,
+                } => {
+                    if lend.send(shard.lend_client(client)).is_err() {
+                        return shard;
+                    }
+                    let Ok(lent) = returned.recv() else {
+                        return shard;
+                    };
+                    shard.restore_client(lent);
+                }
+            }
+        }
+
+        // Nobody is waiting for the results if the sharded exchange was dropped without finishing
+        let _ = results.send(batch_results);
+    }
+
+    shard
+}
+
+/// The order of requests using each transaction ID, shared between every worker.
+///
+/// Ownership of a transaction ID has to be seen in submission order, so a request waits until
+/// every earlier request using the same ID has either looked up the transaction, for claims, or
+/// succeeded or failed, for deposits and withdrawals. A request only ever waits on earlier
+/// requests, and the earliest outstanding request can always make progress, so this can't
+/// deadlock.
+struct TransactionRegistry {
+    partitions: Vec<RegistryPartition>,
+}
+
+#[derive(Default)]
+struct RegistryPartition {
+    /// Sequence numbers of requests using each ID that haven't completed yet, in ascending order.
+    /// Requests complete in the same order, as each waits for every earlier one.
+    pending: Mutex<HashMap<TransactionId, VecDeque<u64>>>,
+    completed: Condvar,
+    /// Number of workers waiting on `completed`, only changed while `pending` is locked. Waking
+    /// nobody is common and not free, so it's skipped.
+    waiting: AtomicUsize,
+}
+
+impl TransactionRegistry {
+    fn new() -> Self {
+        let partitions = (0..REGISTRY_PARTITIONS)
+            .map(|_| RegistryPartition::default())
+            .collect();
+        Self { partitions }
+    }
+
+    fn partition(&self, transaction: TransactionId) -> &RegistryPartition {
+        &self.partitions[partition_of(transaction)]
+    }
+
+    /// Record that request `seq` will use `transaction`.
+    fn reserve(&self, transaction: TransactionId, seq: u64) {
+        self.partition(transaction)
+            .pending
+            .lock()
+            .expect("Transaction registry lock poisoned")
+            .entry(transaction)
+            .or_default()
+            .push_back(seq);
+    }
+
+    /// Wait for every request using `transaction` that was submitted before `seq` to complete.
+    fn wait(&self, transaction: TransactionId, seq: u64) {
+        let partition = self.partition(transaction);
+        let mut pending = partition
+            .pending
+            .lock()
+            .expect("Transaction registry lock poisoned");
+
+        while pending
+            .get(&transaction)
+            .and_then(|pending| pending.front())
+            .is_some_and(|&first| first < seq)
+        {
+            partition.waiting.fetch_add(1, Ordering::Relaxed);
+            pending = partition
+                .completed
+                .wait(pending)
+                .expect("Transaction registry lock poisoned");
+            partition.waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Mark request `seq` using `transaction` as complete.
+    fn complete(&self, transaction: TransactionId, seq: u64) {
+        let partition = self.partition(transaction);
+        let mut pending = partition
+            .pending
+            .lock()
+            .expect("Transaction registry lock poisoned");
+
+        if let Some(requests) = pending.get_mut(&transaction) {
+            debug_assert_eq!(requests.front(), Some(&seq));
+            requests.pop_front();
+            if requests.is_empty() {
+                pending.remove(&transaction);
+            }
+        }
+
+        let waiting = partition.waiting.load(Ordering::Relaxed) > 0;
+        drop(pending);
+        if waiting {
+            partition.completed.notify_all();
+        }
+    }
+}
+
+fn partition_of(transaction: TransactionId) -> usize {
+    transaction.0 as usize % REGISTRY_PARTITIONS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::ProcessTransactionError, types::ClaimType};
+    use rust_decimal::dec;
+
+    fn workers(count: usize) -> NonZeroUsize {
+        NonZeroUsize::new(count).unwrap()
+    }
+
+    fn run(requests: Vec<TransactionRequest>) -> (Vec<Result<()>>, Exchange) {
+        let mut sharded = ShardedExchange::new(Exchange::new(), workers(4));
+        for request in requests {
+            sharded.submit(request);
+        }
+
+        let results = std::iter::from_fn(|| sharded.next_result()).collect();
+        (results, sharded.finish().unwrap())
+    }
+
+    #[test]
+    fn test_duplicate_transaction_across_shards() {
+        let (results, exchange) = run(vec![
+            TransactionRequest::deposit(ClientId(1), TransactionId(1), dec!(1)).unwrap(),
+            TransactionRequest::deposit(ClientId(2), TransactionId(1), dec!(1)).unwrap(),
+        ]);
+
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(ProcessTransactionError::DuplicateTransaction)
+        ));
+        assert_eq!(exchange.client(ClientId(1)).unwrap().available(), dec!(1));
+        assert!(exchange.client(ClientId(2)).is_none());
+    }
+
+    #[test]
+    fn test_failed_transaction_does_not_take_ownership() {
+        let (results, exchange) = run(vec![
+            TransactionRequest::withdrawal(ClientId(1), TransactionId(1), dec!(1)).unwrap(),
+            TransactionRequest::deposit(ClientId(2), TransactionId(1), dec!(1)).unwrap(),
+            TransactionRequest::claim(ClientId(1), TransactionId(1), ClaimType::Dispute),
+        ]);
+
+        assert!(matches!(
+            results[0],
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+        assert!(results[1].is_ok());
+        assert!(matches!(
+            results[2],
+            Err(ProcessTransactionError::Unauthorized)
+        ));
+        assert_eq!(exchange.client(ClientId(2)).unwrap().available(), dec!(1));
+    }
+
+    #[test]
+    fn test_claim_waits_for_earlier_deposit_on_other_shard() {
+        let (results, _) = run(vec![
+            TransactionRequest::deposit(ClientId(1), TransactionId(1), dec!(1)).unwrap(),
+            TransactionRequest::claim(ClientId(2), TransactionId(1), ClaimType::Dispute),
+            TransactionRequest::claim(ClientId(1), TransactionId(1), ClaimType::Dispute),
+        ]);
+
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(ProcessTransactionError::Unauthorized)
+        ));
+        assert!(results[2].is_ok());
+    }
+
+    #[test]
+    fn test_deposit_waits_for_earlier_claim_on_other_shard() {
+        let (results, _) = run(vec![
+            TransactionRequest::claim(ClientId(2), TransactionId(1), ClaimType::Dispute),
+            TransactionRequest::deposit(ClientId(1), TransactionId(1), dec!(1)).unwrap(),
+        ]);
+
+        assert!(matches!(
+            results[0],
+            Err(ProcessTransactionError::TransactionNotFound)
+        ));
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    fn test_transfer_between_shards_sees_earlier_requests() {
+        let transfer = |client, transaction, destination, amount| {
+            TransactionRequest::transfer(
+                ClientId(client),
+                TransactionId(transaction),
+                ClientId(destination),
+                amount,
+            )
+            .unwrap()
+        };
+        let (results, exchange) = run(vec![
+            TransactionRequest::deposit(ClientId(1), TransactionId(1), dec!(5)).unwrap(),
+            transfer(1, 2, 2, dec!(1)),
+            TransactionRequest::deposit(ClientId(2), TransactionId(3), dec!(0)).unwrap(),
+            transfer(1, 4, 2, dec!(3)),
+            transfer(2, 5, 3, dec!(1)),
+            TransactionRequest::deposit(ClientId(3), TransactionId(6), dec!(0)).unwrap(),
+            transfer(2, 7, 3, dec!(2)),
+            TransactionRequest::withdrawal(ClientId(2), TransactionId(8), dec!(2)).unwrap(),
+        ]);
+
+        assert!(matches!(
+            results[1],
+            Err(ProcessTransactionError::DestinationNotFound)
+        ));
+        assert!(matches!(
+            results[4],
+            Err(ProcessTransactionError::DestinationNotFound)
+        ));
+        assert!(matches!(
+            results[7],
+            Err(ProcessTransactionError::InsufficientFunds { .. })
+        ));
+        assert_eq!(exchange.client(ClientId(1)).unwrap().available(), dec!(2));
+        assert_eq!(exchange.client(ClientId(2)).unwrap().available(), dec!(1));
+        assert_eq!(exchange.client(ClientId(3)).unwrap().available(), dec!(2));
+    }
+
+    #[test]
+    fn test_finish_keeps_existing_state() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(ClientId(1), TransactionId(1), dec!(1)).unwrap(),
+            )
+            .unwrap();
+
+        let mut sharded = ShardedExchange::new(exchange, workers(3));
+        sharded.submit(TransactionRequest::claim(
+            ClientId(1),
+            TransactionId(1),
+            ClaimType::Dispute,
+        ));
+        assert!(sharded.next_result().unwrap().is_ok());
+        assert!(sharded.next_result().is_none());
+
+        let mut exchange = sharded.finish().unwrap();
+        assert_eq!(exchange.client(ClientId(1)).unwrap().held(), dec!(1));
+
+        // Ownership of the transaction survives the round trip
+        let result = exchange.process_transaction(
+            TransactionRequest::deposit(ClientId(2), TransactionId(1), dec!(1)).unwrap(),
+        );
+        assert!(matches!(
+            result.unwrap_err(),
+            ProcessTransactionError::DuplicateTransaction
+        ));
+    }
+
+    #[test]
+    fn test_lender_ends_quietly_without_a_borrower() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process_transaction(
+                TransactionRequest::deposit(ClientId(2), TransactionId(1), dec!(1)).unwrap(),
+            )
+            .unwrap();
+
+        let (jobs_tx, jobs) = mpsc::channel();
+        let (results, _) = mpsc::channel();
+        let worker = std::thread::spawn(move || {
+            run_worker(
+                exchange,
+                jobs,
+                results,
+                Arc::new(TransactionRegistry::new()),
+            )
+        });
+
+        // The borrower has gone, so the client is never given back
+        let (lend, lent) = mpsc::channel();
+        let (_, returned) = mpsc::channel();
+        jobs_tx
+            .send(vec![Job::Lend {
+                client: ClientId(2),
+                lend,
+                returned,
+            }])
+            .unwrap();
+        drop(lent);
+        drop(jobs_tx);
+
+        assert!(worker.join().is_ok());
+    }
+
+    #[test]
+    fn test_dropping_unfinished_exchange_stops_workers() {
+        let mut sharded = ShardedExchange::new(Exchange::new(), workers(4));
+        for client in [1, 2] {
+            sharded.submit(
+                TransactionRequest::deposit(
+                    ClientId(client),
+                    TransactionId(client.into()),
+                    dec!(5),
+                )
+                .unwrap(),
+            );
+        }
+        sharded.submit(
+            TransactionRequest::transfer(ClientId(1), TransactionId(3), ClientId(2), dec!(1))
+                .unwrap(),
+        );
+        sharded
+            .submit(TransactionRequest::deposit(ClientId(2), TransactionId(3), dec!(1)).unwrap());
+
+        // Returns once every worker has stopped
+        drop(sharded);
+    }
+}
@@ -0,0 +1,469 @@
+use csv::WriterBuilder;
+use serde::Serialize;
+
+use crate::{
+    error::ProcessError,
+    exchange::AccountStatus,
+    types::{Asset, ClientId, MonetaryAmount, OperatorId, ReasonCode, TransactionId},
+};
+
+/// A client's final balance in a single asset, as passed to every [`BalanceSink`].
+///
+/// Amounts are always serialized as strings so that JSON output keeps their exact precision. The
+/// default asset isn't serialized, so single-currency output doesn't change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientSnapshot {
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    #[serde(skip_serializing_if = "Asset::is_default")]
+    pub asset: Asset,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub available: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub held: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub total: MonetaryAmount,
+    pub locked: bool,
+    /// How much the client can still withdraw, their available funds plus their credit limit.
+    /// [`Exchange::write_balances`](crate::Exchange::write_balances) gives it for every balance
+    /// when any client has a credit limit, and leaves it out otherwise.
+    #[serde(
+        with = "rust_decimal::serde::str_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub headroom: Option<MonetaryAmount>,
+}
+
+/// Format of the final client balances.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A single JSON array of balances.
+    Json,
+    /// Newline-delimited JSON, one object per balance.
+    JsonLines,
+}
+
+/// Destination for the final client balances.
+///
+/// Implement this to send balances somewhere other than the built-in formats, and pass it to
+/// [`Processor::process_into`](crate::Processor::process_into). Errors from a custom sink can be
+/// returned as [`ProcessError::Sink`].
+pub trait BalanceSink {
+    /// Called before any balances are written, with every asset they're in, in ascending order.
+    fn start(&mut self, assets: &[Asset]) -> Result<(), ProcessError> {
+        let _ = assets;
+        Ok(())
+    }
+
+    /// Called once per client and asset, in ascending order of client ID and then of asset.
+    fn write(&mut self, snapshot: &ClientSnapshot) -> Result<(), ProcessError>;
+
+    /// Called once all balances have been written.
+    fn finish(&mut self) -> Result<(), ProcessError> {
+        Ok(())
+    }
+}
+
+/// Collects balances in memory.
+impl BalanceSink for Vec<ClientSnapshot> {
+    fn write(&mut self, snapshot: &ClientSnapshot) -> Result<(), ProcessError> {
+        self.push(snapshot.clone());
+        Ok(())
+    }
+}
+
+/// Create the built-in sink for `format`.
+pub fn balance_sink<'a, W: std::io::Write + 'a>(
+    format: OutputFormat,
+    wtr: W,
+) -> Box<dyn BalanceSink + 'a> {
+    match format {
+        OutputFormat::Csv => Box::new(CsvSink::new(wtr)),
+        OutputFormat::Json => Box::new(JsonSink::array(wtr)),
+        OutputFormat::JsonLines => Box::new(JsonSink::lines(wtr)),
+    }
+}
+
+/// Writes balances as CSV, including the headers when there are no balances.
+///
+/// There's an `asset` column after `client` if any balance is in an asset other than the
+/// default, with an empty field for the default asset, and a `headroom` column at the end if the
+/// balances have a headroom.
+pub struct CsvSink<W: std::io::Write> {
+    wtr: csv::Writer<W>,
+    is_empty: bool,
+    assets: bool,
+}
+
+/// A row of [`CsvSink`] output, which unlike JSON needs the same columns in every row.
+#[derive(Serialize)]
+struct CsvBalance {
+    client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asset: Option<Asset>,
+    #[serde(with = "rust_decimal::serde::str")]
+    available: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    held: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    total: MonetaryAmount,
+    locked: bool,
+    #[serde(
+        with = "rust_decimal::serde::str_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    headroom: Option<MonetaryAmount>,
+}
+
+impl<W: std::io::Write> CsvSink<W> {
+    pub fn new(wtr: W) -> Self {
+        Self {
+            wtr: WriterBuilder::new().has_headers(true).from_writer(wtr),
+            is_empty: true,
+            assets: false,
+        }
+    }
+}
+
+impl<W: std::io::Write> BalanceSink for CsvSink<W> {
+    fn start(&mut self, assets: &[Asset]) -> Result<(), ProcessError> {
+        self.assets = assets.iter().any(|asset| !asset.is_default());
+        Ok(())
+    }
+
+    fn write(&mut self, snapshot: &ClientSnapshot) -> Result<(), ProcessError> {
+        self.is_empty = false;
+        self.wtr.serialize(CsvBalance {
+            client: snapshot.client_id,
+            asset: self.assets.then_some(snapshot.asset),
+            available: snapshot.available,
+            held: snapshot.held,
+            total: snapshot.total,
+            locked: snapshot.locked,
+            headroom: snapshot.headroom,
+        })?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), ProcessError> {
+        // Ensure headers are written even if no records exist
+        if self.is_empty {
+            self.wtr
+                .write_record(["client", "available", "held", "total", "locked"])?;
+        }
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes balances either as a single JSON array or as JSON Lines.
+pub struct JsonSink<W: std::io::Write> {
+    wtr: W,
+    lines: bool,
+    is_empty: bool,
+}
+
+impl<W: std::io::Write> JsonSink<W> {
+    /// Write balances as a single JSON array.
+    pub fn array(wtr: W) -> Self {
+        Self {
+            wtr,
+            lines: false,
+            is_empty: true,
+        }
+    }
+
+    /// Write balances as JSON Lines.
+    pub fn lines(wtr: W) -> Self {
+        Self {
+            wtr,
+            lines: true,
+            is_empty: true,
+        }
+    }
+}
+
+impl<W: std::io::Write> BalanceSink for JsonSink<W> {
+    fn write(&mut self, snapshot: &ClientSnapshot) -> Result<(), ProcessError> {
+        if !self.lines {
+            self.wtr
+                .write_all(if self.is_empty { b"[" } else { b"," })?;
+        }
+        self.is_empty = false;
+
+        serde_json::to_writer(&mut self.wtr, snapshot).map_err(std::io::Error::from)?;
+
+        if self.lines {
+            self.wtr.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), ProcessError> {
+        if !self.lines {
+            self.wtr
+                .write_all(if self.is_empty { b"[]\n" } else { b"]\n" })?;
+        }
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// A row of the house report written by
+/// [`Exchange::write_house_report`](crate::Exchange::write_house_report).
+#[derive(Serialize)]
+pub(crate) struct HouseReportRow {
+    pub(crate) asset: Asset,
+    /// The total of every client's balance in the asset, which the exchange owes them.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) clients: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) fees: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) chargebacks: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) conversions: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) house: MonetaryAmount,
+    /// What the exchange should be holding: the clients' total plus the house's.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) holdings: MonetaryAmount,
+}
+
+/// Write `rows` as CSV, including the headers when there are no rows.
+pub(crate) fn write_house_report<W: std::io::Write>(
+    wtr: W,
+    rows: &[HouseReportRow],
+) -> Result<(), ProcessError> {
+    let mut wtr = WriterBuilder::new().has_headers(true).from_writer(wtr);
+    if rows.is_empty() {
+        wtr.write_record([
+            "asset",
+            "clients",
+            "fees",
+            "chargebacks",
+            "conversions",
+            "house",
+            "holdings",
+        ])?;
+    }
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// A row of the collections report written by
+/// [`Exchange::write_collections_report`](crate::Exchange::write_collections_report).
+#[derive(Serialize)]
+pub(crate) struct CollectionsRow {
+    pub(crate) client: ClientId,
+    pub(crate) asset: Asset,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) available: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) total: MonetaryAmount,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub(crate) peak: MonetaryAmount,
+    pub(crate) since: Option<String>,
+    pub(crate) until: Option<String>,
+    pub(crate) duration_ms: Option<u128>,
+    pub(crate) recovered: bool,
+    /// The IDs of the transactions that caused the deficit, separated by spaces.
+    pub(crate) transactions: String,
+}
+
+/// Write `rows` as CSV, including the headers when there are no rows.
+pub(crate) fn write_collections_report<W: std::io::Write>(
+    wtr: W,
+    rows: &[CollectionsRow],
+) -> Result<(), ProcessError> {
+    let mut wtr = WriterBuilder::new().has_headers(true).from_writer(wtr);
+    if rows.is_empty() {
+        wtr.write_record([
+            "client",
+            "asset",
+            "available",
+            "total",
+            "peak",
+            "since",
+            "until",
+            "duration_ms",
+            "recovered",
+            "transactions",
+        ])?;
+    }
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// A row of the audit trail written by [`EventLog::write_audit`](crate::EventLog::write_audit),
+/// either for a change of status or of credit limit.
+#[derive(Serialize)]
+pub(crate) struct AuditRow {
+    pub(crate) seq: u64,
+    pub(crate) client: ClientId,
+    pub(crate) tx: TransactionId,
+    pub(crate) status: Option<AccountStatus>,
+    pub(crate) operator: Option<OperatorId>,
+    pub(crate) reason: ReasonCode,
+    pub(crate) timestamp: Option<String>,
+    pub(crate) asset: Asset,
+    #[serde(with = "rust_decimal::serde::str_option")]
+    pub(crate) limit: Option<MonetaryAmount>,
+}
+
+/// Write `rows` as CSV, including the headers when there are no rows.
+pub(crate) fn write_audit<W: std::io::Write>(
+    wtr: W,
+    rows: &[AuditRow],
+) -> Result<(), ProcessError> {
+    let mut wtr = WriterBuilder::new().has_headers(true).from_writer(wtr);
+    if rows.is_empty() {
+        wtr.write_record([
+            "seq",
+            "client",
+            "tx",
+            "status",
+            "operator",
+            "reason",
+            "timestamp",
+            "asset",
+            "limit",
+        ])?;
+    }
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    fn snapshots() -> Vec<ClientSnapshot> {
+        vec![
+            ClientSnapshot {
+                client_id: ClientId(1),
+                asset: Asset::default(),
+                available: dec!(1.5000),
+                held: dec!(0.25),
+                total: dec!(1.7500),
+                locked: false,
+                headroom: None,
+            },
+            ClientSnapshot {
+                client_id: ClientId(2),
+                asset: Asset::default(),
+                available: dec!(-0.5),
+                held: dec!(0),
+                total: dec!(-0.5),
+                locked: true,
+                headroom: None,
+            },
+        ]
+    }
+
+    fn write_all(format: OutputFormat, snapshots: &[ClientSnapshot]) -> String {
+        let mut output = Vec::new();
+        let mut sink = balance_sink(format, &mut output);
+        let mut assets = snapshots
+            .iter()
+            .map(|snapshot| snapshot.asset)
+            .collect::<Vec<_>>();
+        assets.sort();
+        assets.dedup();
+        sink.start(&assets).unwrap();
+        for snapshot in snapshots {
+            sink.write(snapshot).unwrap();
+        }
+        sink.finish().unwrap();
+        drop(sink);
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_json_array_keeps_amount_precision() {
+        assert_eq!(
+            write_all(OutputFormat::Json, &snapshots()),
+            concat!(
+                r#"[{"client":1,"available":"1.5000","held":"0.25","total":"1.7500","locked":false},"#,
+                r#"{"client":2,"available":"-0.5","held":"0","total":"-0.5","locked":true}]"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_json_lines() {
+        assert_eq!(
+            write_all(OutputFormat::JsonLines, &snapshots()),
+            concat!(
+                r#"{"client":1,"available":"1.5000","held":"0.25","total":"1.7500","locked":false}"#,
+                "\n",
+                r#"{"client":2,"available":"-0.5","held":"0","total":"-0.5","locked":true}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_asset_column_only_with_other_assets() {
+        assert_eq!(
+            write_all(OutputFormat::Csv, &snapshots()),
+            "client,available,held,total,locked\n1,1.5000,0.25,1.7500,false\n2,-0.5,0,-0.5,true\n"
+        );
+
+        let mut snapshots = snapshots();
+        snapshots[1].asset = "BTC".parse().unwrap();
+        assert_eq!(
+            write_all(OutputFormat::Csv, &snapshots),
+            "client,asset,available,held,total,locked\n1,,1.5000,0.25,1.7500,false\n2,BTC,-0.5,0,-0.5,true\n"
+        );
+        assert_eq!(
+            write_all(OutputFormat::JsonLines, &snapshots[1..]),
+            concat!(
+                r#"{"client":2,"asset":"BTC","available":"-0.5","held":"0","total":"-0.5","locked":true}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_headroom_column_only_with_headroom() {
+        let mut snapshots = snapshots();
+        snapshots[0].headroom = Some(dec!(1.5000));
+        snapshots[1].headroom = Some(dec!(99.5));
+        assert_eq!(
+            write_all(OutputFormat::Csv, &snapshots),
+            "client,available,held,total,locked,headroom\n1,1.5000,0.25,1.7500,false,1.5000\n2,-0.5,0,-0.5,true,99.5\n"
+        );
+        assert_eq!(
+            write_all(OutputFormat::JsonLines, &snapshots[1..]),
+            concat!(
+                r#"{"client":2,"available":"-0.5","held":"0","total":"-0.5","locked":true,"headroom":"99.5"}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_empty_output() {
+        assert_eq!(write_all(OutputFormat::Json, &[]), "[]\n");
+        assert_eq!(write_all(OutputFormat::JsonLines, &[]), "");
+        assert_eq!(
+            write_all(OutputFormat::Csv, &[]),
+            "client,available,held,total,locked\n"
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::SnapshotError,
+    exchange::{AccountStatus, ClaimState, Deficit},
+    types::{Asset, ClientId, MonetaryAmount, MonetaryTransaction, Timestamp, TransactionId},
+};
+
+/// Version of the snapshot format written by this build. Bump it whenever the format changes.
+pub(crate) const SNAPSHOT_VERSION: u32 = 7;
+
+/// The full state of an [`Exchange`](crate::Exchange), as saved to disk.
+///
+/// Clients and transactions are sorted by ID so that the same state always gives the same file.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) version: u32,
+    pub(crate) clients: Vec<ClientRecord>,
+    /// The client that owns every transaction ID in use, including expired transactions.
+    pub(crate) owners: Vec<OwnerRecord>,
+    /// The house account in each asset, in ascending order of asset.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) house: Vec<HouseRecord>,
+    /// The latest timestamp of any request the exchange had accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) latest: Option<Timestamp>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ClientRecord {
+    pub(crate) client: ClientId,
+    /// The client's balance in each asset it has held, in ascending order of asset.
+    pub(crate) balances: Vec<BalanceRecord>,
+    pub(crate) status: AccountStatus,
+    pub(crate) transactions: Vec<TransactionRecord>,
+    /// The client's transactions within the dispute window, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) recent: Vec<TransactionId>,
+    /// Disputed transactions that have left the dispute window.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) overdue: Vec<TransactionId>,
+    /// Every time the client's funds in an asset went below zero, in the order they did.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) deficits: Vec<Deficit>,
+    /// Credit limits given to the client by an operator, in ascending order of asset.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) limits: Vec<LimitRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BalanceRecord {
+    #[serde(default, skip_serializing_if = "Asset::is_default")]
+    pub(crate) asset: Asset,
+    pub(crate) available: MonetaryAmount,
+    pub(crate) held: MonetaryAmount,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LimitRecord {
+    #[serde(default, skip_serializing_if = "Asset::is_default")]
+    pub(crate) asset: Asset,
+    pub(crate) limit: MonetaryAmount,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TransactionRecord {
+    pub(crate) tx: TransactionId,
+    #[serde(flatten)]
+    request: TransactionType,
+    #[serde(default, skip_serializing_if = "Asset::is_default")]
+    pub(crate) asset: Asset,
+    #[serde(default, skip_serializing_if = "MonetaryAmount::is_zero")]
+    pub(crate) fee: MonetaryAmount,
+    #[serde(default, skip_serializing_if = "ClaimState::is_clear")]
+    pub(crate) claim: ClaimState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timestamp: Option<Timestamp>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum TransactionType {
+    Deposit {
+        amount: MonetaryAmount,
+    },
+    Withdrawal {
+        amount: MonetaryAmount,
+    },
+    Transfer {
+        amount: MonetaryAmount,
+        destination: ClientId,
+    },
+    Convert {
+        amount: MonetaryAmount,
+        to_asset: Asset,
+    },
+}
+
+impl Snapshot {
+    /// Check the parts of the snapshot that the exchange relies on being consistent, as a
+    /// snapshot may have been edited since it was saved.
+    fn validate(&self) -> Result<(), SnapshotError> {
+        let owners = self
+            .owners
+            .iter()
+            .map(|record| (record.tx, record.client))
+            .collect::<HashMap<_, _>>();
+
+        for record in &self.clients {
+            let client = record.client;
+            for balance in &record.balances {
+                check_amount(balance.available, true, || {
+                    format!("available funds of client {}", client)
+                })?;
+                check_amount(balance.held, false, || {
+                    format!("held funds of client {}", client)
+                })?;
+            }
+            for limit in &record.limits {
+                check_amount(limit.limit, false, || {
+                    format!("credit limit of client {}", client)
+                })?;
+            }
+
+            for transaction in &record.transactions {
+                let tx = transaction.tx;
+                let amount = match transaction.request {
+                    TransactionType::Deposit { amount }
+                    | TransactionType::Withdrawal { amount }
+                    | TransactionType::Transfer { amount, .. }
+                    | TransactionType::Convert { amount, .. } => amount,
+                };
+                check_amount(amount, false, || format!("amount of transaction {}", tx))?;
+                check_amount(transaction.fee, false, || {
+                    format!("fee of transaction {}", tx)
+                })?;
+                check_amount(transaction.claim.disputed, false, || {
+                    format!("amount disputed of transaction {}", tx)
+                })?;
+                check_amount(transaction.claim.charged_back, false, || {
+                    format!("amount charged back of transaction {}", tx)
+                })?;
+                if owners.get(&tx) != Some(&client) {
+                    return Err(SnapshotError::Inconsistent(format!(
+                        "transaction {} of client {} isn't among the owners",
+                        tx, client
+                    )));
+                }
+            }
+
+            let transactions = record
+                .transactions
+                .iter()
+                .map(|transaction| transaction.tx)
+                .collect::<HashSet<_>>();
+            for (kind, listed) in [("recent", &record.recent), ("overdue", &record.overdue)] {
+                if let Some(tx) = listed.iter().find(|tx| !transactions.contains(tx)) {
+                    return Err(SnapshotError::Inconsistent(format!(
+                        "{} transaction {} of client {} isn't one of its transactions",
+                        kind, tx, client
+                    )));
+                }
+            }
+        }
+
+        for record in &self.house {
+            for amount in [record.fees, record.chargebacks, record.conversions] {
+                check_amount(amount, true, || "house account".to_string())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that an amount has no more than the 4 decimal places that amounts are rounded to, and
+/// isn't negative unless it can be. `what` describes the amount for the error.
+fn check_amount(
+    amount: MonetaryAmount,
+    can_be_negative: bool,
+    what: impl FnOnce() -> String,
+) -> Result<(), SnapshotError> {
+    if amount.normalize().scale() > 4 {
+        Err(SnapshotError::Inconsistent(format!(
+            "{} {} has more than 4 decimal places",
+            what(),
+            amount
+        )))
+    } else if !can_be_negative && amount.is_sign_negative() && !amount.is_zero() {
+        Err(SnapshotError::Inconsistent(format!(
+            "{} {} is negative",
+            what(),
+            amount
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+impl TransactionRecord {
+    pub(crate) fn new(
+        tx: TransactionId,
+        request: MonetaryTransaction,
+        asset: Asset,
+        fee: MonetaryAmount,
+        claim: ClaimState,
+        timestamp: Option<Timestamp>,
+    ) -> Self {
+        let request = match request {
+            MonetaryTransaction::Deposit(amount) => TransactionType::Deposit { amount },
+            MonetaryTransaction::Withdrawal(amount) => TransactionType::Withdrawal { amount },
+            MonetaryTransaction::Transfer {
+                amount,
+                destination,
+            } => TransactionType::Transfer {
+                amount,
+                destination,
+            },
+            MonetaryTransaction::Convert { amount, to } => TransactionType::Convert {
+                amount,
+                to_asset: to,
+            },
+        };
+        Self {
+            tx,
+            request,
+            asset,
+            fee,
+            claim,
+            timestamp,
+        }
+    }
+
+    pub(crate) fn request(&self) -> MonetaryTransaction {
+        match self.request {
+            TransactionType::Deposit { amount } => MonetaryTransaction::Deposit(amount),
+            TransactionType::Withdrawal { amount } => MonetaryTransaction::Withdrawal(amount),
+            TransactionType::Transfer {
+                amount,
+                destination,
+            } => MonetaryTransaction::Transfer {
+                amount,
+                destination,
+            },
+            TransactionType::Convert { amount, to_asset } => MonetaryTransaction::Convert {
+                amount,
+                to: to_asset,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct HouseRecord {
+    #[serde(default, skip_serializing_if = "Asset::is_default")]
+    pub(crate) asset: Asset,
+    #[serde(default, skip_serializing_if = "MonetaryAmount::is_zero")]
+    pub(crate) fees: MonetaryAmount,
+    #[serde(default, skip_serializing_if = "MonetaryAmount::is_zero")]
+    pub(crate) chargebacks: MonetaryAmount,
+    #[serde(default, skip_serializing_if = "MonetaryAmount::is_zero")]
+    pub(crate) conversions: MonetaryAmount,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct OwnerRecord {
+    pub(crate) tx: TransactionId,
+    pub(crate) client: ClientId,
+}
+
+/// Just enough of a snapshot to check its version before reading the rest.
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+pub(crate) fn write_snapshot<W: std::io::Write>(
+    wtr: W,
+    snapshot: &Snapshot,
+) -> Result<(), SnapshotError> {
+    let mut wtr = std::io::BufWriter::new(wtr);
+    serde_json::to_writer(&mut wtr, snapshot)?;
+    wtr.flush()?;
+    Ok(())
+}
+
+pub(crate) fn read_snapshot<R: std::io::Read>(mut rdr: R) -> Result<Snapshot, SnapshotError> {
+    let mut contents = Vec::new();
+    rdr.read_to_end(&mut contents)?;
+
+    let header = serde_json::from_slice::<Header>(&contents)?;
+    if header.version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion {
+            found: header.version,
+            expected: SNAPSHOT_VERSION,
+        });
+    }
+
+    let snapshot = serde_json::from_slice::<Snapshot>(&contents)?;
+    snapshot.validate()?;
+    Ok(snapshot)
+}
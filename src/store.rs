@@ -0,0 +1,646 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    exchange::ClaimState,
+    types::{Asset, ClientId, MonetaryAmount, MonetaryTransaction, Timestamp, TransactionId},
+};
+
+/// Number of independently locked partitions, so that the shards of a
+/// [`ShardedExchange`](crate::ShardedExchange) can share a store without much contention.
+const PARTITIONS: usize = 64;
+
+/// Size of a transaction on disk.
+const RECORD_SIZE: usize = 96;
+
+/// Rough number of bytes each transaction kept in memory uses, including the overhead of the
+/// map and eviction queue.
+const ENTRY_SIZE: usize =
+    size_of::<(TransactionId, TransactionInformation)>() * 8 / 7 + size_of::<TransactionId>() + 1;
+
+/// Every deposit and withdrawal that has been applied, so that it can be claimed later.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TransactionInformation {
+    /// The client that owns the transaction.
+    pub(crate) client: ClientId,
+    pub(crate) request: MonetaryTransaction,
+    pub(crate) asset: Asset,
+    /// The fee charged on the transaction.
+    pub(crate) fee: MonetaryAmount,
+    /// How much of the transaction is disputed and charged back.
+    pub(crate) claim: ClaimState,
+    /// When the transaction was made, if known.
+    pub(crate) timestamp: Option<Timestamp>,
+}
+
+/// Storage for the deposits and withdrawals applied to an [`Exchange`](crate::Exchange), which
+/// are kept for as long as the exchange exists in case they're disputed.
+///
+/// By default every transaction is kept in memory. A store created with
+/// [`TransactionStore::spill_to_disk`] keeps the most recent transactions in memory, within a
+/// budget, and moves older ones to files indexed by transaction ID, so that looking up an old
+/// transaction takes a single read.
+///
+/// Transactions that have left an [`Exchange`](crate::Exchange)'s
+/// [`DisputeWindow`](crate::DisputeWindow) are expired: their details are dropped and only their
+/// owner is kept, so that their IDs can't be reused. A store that spills to disk counts the owners
+/// it keeps in memory against its budget, and moves them to disk along with the transactions.
+pub struct TransactionStore {
+    partitions: Vec<Mutex<Partition>>,
+}
+
+struct Partition {
+    memory: HashMap<TransactionId, TransactionInformation>,
+    /// Transactions in memory, oldest first, if they're spilled to disk. Can include
+    /// transactions that have since expired.
+    order: VecDeque<TransactionId>,
+    spill: Option<SpillFile>,
+    /// The owners of expired transactions kept in memory.
+    expired: HashMap<TransactionId, ClientId>,
+}
+
+/// What's stored under a transaction ID on disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Record {
+    Transaction(TransactionInformation),
+    /// The transaction has expired, and only its owner is kept.
+    Expired(ClientId),
+}
+
+impl Default for TransactionStore {
+    fn default() -> Self {
+        Self::in_memory()
+    }
+}
+
+impl TransactionStore {
+    /// A store that keeps every transaction in memory.
+    pub fn in_memory() -> Self {
+        let partitions = (0..PARTITIONS)
+            .map(|_| {
+                Mutex::new(Partition {
+                    memory: HashMap::new(),
+                    order: VecDeque::new(),
+                    spill: None,
+                    expired: HashMap::new(),
+                })
+            })
+            .collect();
+        Self { partitions }
+    }
+
+    /// A store that keeps roughly `memory_budget` bytes of the most recent transactions in memory
+    /// and moves older ones to files in `dir`. The files are removed when the store is dropped.
+    ///
+    /// The files are indexed by transaction ID, so they are sparse and grow with the largest
+    /// transaction ID moved to disk, up to 96 bytes per ID.
+    pub fn spill_to_disk(dir: impl AsRef<Path>, memory_budget: usize) -> std::io::Result<Self> {
+        let capacity = (memory_budget / ENTRY_SIZE / PARTITIONS).max(1);
+        let partitions = (0..PARTITIONS)
+            .map(|index| {
+                let path = dir.as_ref().join(format!("transactions-{}.bin", index));
+                Ok(Mutex::new(Partition {
+                    memory: HashMap::new(),
+                    order: VecDeque::new(),
+                    spill: Some(SpillFile::create(path, index, capacity)?),
+                    expired: HashMap::new(),
+                }))
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self { partitions })
+    }
+
+    fn partition(&self, transaction: TransactionId) -> MutexGuard<'_, Partition> {
+        self.partitions[transaction.0 as usize % PARTITIONS]
+            .lock()
+            .expect("Transaction store lock poisoned")
+    }
+
+    pub(crate) fn get(
+        &self,
+        transaction: TransactionId,
+    ) -> std::io::Result<Option<TransactionInformation>> {
+        let mut partition = self.partition(transaction);
+        if let Some(info) = partition.memory.get(&transaction) {
+            return Ok(Some(*info));
+        }
+        match partition.spill.as_mut() {
+            Some(spill) => Ok(match spill.read(transaction)? {
+                Some(Record::Transaction(info)) => Some(info),
+                Some(Record::Expired(_)) | None => None,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Record a new transaction, moving the oldest transactions in memory to disk if over budget.
+    pub(crate) fn insert(
+        &self,
+        transaction: TransactionId,
+        info: TransactionInformation,
+    ) -> std::io::Result<()> {
+        let mut partition = self.partition(transaction);
+        partition.memory.insert(transaction, info);
+        if partition.spill.is_some() {
+            partition.order.push_back(transaction);
+        }
+        partition.spill_over_budget()
+    }
+
+    /// Change the claim against a transaction, wherever it's stored. Returns whether the
+    /// transaction exists.
+    pub(crate) fn set_claim(
+        &self,
+        transaction: TransactionId,
+        claim: ClaimState,
+    ) -> std::io::Result<bool> {
+        let mut partition = self.partition(transaction);
+        if let Some(info) = partition.memory.get_mut(&transaction) {
+            info.claim = claim;
+            return Ok(true);
+        }
+        match partition.spill.as_mut() {
+            Some(spill) => spill.set_claim(transaction, claim),
+            None => Ok(false),
+        }
+    }
+
+    /// Drop the details of `transaction`, wherever it's stored, remembering only that it's owned
+    /// by `client`.
+    pub(crate) fn expire(
+        &self,
+        transaction: TransactionId,
+        client: ClientId,
+    ) -> std::io::Result<()> {
+        let mut partition = self.partition(transaction);
+        if partition.memory.remove(&transaction).is_some() {
+            // Expired transactions are only removed from the eviction queue when they reach the
+            // front, so it's compacted once they make up most of it
+            if partition.order.len() > 2 * partition.memory.len() + PARTITIONS {
+                let Partition { memory, order, .. } = &mut *partition;
+                order.retain(|id| memory.contains_key(id));
+            }
+        } else if let Some(spill) = partition.spill.as_mut() {
+            // Replaced by the tombstone straight away, rather than kept in memory
+            if spill.read(transaction)?.is_some() {
+                return spill.write(transaction, Record::Expired(client));
+            }
+        }
+        partition.expired.insert(transaction, client);
+        partition.spill_over_budget()
+    }
+
+    /// The owner of `transaction`, if it has expired.
+    pub(crate) fn expired_owner(
+        &self,
+        transaction: TransactionId,
+    ) -> std::io::Result<Option<ClientId>> {
+        let mut partition = self.partition(transaction);
+        if let Some(client) = partition.expired.get(&transaction) {
+            return Ok(Some(*client));
+        }
+        match partition.spill.as_mut() {
+            Some(spill) => Ok(match spill.read(transaction)? {
+                Some(Record::Expired(client)) => Some(client),
+                Some(Record::Transaction(_)) | None => None,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Move every transaction in `other` into this store.
+    pub(crate) fn extend_from(&self, other: &TransactionStore) -> std::io::Result<()> {
+        for (transaction, info) in other.to_vec()? {
+            self.insert(transaction, info)?;
+        }
+        for (transaction, client) in other.expired_to_vec()? {
+            self.expire(transaction, client)?;
+        }
+        Ok(())
+    }
+
+    /// Every transaction in the store, in no particular order.
+    pub(crate) fn to_vec(&self) -> std::io::Result<Vec<(TransactionId, TransactionInformation)>> {
+        let mut transactions = Vec::new();
+        for partition in &self.partitions {
+            let mut partition = partition.lock().expect("Transaction store lock poisoned");
+            transactions.extend(partition.memory.iter().map(|(id, info)| (*id, *info)));
+            if let Some(spill) = partition.spill.as_mut() {
+                spill.read_all(|id, record| {
+                    if let Record::Transaction(info) = record {
+                        transactions.push((id, info));
+                    }
+                })?;
+            }
+        }
+        Ok(transactions)
+    }
+
+    /// The ID and owner of every expired transaction, in no particular order.
+    pub(crate) fn expired_to_vec(&self) -> std::io::Result<Vec<(TransactionId, ClientId)>> {
+        let mut expired = Vec::new();
+        for partition in &self.partitions {
+            let mut partition = partition.lock().expect("Transaction store lock poisoned");
+            expired.extend(partition.expired.iter().map(|(id, client)| (*id, *client)));
+            if let Some(spill) = partition.spill.as_mut() {
+                spill.read_all(|id, record| {
+                    if let Record::Expired(client) = record {
+                        expired.push((id, client));
+                    }
+                })?;
+            }
+        }
+        Ok(expired)
+    }
+}
+
+impl Partition {
+    /// Move the owners of expired transactions, and then the oldest transactions, to disk until
+    /// what's left in memory is within budget.
+    fn spill_over_budget(&mut self) -> std::io::Result<()> {
+        let Partition {
+            memory,
+            order,
+            spill,
+            expired,
+        } = self;
+        let Some(spill) = spill else {
+            return Ok(());
+        };
+
+        if memory.len() + expired.len() > spill.capacity {
+            for (transaction, client) in expired.drain() {
+                spill.write(transaction, Record::Expired(client))?;
+            }
+        }
+        while memory.len() > spill.capacity {
+            let Some(oldest) = order.pop_front() else {
+                break;
+            };
+            if let Some(info) = memory.remove(&oldest) {
+                spill.write(oldest, Record::Transaction(info))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transactions moved out of memory, each at an offset given by its ID.
+struct SpillFile {
+    file: File,
+    path: PathBuf,
+    /// Index of the partition, which every ID in the file is congruent to.
+    partition: usize,
+    /// Maximum number of transactions to keep in memory before spilling.
+    capacity: usize,
+    /// Smallest and largest IDs written, so that most lookups of new IDs don't touch the disk.
+    written: Option<(TransactionId, TransactionId)>,
+}
+
+impl SpillFile {
+    fn create(path: PathBuf, partition: usize, capacity: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            file,
+            path,
+            partition,
+            capacity,
+            written: None,
+        })
+    }
+
+    fn offset(transaction: TransactionId) -> u64 {
+        (transaction.0 as u64 / PARTITIONS as u64) * RECORD_SIZE as u64
+    }
+
+    fn id_at(&self, offset: u64) -> TransactionId {
+        let id = offset / RECORD_SIZE as u64 * PARTITIONS as u64 + self.partition as u64;
+        TransactionId(id as u32)
+    }
+
+    fn might_contain(&self, transaction: TransactionId) -> bool {
+        self.written
+            .is_some_and(|(min, max)| (min..=max).contains(&transaction))
+    }
+
+    fn read(&mut self, transaction: TransactionId) -> std::io::Result<Option<Record>> {
+        if !self.might_contain(transaction) {
+            return Ok(None);
+        }
+
+        let mut record = [0; RECORD_SIZE];
+        self.file.seek(SeekFrom::Start(Self::offset(transaction)))?;
+        self.file.read_exact(&mut record)?;
+        Ok(decode(&record))
+    }
+
+    fn write(&mut self, transaction: TransactionId, record: Record) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(Self::offset(transaction)))?;
+        self.file.write_all(&encode(&record))?;
+
+        self.written = Some(match self.written {
+            Some((min, max)) => (min.min(transaction), max.max(transaction)),
+            None => (transaction, transaction),
+        });
+        Ok(())
+    }
+
+    fn set_claim(
+        &mut self,
+        transaction: TransactionId,
+        claim: ClaimState,
+    ) -> std::io::Result<bool> {
+        let Some(Record::Transaction(mut info)) = self.read(transaction)? else {
+            return Ok(false);
+        };
+        info.claim = claim;
+        self.write(transaction, Record::Transaction(info))?;
+        Ok(true)
+    }
+
+    /// Call `f` with every record in the file, in ascending order of ID.
+    fn read_all(&mut self, mut f: impl FnMut(TransactionId, Record)) -> std::io::Result<()> {
+        let Some((min, _)) = self.written else {
+            return Ok(());
+        };
+
+        self.file.seek(SeekFrom::Start(Self::offset(min)))?;
+        let mut rdr = std::io::BufReader::new(&self.file);
+        let mut offset = Self::offset(min);
+        let mut record = [0; RECORD_SIZE];
+        loop {
+            match rdr.read_exact(&mut record) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            if let Some(record) = decode(&record) {
+                f(self.id_at(offset), record);
+            }
+            offset += RECORD_SIZE as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Lay out a transaction as: a byte that's 1 if the record is present (so that the gaps in a
+/// sparse file read as absent), the client ID, the transaction type, an unused byte, a byte that's
+/// 1 if there's a timestamp, a transfer's destination, the amount, the timestamp, the asset, the
+/// asset a conversion is to, the fee, the amount disputed and the amount charged back.
+///
+/// An expired transaction is a byte that's 2 followed by the client ID, and zeroes.
+fn encode(record: &Record) -> [u8; RECORD_SIZE] {
+    let info = match record {
+        Record::Transaction(info) => info,
+        Record::Expired(client) => {
+            let mut record = [0; RECORD_SIZE];
+            record[0] = 2;
+            record[1..3].copy_from_slice(&client.0.to_le_bytes());
+            return record;
+        }
+    };
+    let (transaction_type, amount, destination, to) = match info.request {
+        MonetaryTransaction::Deposit(amount) => (0, amount, ClientId(0), Asset::default()),
+        MonetaryTransaction::Withdrawal(amount) => (1, amount, ClientId(0), Asset::default()),
+        MonetaryTransaction::Transfer {
+            amount,
+            destination,
+        } => (2, amount, destination, Asset::default()),
+        MonetaryTransaction::Convert { amount, to } => (3, amount, ClientId(0), to),
+    };
+    let mut record = [0; RECORD_SIZE];
+    record[0] = 1;
+    record[1..3].copy_from_slice(&info.client.0.to_le_bytes());
+    record[3] = transaction_type;
+    record[6..8].copy_from_slice(&destination.0.to_le_bytes());
+    record[8..24].copy_from_slice(&amount.serialize());
+    if let Some(timestamp) = info.timestamp {
+        record[5] = 1;
+        record[24..32].copy_from_slice(&timestamp.as_millis().to_le_bytes());
+    }
+    record[32..40].copy_from_slice(&info.asset.to_bytes());
+    record[40..48].copy_from_slice(&to.to_bytes());
+    record[48..64].copy_from_slice(&info.fee.serialize());
+    record[64..80].copy_from_slice(&info.claim.disputed.serialize());
+    record[80..96].copy_from_slice(&info.claim.charged_back.serialize());
+    record
+}
+
+fn decode(record: &[u8; RECORD_SIZE]) -> Option<Record> {
+    let client = ClientId(u16::from_le_bytes([record[1], record[2]]));
+    match record[0] {
+        1 => {}
+        2 => return Some(Record::Expired(client)),
+        _ => return None,
+    }
+
+    let amount = Decimal::deserialize(record[8..24].try_into().expect("Amount is 16 bytes"));
+    let request = match record[3] {
+        0 => MonetaryTransaction::Deposit(amount),
+        1 => MonetaryTransaction::Withdrawal(amount),
+        2 => MonetaryTransaction::Transfer {
+            amount,
+            destination: ClientId(u16::from_le_bytes([record[6], record[7]])),
+        },
+        _ => MonetaryTransaction::Convert {
+            amount,
+            to: Asset::from_bytes(record[40..48].try_into().expect("Asset is 8 bytes")),
+        },
+    };
+    let timestamp = (record[5] == 1).then(|| {
+        Timestamp::from_millis(i64::from_le_bytes(
+            record[24..32].try_into().expect("Timestamp is 8 bytes"),
+        ))
+    });
+
+    let asset = Asset::from_bytes(record[32..40].try_into().expect("Asset is 8 bytes"));
+    let fee = Decimal::deserialize(record[48..64].try_into().expect("Fee is 16 bytes"));
+    let claim = ClaimState {
+        disputed: Decimal::deserialize(record[64..80].try_into().expect("Amount is 16 bytes")),
+        charged_back: Decimal::deserialize(record[80..96].try_into().expect("Amount is 16 bytes")),
+    };
+
+    Some(Record::Transaction(TransactionInformation {
+        client,
+        request,
+        asset,
+        fee,
+        claim,
+        timestamp,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    fn spill_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("store-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn deposit(client: u16) -> TransactionInformation {
+        TransactionInformation {
+            client: ClientId(client),
+            request: MonetaryTransaction::Deposit(dec!(1.2345)),
+            asset: Asset::default(),
+            fee: MonetaryAmount::ZERO,
+            claim: ClaimState::default(),
+            timestamp: Some(Timestamp::from_millis(-i64::from(client))),
+        }
+    }
+
+    #[test]
+    fn test_encode_transfer() {
+        let transfer = TransactionInformation {
+            client: ClientId(3),
+            request: MonetaryTransaction::Transfer {
+                amount: dec!(2.5),
+                destination: ClientId(513),
+            },
+            asset: "USDT1234".parse().unwrap(),
+            fee: MonetaryAmount::ZERO,
+            claim: ClaimState::default(),
+            timestamp: None,
+        };
+
+        let transfer = Record::Transaction(transfer);
+        assert_eq!(decode(&encode(&transfer)), Some(transfer));
+    }
+
+    #[test]
+    fn test_encode_convert() {
+        let convert = TransactionInformation {
+            client: ClientId(3),
+            request: MonetaryTransaction::Convert {
+                amount: dec!(2.5),
+                to: "EUR".parse().unwrap(),
+            },
+            asset: "USD".parse().unwrap(),
+            fee: dec!(0.0125),
+            claim: ClaimState::default(),
+            timestamp: Some(Timestamp::from_millis(1)),
+        };
+
+        let convert = Record::Transaction(convert);
+        assert_eq!(decode(&encode(&convert)), Some(convert));
+    }
+
+    #[test]
+    fn test_spilled_transactions_can_be_read_and_claimed() {
+        let store = TransactionStore::spill_to_disk(spill_dir("claim"), 0).unwrap();
+        for id in 0..1_000 {
+            store.insert(TransactionId(id), deposit(id as u16)).unwrap();
+        }
+
+        // Only one transaction per partition is kept in memory, so this one is on disk
+        assert_eq!(store.get(TransactionId(7)).unwrap(), Some(deposit(7)));
+        assert_eq!(store.get(TransactionId(1_000)).unwrap(), None);
+
+        let claim = ClaimState {
+            disputed: dec!(0.25),
+            charged_back: dec!(0.5),
+        };
+        assert!(store.set_claim(TransactionId(7), claim).unwrap());
+        assert_eq!(store.get(TransactionId(7)).unwrap().unwrap().claim, claim);
+        assert!(
+            !store
+                .set_claim(TransactionId(1_000), ClaimState::default())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_to_vec_includes_spilled_transactions() {
+        let store = TransactionStore::spill_to_disk(spill_dir("to-vec"), 0).unwrap();
+        for id in (0..2_000).step_by(3) {
+            store.insert(TransactionId(id), deposit(1)).unwrap();
+        }
+
+        let mut ids = store
+            .to_vec()
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id.0)
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, (0..2_000).step_by(3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_expired_transactions_keep_only_owner() {
+        let store = TransactionStore::spill_to_disk(spill_dir("expire"), 0).unwrap();
+        for id in 0..1_000 {
+            store.insert(TransactionId(id), deposit(id as u16)).unwrap();
+        }
+
+        // One transaction on disk and one still in memory
+        store.expire(TransactionId(7), ClientId(7)).unwrap();
+        store.expire(TransactionId(999), ClientId(999)).unwrap();
+
+        for id in [7, 999] {
+            assert_eq!(store.get(TransactionId(id)).unwrap(), None);
+            assert_eq!(
+                store.expired_owner(TransactionId(id)).unwrap(),
+                Some(ClientId(id as u16))
+            );
+        }
+        assert_eq!(store.to_vec().unwrap().len(), 998);
+        assert_eq!(store.expired_to_vec().unwrap().len(), 2);
+        assert_eq!(store.expired_owner(TransactionId(8)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_expired_owners_are_spilled_within_budget() {
+        let store = TransactionStore::spill_to_disk(spill_dir("expire-budget"), 0).unwrap();
+        for id in 0..1_000 {
+            store.insert(TransactionId(id), deposit(id as u16)).unwrap();
+            store
+                .expire(TransactionId(id), ClientId(id as u16))
+                .unwrap();
+        }
+
+        // Only one expired owner per partition is kept in memory
+        for partition in &store.partitions {
+            let partition = partition.lock().unwrap();
+            assert!(partition.memory.len() + partition.expired.len() <= 1);
+        }
+        assert_eq!(
+            store.expired_owner(TransactionId(7)).unwrap(),
+            Some(ClientId(7))
+        );
+        assert_eq!(store.get(TransactionId(7)).unwrap(), None);
+        assert_eq!(store.expired_to_vec().unwrap().len(), 1_000);
+        assert!(store.to_vec().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_spill_files_removed_on_drop() {
+        let dir = spill_dir("drop");
+        let store = TransactionStore::spill_to_disk(&dir, 0).unwrap();
+        store.insert(TransactionId(1), deposit(1)).unwrap();
+        drop(store);
+
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+}
@@ -1,36 +1,897 @@
+use std::{fmt, str::FromStr};
+
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
+use crate::error::{ProcessTransactionError, Result};
+
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Serialize, PartialOrd, Ord)]
 pub struct ClientId(pub u16);
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Serialize, PartialOrd, Ord)]
 pub struct TransactionId(pub u32);
 
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// An operator who can make [`AdminRequest`]s, once authorized with
+/// [`Exchange::set_operators`](crate::Exchange::set_operators).
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Serialize, PartialOrd, Ord)]
+pub struct OperatorId(pub u32);
+
+impl fmt::Display for OperatorId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 pub type MonetaryAmount = Decimal;
 
-#[derive(Debug)]
+/// A point in time, with millisecond precision.
+///
+/// Read from either an RFC 3339 date and time, such as `2024-05-01T09:30:00Z`, or a number of
+/// milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub fn from_millis(millis: i64) -> Self {
+        Self(millis)
+    }
+
+    /// Milliseconds since the Unix epoch.
+    pub fn as_millis(&self) -> i64 {
+        self.0
+    }
+
+    /// The timestamp `duration` earlier, saturating at the earliest representable timestamp.
+    pub fn saturating_sub(&self, duration: std::time::Duration) -> Self {
+        let millis = i64::try_from(duration.as_millis()).unwrap_or(i64::MAX);
+        Self(self.0.saturating_sub(millis))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match chrono::DateTime::from_timestamp_millis(self.0) {
+            Some(time) => f.write_str(&time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            None => self.0.fmt(f),
+        }
+    }
+}
+
+/// Why a timestamp could not be parsed.
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid timestamp {0:?}, expected RFC 3339 or milliseconds since the Unix epoch")]
+pub struct ParseTimestampError(String);
+
+impl FromStr for Timestamp {
+    type Err = ParseTimestampError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(millis) = s.parse() {
+            return Ok(Self(millis));
+        }
+        chrono::DateTime::parse_from_rfc3339(s)
+            .map(|time| Self(time.timestamp_millis()))
+            .map_err(|_| ParseTimestampError(s.to_string()))
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        struct TimestampVisitor;
+
+        impl serde::de::Visitor<'_> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an RFC 3339 timestamp or milliseconds since the Unix epoch")
+            }
+
+            fn visit_i64<E: serde::de::Error>(
+                self,
+                millis: i64,
+            ) -> std::result::Result<Timestamp, E> {
+                Ok(Timestamp(millis))
+            }
+
+            fn visit_u64<E: serde::de::Error>(
+                self,
+                millis: u64,
+            ) -> std::result::Result<Timestamp, E> {
+                i64::try_from(millis)
+                    .map(Timestamp)
+                    .map_err(|_| E::custom("timestamp out of range"))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> std::result::Result<Timestamp, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+/// The asset or currency a balance is held in, such as `USD` or `BTC`.
+///
+/// Codes are up to 8 ASCII letters or digits, and are case sensitive. Rows without one are in
+/// the default asset, which has an empty code, so that files in a single currency don't need to
+/// name it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Asset([u8; Asset::MAX_LEN]);
+
+impl Asset {
+    pub const MAX_LEN: usize = 8;
+
+    pub fn is_default(&self) -> bool {
+        *self == Asset::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self
+            .0
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(Asset::MAX_LEN);
+        std::str::from_utf8(&self.0[..len]).expect("Asset codes are ASCII")
+    }
+
+    /// The code padded with zeros, as kept in a [`TransactionStore`](crate::TransactionStore).
+    pub(crate) fn to_bytes(self) -> [u8; Asset::MAX_LEN] {
+        self.0
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; Asset::MAX_LEN]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why an asset code could not be parsed.
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid asset {0:?}, expected up to 8 ASCII letters or digits")]
+pub struct ParseAssetError(String);
+
+impl FromStr for Asset {
+    type Err = ParseAssetError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.len() > Asset::MAX_LEN || !s.bytes().all(|byte| byte.is_ascii_alphanumeric()) {
+            return Err(ParseAssetError(s.to_string()));
+        }
+        let mut code = [0; Asset::MAX_LEN];
+        code[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self(code))
+    }
+}
+
+impl Serialize for Asset {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Asset {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        struct AssetVisitor;
+
+        impl serde::de::Visitor<'_> for AssetVisitor {
+            type Value = Asset;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an asset code of up to 8 ASCII letters or digits")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> std::result::Result<Asset, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(AssetVisitor)
+    }
+}
+
+/// Why the status of an account was changed, such as `risk_cleared` or `fraud`, kept in the
+/// audit trail.
+///
+/// Codes are up to 16 ASCII letters, digits, underscores or hyphens, and are case sensitive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ReasonCode([u8; ReasonCode::MAX_LEN]);
+
+impl ReasonCode {
+    pub const MAX_LEN: usize = 16;
+
+    /// The reason given for locking an account after a chargeback.
+    pub const CHARGEBACK: ReasonCode = ReasonCode(*b"chargeback\0\0\0\0\0\0");
+
+    pub fn as_str(&self) -> &str {
+        let len = self
+            .0
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(ReasonCode::MAX_LEN);
+        std::str::from_utf8(&self.0[..len]).expect("Reason codes are ASCII")
+    }
+}
+
+impl fmt::Display for ReasonCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a reason code could not be parsed.
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "Invalid reason code {0:?}, expected 1 to 16 ASCII letters, digits, underscores or hyphens"
+)]
+pub struct ParseReasonCodeError(String);
+
+impl FromStr for ReasonCode {
+    type Err = ParseReasonCodeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let valid = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-';
+        if s.is_empty() || s.len() > ReasonCode::MAX_LEN || !s.bytes().all(valid) {
+            return Err(ParseReasonCodeError(s.to_string()));
+        }
+        let mut code = [0; ReasonCode::MAX_LEN];
+        code[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self(code))
+    }
+}
+
+impl Serialize for ReasonCode {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ReasonCode {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        struct ReasonCodeVisitor;
+
+        impl serde::de::Visitor<'_> for ReasonCodeVisitor {
+            type Value = ReasonCode;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(
+                    "a reason code of up to 16 ASCII letters, digits, underscores or hyphens",
+                )
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> std::result::Result<ReasonCode, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(ReasonCodeVisitor)
+    }
+}
+
+/// A single request to the [`Exchange`](crate::Exchange).
+///
+/// Deposits and withdrawals can only be constructed with a valid amount.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct TransactionRequest {
-    pub client: ClientId,
-    pub transaction: TransactionId,
-    pub request_type: RequestType,
+    pub(crate) client: ClientId,
+    pub(crate) transaction: TransactionId,
+    pub(crate) request_type: RequestType,
+    pub(crate) asset: Asset,
+    pub(crate) timestamp: Option<Timestamp>,
+}
+
+impl TransactionRequest {
+    /// A deposit of `amount`, which must not be negative and is rounded to 4 decimal places.
+    pub fn deposit(
+        client: ClientId,
+        transaction: TransactionId,
+        amount: MonetaryAmount,
+    ) -> Result<Self> {
+        Ok(Self {
+            client,
+            transaction,
+            request_type: RequestType::Monetary(MonetaryTransaction::Deposit(validate_amount(
+                Some(amount),
+            )?)),
+            asset: Asset::default(),
+            timestamp: None,
+        })
+    }
+
+    /// A withdrawal of `amount`, which must not be negative and is rounded to 4 decimal places.
+    pub fn withdrawal(
+        client: ClientId,
+        transaction: TransactionId,
+        amount: MonetaryAmount,
+    ) -> Result<Self> {
+        Ok(Self {
+            client,
+            transaction,
+            request_type: RequestType::Monetary(MonetaryTransaction::Withdrawal(validate_amount(
+                Some(amount),
+            )?)),
+            asset: Asset::default(),
+            timestamp: None,
+        })
+    }
+
+    /// A transfer of `amount` from `client` to `destination`, which must be a different client.
+    ///
+    /// The amount must not be negative and is rounded to 4 decimal places.
+    pub fn transfer(
+        client: ClientId,
+        transaction: TransactionId,
+        destination: ClientId,
+        amount: MonetaryAmount,
+    ) -> Result<Self> {
+        Ok(Self {
+            client,
+            transaction,
+            request_type: RequestType::Monetary(MonetaryTransaction::Transfer {
+                amount: validate_amount(Some(amount))?,
+                destination: validate_destination(client, Some(destination))?,
+            }),
+            asset: Asset::default(),
+            timestamp: None,
+        })
+    }
+
+    /// A conversion of `amount` of the client's `asset` into `to`, at the rate in the exchange's
+    /// [`RateTable`](crate::RateTable) when the conversion is applied.
+    ///
+    /// The amount must not be negative and is rounded to 4 decimal places.
+    pub fn convert(
+        client: ClientId,
+        transaction: TransactionId,
+        asset: Asset,
+        to: Asset,
+        amount: MonetaryAmount,
+    ) -> Result<Self> {
+        Ok(Self {
+            client,
+            transaction,
+            request_type: RequestType::Monetary(MonetaryTransaction::Convert {
+                amount: validate_amount(Some(amount))?,
+                to: validate_target_asset(asset, Some(to))?,
+            }),
+            asset,
+            timestamp: None,
+        })
+    }
+
+    /// A claim against an earlier deposit or withdrawal made by the same client. A dispute is for
+    /// whatever of the transaction isn't disputed or charged back yet, and a resolve or
+    /// chargeback for everything that's disputed.
+    pub fn claim(client: ClientId, transaction: TransactionId, claim_type: ClaimType) -> Self {
+        Self {
+            client,
+            transaction,
+            request_type: RequestType::Claim(ClaimRequest {
+                claim_type,
+                amount: None,
+            }),
+            asset: Asset::default(),
+            timestamp: None,
+        }
+    }
+
+    /// A claim against `amount` of an earlier deposit or withdrawal made by the same client.
+    ///
+    /// The amount must be positive and is rounded to 4 decimal places.
+    pub fn partial_claim(
+        client: ClientId,
+        transaction: TransactionId,
+        claim_type: ClaimType,
+        amount: MonetaryAmount,
+    ) -> Result<Self> {
+        Ok(Self {
+            client,
+            transaction,
+            request_type: RequestType::Claim(ClaimRequest {
+                claim_type,
+                amount: validate_claim_amount(Some(amount))?,
+            }),
+            asset: Asset::default(),
+            timestamp: None,
+        })
+    }
+
+    /// An administrative change to `client`'s account, made by `operator` for `reason`.
+    /// `transaction` identifies the request in the audit trail, and isn't checked against the IDs
+    /// of other transactions.
+    pub fn admin(
+        client: ClientId,
+        transaction: TransactionId,
+        action: AdminAction,
+        operator: OperatorId,
+        reason: ReasonCode,
+    ) -> Self {
+        Self {
+            client,
+            transaction,
+            request_type: RequestType::Admin(AdminRequest {
+                action,
+                operator,
+                reason,
+            }),
+            asset: Asset::default(),
+            timestamp: None,
+        }
+    }
+
+    /// An administrative change to `client`'s credit limit in the default asset, or in another
+    /// given with [`TransactionRequest::with_asset`], made by `operator` for `reason`.
+    ///
+    /// The limit must not be negative and is rounded to 4 decimal places.
+    pub fn set_limit(
+        client: ClientId,
+        transaction: TransactionId,
+        limit: MonetaryAmount,
+        operator: OperatorId,
+        reason: ReasonCode,
+    ) -> Result<Self> {
+        let action = AdminAction::SetLimit(validate_amount(Some(limit))?);
+        Ok(Self::admin(client, transaction, action, operator, reason))
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    pub fn transaction(&self) -> TransactionId {
+        self.transaction
+    }
+
+    pub fn request_type(&self) -> RequestType {
+        self.request_type
+    }
+
+    /// The asset of a deposit, withdrawal or transfer, or the asset a conversion is from. Claims
+    /// are applied in the asset of the transaction they refer to, whatever this is.
+    pub fn asset(&self) -> Asset {
+        self.asset
+    }
+
+    pub fn with_asset(mut self, asset: Asset) -> Self {
+        self.asset = asset;
+        self
+    }
+
+    /// When the request was made, if known.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    pub fn with_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+pub(crate) fn validate_amount(amount: Option<MonetaryAmount>) -> Result<MonetaryAmount> {
+    let amount = amount.ok_or(ProcessTransactionError::MissingAmount)?;
+
+    if amount.is_sign_positive() {
+        Ok(amount.round_dp(4))
+    } else {
+        Err(ProcessTransactionError::NegativeAmount)
+    }
+}
+
+/// A claim's amount is optional, but if it's given it has to be for something.
+pub(crate) fn validate_claim_amount(
+    amount: Option<MonetaryAmount>,
+) -> Result<Option<MonetaryAmount>> {
+    let Some(amount) = amount else {
+        return Ok(None);
+    };
+    match validate_amount(Some(amount))? {
+        amount if amount.is_zero() => Err(ProcessTransactionError::ZeroClaimAmount),
+        amount => Ok(Some(amount)),
+    }
+}
+
+pub(crate) fn validate_destination(
+    client: ClientId,
+    destination: Option<ClientId>,
+) -> Result<ClientId> {
+    match destination {
+        None => Err(ProcessTransactionError::MissingDestination),
+        Some(destination) if destination == client => Err(ProcessTransactionError::TransferToSelf),
+        Some(destination) => Ok(destination),
+    }
+}
+
+pub(crate) fn validate_target_asset(asset: Asset, to: Option<Asset>) -> Result<Asset> {
+    match to {
+        None => Err(ProcessTransactionError::MissingTargetAsset),
+        Some(to) if to == asset => Err(ProcessTransactionError::ConvertToSameAsset),
+        Some(to) => Ok(to),
+    }
+}
+
+pub(crate) fn validate_admin(
+    action: AdminAction,
+    operator: Option<OperatorId>,
+    reason: Option<ReasonCode>,
+) -> Result<AdminRequest> {
+    Ok(AdminRequest {
+        action,
+        operator: operator.ok_or(ProcessTransactionError::MissingOperator)?,
+        reason: reason.ok_or(ProcessTransactionError::MissingReason)?,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RequestType {
     Monetary(MonetaryTransaction),
-    Claim(ClaimType),
+    Claim(ClaimRequest),
+    Admin(AdminRequest),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MonetaryTransaction {
     Deposit(MonetaryAmount),
     Withdrawal(MonetaryAmount),
+    /// Moves funds from the requesting client to `destination` in one step.
+    Transfer {
+        amount: MonetaryAmount,
+        destination: ClientId,
+    },
+    /// Exchanges `amount` of the request's asset for the asset `to`, within the requesting
+    /// client's account.
+    Convert {
+        amount: MonetaryAmount,
+        to: Asset,
+    },
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ClaimType {
     Dispute,
     Resolve,
     Chargeback,
 }
+
+/// A claim against all or part of an earlier deposit or withdrawal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClaimRequest {
+    pub claim_type: ClaimType,
+    /// How much of the transaction the claim is for. Without one, a dispute is for whatever of
+    /// the transaction isn't disputed or charged back yet, and a resolve or chargeback is for
+    /// everything that's disputed.
+    pub amount: Option<MonetaryAmount>,
+}
+
+/// A change to an account by an operator, who has to be authorized by the exchange.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdminRequest {
+    pub action: AdminAction,
+    pub operator: OperatorId,
+    pub reason: ReasonCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminAction {
+    /// Let a locked or frozen account make transactions again.
+    Unlock,
+    /// Stop the account making transactions until it's unlocked.
+    Freeze,
+    /// Stop the account making transactions for good. Only an account without any funds can be
+    /// closed.
+    Close,
+    /// Let the account's withdrawals in the request's asset take its available funds down to
+    /// minus this much, instead of the limit it had.
+    SetLimit(MonetaryAmount),
+}
+
+impl fmt::Display for RequestType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestType::Monetary(MonetaryTransaction::Deposit(amount)) => {
+                write!(f, "deposit of {}", amount)
+            }
+            RequestType::Monetary(MonetaryTransaction::Withdrawal(amount)) => {
+                write!(f, "withdrawal of {}", amount)
+            }
+            RequestType::Monetary(MonetaryTransaction::Transfer {
+                amount,
+                destination,
+            }) => write!(f, "transfer of {} to client {}", amount, destination),
+            RequestType::Monetary(MonetaryTransaction::Convert { amount, to }) => {
+                write!(f, "conversion of {} to {}", amount, to)
+            }
+            RequestType::Claim(claim) => {
+                let claim_type = match claim.claim_type {
+                    ClaimType::Dispute => "dispute",
+                    ClaimType::Resolve => "resolve",
+                    ClaimType::Chargeback => "chargeback",
+                };
+                match claim.amount {
+                    Some(amount) => write!(f, "{} of {}", claim_type, amount),
+                    None => write!(f, "{}", claim_type),
+                }
+            }
+            RequestType::Admin(admin) => {
+                match admin.action {
+                    AdminAction::Unlock => write!(f, "unlock")?,
+                    AdminAction::Freeze => write!(f, "freeze")?,
+                    AdminAction::Close => write!(f, "close")?,
+                    AdminAction::SetLimit(limit) => write!(f, "limit of {}", limit)?,
+                }
+                write!(f, " by operator {} for {}", admin.operator, admin.reason)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::{Decimal, dec};
+
+    #[test]
+    fn test_validate_amount() {
+        // Standard valid decimal
+        assert_eq!(
+            validate_amount(Some(dec!(100.1234))).unwrap(),
+            dec!(100.1234)
+        );
+        // Valid decimal with more than 4 decimal places has rounding applied using banker's rounding
+        assert_eq!(
+            validate_amount(Some(dec!(100.12345))).unwrap(),
+            dec!(100.1234)
+        );
+        assert_eq!(
+            validate_amount(Some(dec!(100.12343))).unwrap(),
+            dec!(100.1234)
+        );
+        assert_eq!(
+            validate_amount(Some(dec!(100.12346))).unwrap(),
+            dec!(100.1235)
+        );
+        assert_eq!(validate_amount(Some(dec!(0.00001))).unwrap(), dec!(0.0000));
+
+        // Zero is accepted as valid
+        assert_eq!(validate_amount(Some(dec!(0.0))).unwrap(), Decimal::ZERO);
+        // Negative zero is treated as zero
+        assert_eq!(validate_amount(Some(dec!(-0))).unwrap(), Decimal::ZERO);
+
+        // Negative amounts are invalid
+        assert!(validate_amount(Some(dec!(-100.0))).is_err());
+
+        // None is invalid
+        assert!(validate_amount(None).is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let millis = Timestamp::from_millis(1_714_554_000_000);
+
+        assert_eq!("1714554000000".parse::<Timestamp>().unwrap(), millis);
+        assert_eq!("2024-05-01T09:00:00Z".parse::<Timestamp>().unwrap(), millis);
+        assert_eq!(
+            "2024-05-01T10:00:00+01:00".parse::<Timestamp>().unwrap(),
+            millis
+        );
+        assert_eq!(millis.to_string(), "2024-05-01T09:00:00.000Z");
+
+        assert!("yesterday".parse::<Timestamp>().is_err());
+    }
+
+    #[test]
+    fn test_monetary_requests_validate_amount() {
+        let deposit = TransactionRequest::deposit(ClientId(1), TransactionId(1), dec!(1.00005));
+        assert!(matches!(
+            deposit.unwrap().request_type(),
+            RequestType::Monetary(MonetaryTransaction::Deposit(amount)) if amount == dec!(1.0000)
+        ));
+
+        let withdrawal = TransactionRequest::withdrawal(ClientId(1), TransactionId(2), dec!(-1));
+        assert!(matches!(
+            withdrawal.unwrap_err(),
+            ProcessTransactionError::NegativeAmount
+        ));
+    }
+
+    #[test]
+    fn test_partial_claim_validates_amount() {
+        let dispute = TransactionRequest::partial_claim(
+            ClientId(1),
+            TransactionId(1),
+            ClaimType::Dispute,
+            dec!(0.25005),
+        );
+        assert!(matches!(
+            dispute.unwrap().request_type(),
+            RequestType::Claim(ClaimRequest {
+                claim_type: ClaimType::Dispute,
+                amount: Some(amount),
+            }) if amount == dec!(0.2500)
+        ));
+
+        // A claim for nothing would still lock the account if it were charged back
+        let chargeback = |amount| {
+            TransactionRequest::partial_claim(
+                ClientId(1),
+                TransactionId(1),
+                ClaimType::Chargeback,
+                amount,
+            )
+        };
+        assert!(matches!(
+            chargeback(dec!(-1)).unwrap_err(),
+            ProcessTransactionError::NegativeAmount
+        ));
+        for amount in [dec!(0), dec!(0.00001)] {
+            assert!(matches!(
+                chargeback(amount).unwrap_err(),
+                ProcessTransactionError::ZeroClaimAmount
+            ));
+        }
+        assert_eq!(validate_claim_amount(None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_set_limit_validates_limit() {
+        let reason = "overdraft".parse::<ReasonCode>().unwrap();
+        let set_limit = |limit| {
+            TransactionRequest::set_limit(
+                ClientId(1),
+                TransactionId(1),
+                limit,
+                OperatorId(7),
+                reason,
+            )
+        };
+
+        // A limit of zero takes away any overdraft the client had
+        assert!(matches!(
+            set_limit(dec!(0)).unwrap().request_type(),
+            RequestType::Admin(AdminRequest {
+                action: AdminAction::SetLimit(limit),
+                ..
+            }) if limit.is_zero()
+        ));
+        assert_eq!(
+            set_limit(dec!(250.00005))
+                .unwrap()
+                .request_type()
+                .to_string(),
+            "limit of 250.0000 by operator 7 for overdraft"
+        );
+        assert!(matches!(
+            set_limit(dec!(-1)).unwrap_err(),
+            ProcessTransactionError::NegativeAmount
+        ));
+    }
+
+    #[test]
+    fn test_transfer_validates_destination() {
+        let transfer =
+            TransactionRequest::transfer(ClientId(1), TransactionId(1), ClientId(2), dec!(1.5));
+        assert!(matches!(
+            transfer.unwrap().request_type(),
+            RequestType::Monetary(MonetaryTransaction::Transfer { amount, destination })
+                if amount == dec!(1.5) && destination == ClientId(2)
+        ));
+
+        let to_self =
+            TransactionRequest::transfer(ClientId(1), TransactionId(1), ClientId(1), dec!(1.5));
+        assert!(matches!(
+            to_self.unwrap_err(),
+            ProcessTransactionError::TransferToSelf
+        ));
+
+        assert!(matches!(
+            validate_destination(ClientId(1), None).unwrap_err(),
+            ProcessTransactionError::MissingDestination
+        ));
+    }
+
+    #[test]
+    fn test_convert_validates_target_asset() {
+        let usd = "USD".parse().unwrap();
+        let eur = "EUR".parse().unwrap();
+
+        let convert =
+            TransactionRequest::convert(ClientId(1), TransactionId(1), usd, eur, dec!(1.00005));
+        let convert = convert.unwrap();
+        assert_eq!(convert.asset(), usd);
+        assert!(matches!(
+            convert.request_type(),
+            RequestType::Monetary(MonetaryTransaction::Convert { amount, to })
+                if amount == dec!(1.0000) && to == eur
+        ));
+
+        let to_self =
+            TransactionRequest::convert(ClientId(1), TransactionId(1), usd, usd, dec!(1.5));
+        assert!(matches!(
+            to_self.unwrap_err(),
+            ProcessTransactionError::ConvertToSameAsset
+        ));
+
+        assert!(matches!(
+            validate_target_asset(usd, None).unwrap_err(),
+            ProcessTransactionError::MissingTargetAsset
+        ));
+    }
+
+    #[test]
+    fn test_parse_reason_code() {
+        let reason = "risk_cleared-2".parse::<ReasonCode>().unwrap();
+        assert_eq!(reason.to_string(), "risk_cleared-2");
+        assert_eq!(ReasonCode::CHARGEBACK.as_str(), "chargeback");
+        assert_eq!(
+            "1234567890abcdef".parse::<ReasonCode>().unwrap().as_str(),
+            "1234567890abcdef"
+        );
+
+        assert!("".parse::<ReasonCode>().is_err());
+        assert!("1234567890abcdefg".parse::<ReasonCode>().is_err());
+        assert!("risk cleared".parse::<ReasonCode>().is_err());
+
+        assert!(matches!(
+            validate_admin(AdminAction::Unlock, None, Some(reason)).unwrap_err(),
+            ProcessTransactionError::MissingOperator
+        ));
+        assert!(matches!(
+            validate_admin(AdminAction::Unlock, Some(OperatorId(1)), None).unwrap_err(),
+            ProcessTransactionError::MissingReason
+        ));
+    }
+
+    #[test]
+    fn test_parse_asset() {
+        let btc = "BTC".parse::<Asset>().unwrap();
+        assert_eq!(btc.to_string(), "BTC");
+        assert!(!btc.is_default());
+        assert_eq!("".parse::<Asset>().unwrap(), Asset::default());
+        assert_eq!("USDT1234".parse::<Asset>().unwrap().as_str(), "USDT1234");
+
+        // The default asset sorts first
+        assert!(Asset::default() < btc);
+        assert!(btc < "BTCX".parse().unwrap());
+
+        assert!("USDT12345".parse::<Asset>().is_err());
+        assert!("US-D".parse::<Asset>().is_err());
+    }
+}
@@ -1,12 +1,19 @@
-use std::fs::File;
-use transaction_processor::process;
+use rust_decimal::dec;
+use std::{fs::File, num::NonZeroUsize, path::Path, time::Duration};
+use transaction_processor::{
+    AdminAction, Asset, BalanceSink, ClaimType, ClientId, ClientSnapshot, CreditLimits,
+    DisputeWindow, EventKind, Exchange, FeeSchedule, IngestPolicy, InputFormat, InputPosition,
+    MonetaryTransaction, OperatorId, OutputFormat, ProcessError, ProcessReport,
+    ProcessTransactionError, Processor, RateTable, RequestType, RowError, StandardClaimPolicy,
+    TimestampOrder, TransactionId, TransactionRequest, TransactionStore, balance_sink, process,
+};
 
-fn test_handler(file_name: &str) {
+fn test_handler(file_name: &str) -> ProcessReport {
     let input_file =
         File::open(format!("tests/input/{}.csv", file_name)).expect("Failed to open input file");
     let mut output = Vec::new();
 
-    process(input_file, &mut output);
+    let report = process(input_file, &mut output).expect("Failed to process input");
 
     let output_str = String::from_utf8(output).expect("Invalid UTF-8 output");
 
@@ -14,6 +21,8 @@ fn test_handler(file_name: &str) {
         .expect("Failed to read expected output file");
 
     assert_eq!(output_str, expected_output_str);
+
+    report
 }
 
 #[test]
@@ -46,6 +55,21 @@ fn test_dispute_resolve() {
     test_handler("dispute_resolve");
 }
 
+#[test]
+fn test_transfer() {
+    let report = test_handler("transfer");
+
+    assert_eq!(report.rejected, 3);
+    assert_eq!(report.malformed, 1);
+}
+
+#[test]
+fn test_assets() {
+    let report = test_handler("assets");
+
+    assert_eq!(report.rejected, 2);
+}
+
 #[test]
 fn test_empty_input() {
     test_handler("empty_transactions");
@@ -55,3 +79,1090 @@ fn test_empty_input() {
 fn test_no_deposits_or_withdrawals() {
     test_handler("no_deposits_or_withdrawals");
 }
+
+#[test]
+fn test_malformed_rows_are_skipped() {
+    let report = test_handler("malformed_rows");
+
+    assert_eq!(
+        report,
+        ProcessReport {
+            accepted: 2,
+            rejected: 1,
+            malformed: 5,
+        }
+    );
+}
+
+fn process_fixture_with_policy(
+    file_name: &str,
+    policy: IngestPolicy,
+) -> Result<ProcessReport, ProcessError> {
+    let input_file =
+        File::open(format!("tests/input/{}.csv", file_name)).expect("Failed to open input file");
+
+    Processor::new()
+        .ingest_policy(policy)
+        .process(input_file, Vec::new())
+}
+
+#[test]
+fn test_strict_policy_stops_at_first_bad_row() {
+    let result = process_fixture_with_policy("malformed_rows", IngestPolicy::Strict);
+
+    let ProcessError::BadRow(bad_row) = result.unwrap_err() else {
+        panic!("Expected a bad row error");
+    };
+    assert!(matches!(
+        bad_row.error(),
+        RowError::Invalid(ProcessTransactionError::MissingDestination)
+    ));
+
+    // Context is recovered from the raw row even though it couldn't be converted to a request
+    let context = bad_row.context();
+    assert_eq!(context.position.map(|position| position.line), Some(3));
+    assert_eq!(context.transaction, Some(TransactionId(2)));
+    assert_eq!(context.client, Some(ClientId(1)));
+    assert!(context.request_type.is_none());
+}
+
+#[test]
+fn test_strict_policy_stops_on_rejected_transaction() {
+    let result = process_fixture_with_policy("client_created_on_withdrawal", IngestPolicy::Strict);
+
+    let ProcessError::BadRow(bad_row) = result.unwrap_err() else {
+        panic!("Expected a bad row error");
+    };
+    assert!(matches!(
+        bad_row.error(),
+        RowError::Rejected(ProcessTransactionError::InsufficientFunds { .. })
+    ));
+
+    let context = bad_row.context();
+    assert_eq!(context.position, Some(InputPosition { byte: 25, line: 2 }));
+    assert_eq!(context.transaction, Some(TransactionId(4)));
+    assert_eq!(context.client, Some(ClientId(1)));
+    assert!(matches!(
+        context.request_type,
+        Some(RequestType::Monetary(MonetaryTransaction::Withdrawal(_)))
+    ));
+    assert_eq!(
+        bad_row.to_string(),
+        "E_INSUFFICIENT_FUNDS: Error processing transaction: Insufficient funds: 1.5 requested, 0 available (line 2, byte 25, tx 4, client 1, withdrawal of 1.5)"
+    );
+}
+
+#[test]
+fn test_codes_split_out_keep_their_aliases() {
+    let input = "type,client,tx,amount\ndeposit,1,1,\n";
+    let result = Processor::new()
+        .ingest_policy(IngestPolicy::Strict)
+        .process(input.as_bytes(), Vec::new());
+
+    let ProcessError::BadRow(bad_row) = result.unwrap_err() else {
+        panic!("Expected a bad row error");
+    };
+    assert_eq!(bad_row.error().code(), "E_MISSING_AMOUNT");
+    assert!(bad_row.error().has_code("E_MISSING_AMOUNT"));
+    assert!(bad_row.error().has_code("E_INVALID_DATA"));
+    assert!(!bad_row.error().has_code("E_INVALID_OPERATION"));
+
+    assert_eq!(
+        ProcessTransactionError::NoDisputeToResolve.aliases(),
+        ["E_INVALID_OPERATION"]
+    );
+    assert!(ProcessTransactionError::Overflow.aliases().is_empty());
+}
+
+#[test]
+fn test_strict_policy_accepts_clean_input() {
+    let report = process_fixture_with_policy("single_client", IngestPolicy::Strict).unwrap();
+
+    assert_eq!(report.bad_rows(), 0);
+}
+
+#[test]
+fn test_lenient_policy_aborts_after_max_bad_rows() {
+    let result = process_fixture_with_policy(
+        "malformed_rows",
+        IngestPolicy::Lenient {
+            max_bad_rows: Some(2),
+        },
+    );
+
+    assert!(matches!(
+        result.unwrap_err(),
+        ProcessError::TooManyBadRows { limit: 2, .. }
+    ));
+
+    let report = process_fixture_with_policy(
+        "malformed_rows",
+        IngestPolicy::Lenient {
+            max_bad_rows: Some(6),
+        },
+    )
+    .unwrap();
+
+    assert_eq!(report.bad_rows(), 6);
+}
+
+#[test]
+fn test_dead_letter_records_bad_rows() {
+    let input_file =
+        File::open("tests/input/malformed_rows.csv").expect("Failed to open input file");
+    let mut dead_letter = Vec::new();
+
+    Processor::new()
+        .dead_letter(&mut dead_letter)
+        .process(input_file, Vec::new())
+        .expect("Failed to process input");
+
+    let dead_letter_str = String::from_utf8(dead_letter).expect("Invalid UTF-8 output");
+    let expected_dead_letter_str =
+        std::fs::read_to_string("tests/output/malformed_rows_dead_letter.csv")
+            .expect("Failed to read expected dead letter file");
+
+    assert_eq!(dead_letter_str, expected_dead_letter_str);
+}
+
+#[test]
+fn test_dead_letter_keeps_rows_the_reader_rejects() {
+    let input = b"type,client,tx,amount\ndeposit,1,1,\xff1.0\ndeposit,1,2\ndeposit,1,3,1.0\n";
+    let mut dead_letter = Vec::new();
+
+    let report = Processor::new()
+        .dead_letter(&mut dead_letter)
+        .process(&input[..], Vec::new())
+        .expect("Failed to process input");
+    assert_eq!(report.malformed, 2);
+
+    // Rows that aren't valid UTF-8 or have the wrong number of fields still keep their fields
+    let dead_letter = String::from_utf8(dead_letter).unwrap();
+    let rows = dead_letter
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').take(4).collect::<Vec<_>>().join(","))
+        .collect::<Vec<_>>();
+    assert_eq!(rows, ["deposit,1,1,\u{fffd}1.0", "deposit,1,2,"]);
+}
+
+#[test]
+fn test_dead_letter_written_for_clean_input() {
+    let input_file =
+        File::open("tests/input/single_client.csv").expect("Failed to open input file");
+    let mut dead_letter = Vec::new();
+
+    Processor::new()
+        .dead_letter(&mut dead_letter)
+        .process(input_file, Vec::new())
+        .expect("Failed to process input");
+
+    assert_eq!(
+        String::from_utf8(dead_letter).unwrap(),
+        "type,client,tx,amount,asset,destination,to_asset,operator,reason,timestamp,line,code,category,message\n"
+    );
+}
+
+#[test]
+fn test_json_lines_input_matches_csv() {
+    let input_file =
+        File::open("tests/input/dispute_chargeback.jsonl").expect("Failed to open input file");
+    let mut output = Vec::new();
+
+    Processor::new()
+        .input_format(InputFormat::JsonLines)
+        .process(input_file, &mut output)
+        .expect("Failed to process input");
+
+    let expected_output_str = std::fs::read_to_string("tests/output/dispute_chargeback.csv")
+        .expect("Failed to read expected output file");
+
+    assert_eq!(String::from_utf8(output).unwrap(), expected_output_str);
+}
+
+#[test]
+fn test_input_format_inferred_from_extension() {
+    assert_eq!(
+        InputFormat::from_path("tests/input/dispute_chargeback.jsonl"),
+        Some(InputFormat::JsonLines)
+    );
+    assert_eq!(
+        InputFormat::from_path("tests/input/dispute_chargeback.csv"),
+        Some(InputFormat::Csv)
+    );
+    assert_eq!(InputFormat::from_path("transactions"), None);
+}
+
+#[test]
+fn test_json_output() {
+    let input_file =
+        File::open("tests/input/dispute_chargeback.csv").expect("Failed to open input file");
+    let mut output = Vec::new();
+
+    Processor::new()
+        .output_format(OutputFormat::Json)
+        .process(input_file, &mut output)
+        .expect("Failed to process input");
+
+    let expected_output_str = std::fs::read_to_string("tests/output/dispute_chargeback.json")
+        .expect("Failed to read expected output file");
+
+    assert_eq!(String::from_utf8(output).unwrap(), expected_output_str);
+}
+
+#[test]
+fn test_process_into_vec_sink() {
+    let input_file =
+        File::open("tests/input/multiple_client.csv").expect("Failed to open input file");
+    let mut balances: Vec<ClientSnapshot> = Vec::new();
+
+    Processor::new()
+        .process_into(input_file, &mut balances)
+        .expect("Failed to process input");
+
+    assert_eq!(
+        balances,
+        vec![
+            ClientSnapshot {
+                client_id: ClientId(1),
+                asset: Asset::default(),
+                available: dec!(15),
+                held: dec!(0),
+                total: dec!(15),
+                locked: false,
+                headroom: None,
+            },
+            ClientSnapshot {
+                client_id: ClientId(2),
+                asset: Asset::default(),
+                available: dec!(20),
+                held: dec!(0),
+                total: dec!(20),
+                locked: false,
+                headroom: None,
+            },
+        ]
+    );
+}
+
+struct FailingSink;
+
+impl BalanceSink for FailingSink {
+    fn write(&mut self, _snapshot: &ClientSnapshot) -> Result<(), ProcessError> {
+        Err(ProcessError::Sink("database unavailable".into()))
+    }
+}
+
+#[test]
+fn test_custom_sink_error_is_returned() {
+    let input_file =
+        File::open("tests/input/single_client.csv").expect("Failed to open input file");
+
+    let result = Processor::new().process_into(input_file, &mut FailingSink);
+
+    assert!(matches!(result.unwrap_err(), ProcessError::Sink(_)));
+}
+
+fn sharded() -> NonZeroUsize {
+    NonZeroUsize::new(4).unwrap()
+}
+
+#[test]
+fn test_sharded_output_matches_fixtures() {
+    for entry in std::fs::read_dir("tests/input").expect("Failed to read input directory") {
+        let path = entry.expect("Failed to read input directory").path();
+        if path.extension().is_none_or(|extension| extension != "csv") {
+            continue;
+        }
+
+        let input_file = File::open(&path).expect("Failed to open input file");
+        let mut output = Vec::new();
+
+        Processor::new()
+            .workers(sharded())
+            .process(input_file, &mut output)
+            .expect("Failed to process input");
+
+        let expected_output_path = Path::new("tests/output").join(path.file_name().unwrap());
+        let expected_output_str = std::fs::read_to_string(&expected_output_path)
+            .expect("Failed to read expected output file");
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            expected_output_str,
+            "{}",
+            path.display()
+        );
+    }
+}
+
+/// Deterministic input with lots of transaction ID collisions and transfers between clients, so
+/// that the ordering guarantees of the sharded exchange are exercised.
+fn generated_input(rows: usize) -> String {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = |bound: u64| {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (state >> 33) % bound
+    };
+
+    let mut input = String::from("type,client,tx,amount,destination\n");
+    for _ in 0..rows {
+        let transaction_type = [
+            "deposit",
+            "deposit",
+            "withdrawal",
+            "transfer",
+            "dispute",
+            "resolve",
+            "chargeback",
+        ][next(7) as usize];
+        let client = next(50) + 1;
+        let tx = next(rows as u64 / 2) + 1;
+        let amount = format!("{}.{:04}", next(100), next(10_000));
+        let destination = next(50) + 1;
+        input.push_str(&format!(
+            "{},{},{},{},{}\n",
+            transaction_type, client, tx, amount, destination
+        ));
+    }
+    input
+}
+
+/// Chargebacks of a deposit and a withdrawal, by clients outside those of [`generated_input`].
+const CHARGEBACKS: &str = "\
+deposit,51,1000001,100.0,
+dispute,51,1000001,,
+chargeback,51,1000001,,
+deposit,52,1000002,50.0,
+withdrawal,52,1000003,20.0,
+dispute,52,1000003,,
+chargeback,52,1000003,,
+";
+
+#[test]
+fn test_sharded_matches_sequential() {
+    let input = generated_input(20_000);
+
+    let mut sequential_output = Vec::new();
+    let mut sequential_dead_letter = Vec::new();
+    let sequential_report = Processor::new()
+        .dead_letter(&mut sequential_dead_letter)
+        .process(input.as_bytes(), &mut sequential_output)
+        .expect("Failed to process input");
+
+    let mut sharded_output = Vec::new();
+    let mut sharded_dead_letter = Vec::new();
+    let sharded_report = Processor::new()
+        .workers(sharded())
+        .dead_letter(&mut sharded_dead_letter)
+        .process(input.as_bytes(), &mut sharded_output)
+        .expect("Failed to process input");
+
+    assert_eq!(sharded_report, sequential_report);
+    assert_eq!(sharded_output, sequential_output);
+    assert_eq!(
+        String::from_utf8(sharded_dead_letter).unwrap(),
+        String::from_utf8(sequential_dead_letter).unwrap()
+    );
+    assert!(sequential_report.rejected > 0);
+}
+
+#[test]
+fn test_sharded_strict_policy_stops_at_first_bad_row() {
+    let input_file = File::open("tests/input/client_created_on_withdrawal.csv")
+        .expect("Failed to open input file");
+
+    let result = Processor::new()
+        .workers(sharded())
+        .ingest_policy(IngestPolicy::Strict)
+        .process(input_file, Vec::new());
+
+    let ProcessError::BadRow(bad_row) = result.unwrap_err() else {
+        panic!("Expected a bad row error");
+    };
+    assert_eq!(bad_row.context().transaction, Some(TransactionId(4)));
+}
+
+fn sorted_snapshots(exchange: &Exchange) -> Vec<ClientSnapshot> {
+    let mut snapshots = exchange
+        .clients()
+        .flat_map(|client| client.snapshots())
+        .collect::<Vec<_>>();
+    snapshots.sort_by_key(|snapshot| (snapshot.client_id, snapshot.asset));
+    snapshots
+}
+
+#[test]
+fn test_replay_matches_every_fixture() {
+    for entry in std::fs::read_dir("tests/input").expect("Failed to read input directory") {
+        let path = entry.expect("Failed to read input directory").path();
+        let input_format = InputFormat::from_path(&path).expect("Unknown fixture format");
+
+        let mut exchange = Exchange::with_event_log();
+        Processor::new()
+            .input_format(input_format)
+            .process_exchange(
+                File::open(&path).expect("Failed to open input file"),
+                &mut exchange,
+            )
+            .expect("Failed to process input");
+
+        let log = exchange.event_log().expect("Events are recorded").clone();
+        let replayed = Exchange::replay(log.clone()).expect("Failed to replay log");
+        assert_eq!(
+            sorted_snapshots(&replayed),
+            sorted_snapshots(&exchange),
+            "{}",
+            path.display()
+        );
+
+        // The sharded exchange records exactly the same log
+        let mut sharded_exchange = Exchange::with_event_log();
+        Processor::new()
+            .input_format(input_format)
+            .workers(sharded())
+            .process_exchange(
+                File::open(&path).expect("Failed to open input file"),
+                &mut sharded_exchange,
+            )
+            .expect("Failed to process input");
+        assert_eq!(
+            sharded_exchange.event_log(),
+            Some(&log),
+            "{}",
+            path.display()
+        );
+    }
+}
+
+#[test]
+fn test_replay_matches_generated_input() {
+    let input = generated_input(5_000);
+
+    let mut exchange = Exchange::with_event_log();
+    Processor::new()
+        .process_exchange(input.as_bytes(), &mut exchange)
+        .expect("Failed to process input");
+
+    let replayed = Exchange::replay(exchange.event_log().unwrap().clone()).unwrap();
+    assert_eq!(sorted_snapshots(&replayed), sorted_snapshots(&exchange));
+}
+
+#[test]
+fn test_resume_from_snapshot_matches_single_run() {
+    let input = std::fs::read_to_string("tests/input/dispute_chargeback.csv")
+        .expect("Failed to read input file");
+    let mut lines = input.lines();
+    let header = lines.next().unwrap();
+    let rows = lines.collect::<Vec<_>>();
+
+    // Split the file part-way through, so that later claims refer to earlier deposits
+    let (first, second) = rows.split_at(rows.len() / 2);
+    let part = |rows: &[&str]| format!("{}\n{}\n", header, rows.join("\n"));
+
+    let mut exchange = Exchange::new();
+    Processor::new()
+        .process_exchange(part(first).as_bytes(), &mut exchange)
+        .expect("Failed to process input");
+    let mut snapshot = Vec::new();
+    exchange
+        .save_snapshot(&mut snapshot)
+        .expect("Failed to save snapshot");
+
+    let mut resumed =
+        Exchange::load_snapshot(snapshot.as_slice()).expect("Failed to load snapshot");
+    Processor::new()
+        .process_exchange(part(second).as_bytes(), &mut resumed)
+        .expect("Failed to process input");
+
+    let mut output = Vec::new();
+    resumed
+        .write_balances(balance_sink(OutputFormat::Csv, &mut output).as_mut())
+        .expect("Failed to write balances");
+
+    let expected_output_str = std::fs::read_to_string("tests/output/dispute_chargeback.csv")
+        .expect("Failed to read expected output file");
+    assert_eq!(String::from_utf8(output).unwrap(), expected_output_str);
+}
+
+#[test]
+fn test_spilled_transactions_match_in_memory() {
+    let input = generated_input(20_000);
+    let dir = std::env::temp_dir().join(format!("transactions-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Failed to create spill directory");
+
+    let run = |exchange: &mut Exchange| {
+        let mut dead_letter = Vec::new();
+        Processor::new()
+            .dead_letter(&mut dead_letter)
+            .process_exchange(input.as_bytes(), exchange)
+            .expect("Failed to process input");
+        let mut output = Vec::new();
+        exchange
+            .write_balances(balance_sink(OutputFormat::Csv, &mut output).as_mut())
+            .expect("Failed to write balances");
+        (output, dead_letter)
+    };
+
+    let in_memory = run(&mut Exchange::new());
+
+    // A budget this small keeps only a handful of transactions in memory
+    let mut spilled = Exchange::new();
+    spilled
+        .set_transaction_store(
+            TransactionStore::spill_to_disk(&dir, 4096).expect("Failed to create store"),
+        )
+        .expect("Failed to set store");
+    assert_eq!(run(&mut spilled), in_memory);
+}
+
+#[test]
+fn test_sharded_dispute_window_matches_sequential() {
+    let input = generated_input(20_000);
+
+    let run = |workers: Option<NonZeroUsize>| {
+        let mut exchange = Exchange::new();
+        exchange.set_dispute_window(DisputeWindow::Transactions(NonZeroUsize::new(3).unwrap()));
+        let mut processor = Processor::new();
+        if let Some(workers) = workers {
+            processor = processor.workers(workers);
+        }
+
+        let mut dead_letter = Vec::new();
+        let report = processor
+            .dead_letter(&mut dead_letter)
+            .process_exchange(input.as_bytes(), &mut exchange)
+            .expect("Failed to process input");
+        let mut output = Vec::new();
+        exchange
+            .write_balances(balance_sink(OutputFormat::Csv, &mut output).as_mut())
+            .expect("Failed to write balances");
+        (report, output, String::from_utf8(dead_letter).unwrap())
+    };
+
+    let sequential = run(None);
+    assert_eq!(run(Some(sharded())), sequential);
+    assert!(sequential.2.contains("E_DISPUTE_WINDOW_EXPIRED"));
+}
+
+fn timestamps_csv(
+    timestamp_order: TimestampOrder,
+    exchange: &mut Exchange,
+) -> (ProcessReport, String) {
+    let input_file = File::open("tests/input/timestamps.csv").expect("Failed to open input file");
+    let mut dead_letter = Vec::new();
+    let report = Processor::new()
+        .timestamp_order(timestamp_order)
+        .dead_letter(&mut dead_letter)
+        .process_exchange(input_file, exchange)
+        .expect("Failed to process input");
+    (report, String::from_utf8(dead_letter).unwrap())
+}
+
+fn csv_balances(exchange: &Exchange) -> String {
+    let mut output = Vec::new();
+    exchange
+        .write_balances(balance_sink(OutputFormat::Csv, &mut output).as_mut())
+        .expect("Failed to write balances");
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_reorder_applies_rows_in_timestamp_order() {
+    let mut exchange = Exchange::new();
+    let (report, _) = timestamps_csv(
+        TimestampOrder::Reorder {
+            tolerance: Duration::from_secs(3600),
+        },
+        &mut exchange,
+    );
+
+    // Client 2's withdrawal is applied before the dispute that follows it in time
+    assert_eq!(report.bad_rows(), 0);
+    assert_eq!(
+        csv_balances(&exchange),
+        "client,available,held,total,locked\n1,7,0,7,false\n2,-1,5,4,false\n"
+    );
+}
+
+#[test]
+fn test_reject_rows_outside_tolerance() {
+    let mut exchange = Exchange::new();
+    let (report, dead_letter) = timestamps_csv(
+        TimestampOrder::Reject {
+            tolerance: Duration::from_secs(15 * 60),
+        },
+        &mut exchange,
+    );
+
+    assert_eq!(report.malformed, 2);
+    let lines = dead_letter.lines().skip(1).collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().all(|line| line.contains("E_OUT_OF_ORDER")));
+    assert!(lines[0].starts_with("deposit,1,4,1.0,,,,,,2024-05-01T09:30:00Z,5,"));
+}
+
+#[test]
+fn test_ordering_requires_timestamps() {
+    let input_file =
+        File::open("tests/input/single_client.csv").expect("Failed to open input file");
+
+    let result = Processor::new()
+        .timestamp_order(TimestampOrder::Reorder {
+            tolerance: Duration::ZERO,
+        })
+        .ingest_policy(IngestPolicy::Strict)
+        .process(input_file, Vec::new());
+
+    let ProcessError::BadRow(bad_row) = result.unwrap_err() else {
+        panic!("Expected a bad row error");
+    };
+    assert_eq!(bad_row.error().code(), "E_MISSING_TIMESTAMP");
+}
+
+#[test]
+fn test_balances_as_of() {
+    let mut exchange = Exchange::with_event_log();
+    timestamps_csv(
+        TimestampOrder::Reorder {
+            tolerance: Duration::from_secs(3600),
+        },
+        &mut exchange,
+    );
+
+    let as_of = "2024-05-01T09:45:00Z".parse().unwrap();
+    let log = exchange.event_log().unwrap().until(as_of);
+    let replayed = Exchange::replay(log).expect("Failed to replay log");
+
+    assert_eq!(
+        csv_balances(&replayed),
+        "client,available,held,total,locked\n1,11,0,11,false\n2,5,0,5,false\n"
+    );
+}
+
+#[test]
+fn test_convert() {
+    let rates = "\
+pair, rate, valid_from
+USD/EUR, 0.9, 2024-05-01T00:00:00Z
+USD/EUR, 0.95, 2024-05-03T00:00:00Z
+EUR/USD, 1.1, 2024-05-01T00:00:00Z
+";
+    let input = "\
+type, client, tx, amount, asset, to_asset, timestamp
+deposit, 1, 1, 100, USD, , 2024-05-01T09:00:00Z
+convert, 1, 2, 10.00005, USD, EUR, 2024-05-01T12:00:00Z
+convert, 1, 3, 5, EUR, USD, 2024-05-03T09:00:00Z
+convert, 1, 4, 10, USD, EUR, 2024-05-03T09:00:00Z
+convert, 1, 5, 1, USD, BTC, 2024-05-03T09:00:00Z
+convert, 1, 6, 1, USD, , 2024-05-03T09:00:00Z
+convert, 1, 7, 1000, USD, EUR, 2024-05-03T09:00:00Z
+";
+
+    let mut exchange = Exchange::new();
+    exchange.set_rate_table(
+        RateTable::from_csv(rates.as_bytes())
+            .expect("Failed to read rates")
+            .with_max_age(Duration::from_secs(24 * 3600)),
+    );
+    let mut dead_letter = Vec::new();
+    let report = Processor::new()
+        .dead_letter(&mut dead_letter)
+        .process_exchange(input.as_bytes(), &mut exchange)
+        .expect("Failed to process input");
+
+    // 10.00005 is rounded to 10.0000 before converting, and the later USD/EUR rate is used once
+    // it's valid. The EUR/USD rate is too old by then.
+    assert_eq!(
+        csv_balances(&exchange),
+        "client,asset,available,held,total,locked\n\
+         1,EUR,18.5000,0,18.5000,false\n\
+         1,USD,80.0000,0,80.0000,false\n"
+    );
+
+    let codes = String::from_utf8(dead_letter)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').nth(11).unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        codes,
+        [
+            "E_STALE_RATE",
+            "E_UNKNOWN_PAIR",
+            "E_MISSING_TARGET_ASSET",
+            "E_INSUFFICIENT_FUNDS"
+        ]
+    );
+    assert_eq!(report.malformed, 1);
+    assert_eq!(report.rejected, 3);
+}
+
+#[test]
+fn test_sharded_fees_match_sequential() {
+    let fees = r#"{
+        "tiers": {
+            "default": {"deposit": {"percent": "0.25"}, "withdrawal": {"flat": "0.5", "percent": "0.1"}},
+            "free": {}
+        },
+        "clients": {"3": "free", "4": "free"},
+        "disputed_fees": "refund"
+    }"#;
+    let input = generated_input(20_000) + CHARGEBACKS;
+
+    let run = |workers: Option<NonZeroUsize>| {
+        let mut exchange = Exchange::new();
+        exchange.set_fee_schedule(FeeSchedule::from_json(fees.as_bytes()).unwrap());
+        let mut processor = Processor::new();
+        if let Some(workers) = workers {
+            processor = processor.workers(workers);
+        }
+        let report = processor
+            .process_exchange(input.as_bytes(), &mut exchange)
+            .expect("Failed to process input");
+        (
+            report,
+            csv_balances(&exchange),
+            exchange.house_balances().collect::<Vec<_>>(),
+        )
+    };
+
+    let sequential = run(None);
+    assert_eq!(run(Some(sharded())), sequential);
+    assert!(sequential.2[0].1.fees > dec!(0));
+    assert!(!sequential.2[0].1.chargebacks.is_zero());
+}
+
+#[test]
+fn test_sharded_claim_policy_matches_sequential() {
+    let policy = r#"{
+        "hold_disputed_withdrawals": true,
+        "lock_on_chargeback": false,
+        "allow_negative_available": false
+    }"#;
+    let input = generated_input(20_000) + CHARGEBACKS;
+
+    let run = |workers: Option<NonZeroUsize>| {
+        let mut exchange = Exchange::new();
+        exchange.set_claim_policy(StandardClaimPolicy::from_json(policy.as_bytes()).unwrap());
+        let mut processor = Processor::new();
+        if let Some(workers) = workers {
+            processor = processor.workers(workers);
+        }
+        let report = processor
+            .process_exchange(input.as_bytes(), &mut exchange)
+            .expect("Failed to process input");
+        (
+            report,
+            csv_balances(&exchange),
+            exchange.house_balances().collect::<Vec<_>>(),
+        )
+    };
+
+    let sequential = run(None);
+    assert_eq!(run(Some(sharded())), sequential);
+    assert!(!sequential.1.contains("true"));
+    assert!(!sequential.2[0].1.chargebacks.is_zero());
+}
+
+#[test]
+fn test_house_report_reconciles_with_deposits_and_withdrawals() {
+    let fees = r#"{
+        "tiers": {"default": {"deposit": {"percent": "0.25"}, "withdrawal": {"flat": "0.5"}}},
+        "disputed_fees": "refund"
+    }"#;
+    let mut exchange = Exchange::with_event_log();
+    exchange.set_fee_schedule(FeeSchedule::from_json(fees.as_bytes()).unwrap());
+    Processor::new()
+        .process_exchange(
+            (generated_input(5_000) + CHARGEBACKS).as_bytes(),
+            &mut exchange,
+        )
+        .expect("Failed to process input");
+
+    let net = exchange
+        .event_log()
+        .unwrap()
+        .events()
+        .iter()
+        .map(|event| match &event.kind {
+            EventKind::Accepted(request) => match request.request_type() {
+                RequestType::Monetary(MonetaryTransaction::Deposit(amount)) => amount,
+                RequestType::Monetary(MonetaryTransaction::Withdrawal(amount)) => -amount,
+                _ => dec!(0),
+            },
+            EventKind::Changed(_) => dec!(0),
+        })
+        .sum::<rust_decimal::Decimal>();
+
+    let mut report = Vec::new();
+    exchange.write_house_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    let mut rows = report.lines();
+    assert_eq!(
+        rows.next(),
+        Some("asset,clients,fees,chargebacks,conversions,house,holdings")
+    );
+    let row = rows.next().unwrap().split(',').collect::<Vec<_>>();
+    assert_eq!(rows.next(), None);
+
+    let amount = |column: usize| row[column].parse::<rust_decimal::Decimal>().unwrap();
+    assert_eq!(row[0], "");
+    assert_eq!(amount(1) + amount(5), amount(6));
+    assert_eq!(amount(6), net);
+    assert!(!amount(3).is_zero());
+}
+
+#[test]
+fn test_admin_actions() {
+    let input = "\
+type,client,tx,amount,operator,reason,timestamp
+deposit,1,1,10,,,2024-05-01T09:00:00Z
+dispute,1,1,,,,2024-05-01T09:05:00Z
+chargeback,1,1,,,,2024-05-01T09:10:00Z
+deposit,1,2,5,,,2024-05-01T09:15:00Z
+unlock,1,100,,9,risk_cleared,2024-05-01T10:00:00Z
+unlock,1,101,,,risk_cleared,2024-05-01T10:00:00Z
+unlock,1,102,,7,risk_cleared,2024-05-01T10:00:00Z
+deposit,1,3,5,,,2024-05-01T10:15:00Z
+freeze,2,103,,7,fraud_review,
+";
+
+    let mut exchange = Exchange::with_event_log();
+    exchange.set_operators([OperatorId(7)]);
+    let mut dead_letter = Vec::new();
+    let report = Processor::new()
+        .dead_letter(&mut dead_letter)
+        .process_exchange(input.as_bytes(), &mut exchange)
+        .expect("Failed to process input");
+
+    assert_eq!(
+        csv_balances(&exchange),
+        "client,available,held,total,locked\n1,5,0,5,false\n"
+    );
+
+    let codes = String::from_utf8(dead_letter)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').nth(11).unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        codes,
+        [
+            "E_CLIENT_LOCKED",
+            "E_UNAUTHORIZED_OPERATOR",
+            "E_MISSING_OPERATOR",
+            "E_CLIENT_NOT_FOUND"
+        ]
+    );
+    assert_eq!(report.malformed, 1);
+    assert_eq!(report.rejected, 3);
+
+    let mut audit = Vec::new();
+    exchange
+        .event_log()
+        .unwrap()
+        .write_audit(&mut audit)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(audit).unwrap(),
+        "seq,client,tx,status,operator,reason,timestamp,asset,limit\n\
+         11,1,1,locked,,chargeback,2024-05-01T09:10:00.000Z,,\n\
+         13,1,102,active,7,risk_cleared,2024-05-01T10:00:00.000Z,,\n"
+    );
+}
+
+#[test]
+fn test_partial_claims() {
+    let input = "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,2,2,50
+dispute,1,1,30
+dispute,1,1,80
+dispute,1,1,20
+resolve,1,1,60
+resolve,1,1,10
+chargeback,1,1,25
+dispute,2,2,0
+";
+
+    let mut exchange = Exchange::new();
+    let mut dead_letter = Vec::new();
+    let report = Processor::new()
+        .dead_letter(&mut dead_letter)
+        .process_exchange(input.as_bytes(), &mut exchange)
+        .expect("Failed to process input");
+
+    assert_eq!(
+        csv_balances(&exchange),
+        "client,available,held,total,locked\n1,60,15,75,true\n2,50,0,50,false\n"
+    );
+    let codes = String::from_utf8(dead_letter)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').nth(11).unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        codes,
+        [
+            "E_DISPUTE_EXCEEDS_TRANSACTION",
+            "E_CLAIM_EXCEEDS_DISPUTE",
+            "E_ZERO_CLAIM_AMOUNT"
+        ]
+    );
+    assert_eq!(report.malformed, 1);
+    assert_eq!(report.rejected, 2);
+
+    // The amounts disputed and charged back are part of the snapshot
+    let mut saved = Vec::new();
+    exchange.save_snapshot(&mut saved).unwrap();
+    let mut loaded = Exchange::load_snapshot(saved.as_slice()).unwrap();
+    loaded.set_operators([OperatorId(7)]);
+    let unlock = TransactionRequest::admin(
+        ClientId(1),
+        TransactionId(100),
+        AdminAction::Unlock,
+        OperatorId(7),
+        "reviewed".parse().unwrap(),
+    );
+    loaded.process_transaction(unlock).unwrap();
+    for claim_type in [ClaimType::Resolve, ClaimType::Dispute] {
+        let claim = TransactionRequest::claim(ClientId(1), TransactionId(1), claim_type);
+        loaded.process_transaction(claim).unwrap();
+    }
+    assert_eq!(
+        csv_balances(&loaded),
+        "client,available,held,total,locked\n1,0,75,75,false\n2,50,0,50,false\n"
+    );
+}
+
+fn collections_report(exchange: &Exchange) -> String {
+    let mut report = Vec::new();
+    exchange
+        .write_collections_report(&mut report)
+        .expect("Failed to write collections report");
+    String::from_utf8(report).unwrap()
+}
+
+#[test]
+fn test_collections_report_matches_sharded_and_replayed() {
+    // Client 53 disputes a deposit they've mostly withdrawn
+    let input = generated_input(20_000)
+        + "deposit,53,1000001,10.0,\nwithdrawal,53,1000002,8.0,\ndispute,53,1000001,,\n";
+
+    let mut sequential = Exchange::with_event_log();
+    Processor::new()
+        .process_exchange(input.as_bytes(), &mut sequential)
+        .expect("Failed to process input");
+    let mut sharded = Exchange::new();
+    Processor::new()
+        .workers(self::sharded())
+        .process_exchange(input.as_bytes(), &mut sharded)
+        .expect("Failed to process input");
+    let replayed = Exchange::replay(sequential.event_log().unwrap().clone()).unwrap();
+
+    let report = collections_report(&sequential);
+    assert_eq!(collections_report(&sharded), report);
+    assert_eq!(collections_report(&replayed), report);
+
+    // Every client still in deficit owes at least as much as their peak
+    let mut rows = report.lines();
+    assert_eq!(
+        rows.next(),
+        Some("client,asset,available,total,peak,since,until,duration_ms,recovered,transactions")
+    );
+    let rows = rows
+        .map(|row| row.split(',').collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows.last().unwrap().join(","),
+        "53,,-8,2,-8,,,,false,1000001"
+    );
+    for row in rows.iter().filter(|row| row[8] == "false") {
+        let amount = |column: usize| row[column].parse::<rust_decimal::Decimal>().unwrap();
+        assert!(amount(2).min(amount(3)).is_sign_negative(), "{:?}", row);
+        assert!(amount(4) <= amount(2).min(amount(3)), "{:?}", row);
+    }
+}
+
+#[test]
+fn test_collections_report_with_timestamps() {
+    let mut exchange = Exchange::new();
+    timestamps_csv(
+        TimestampOrder::Reorder {
+            tolerance: Duration::from_secs(3600),
+        },
+        &mut exchange,
+    );
+
+    // Client 2 had withdrawn part of the deposit they disputed
+    assert_eq!(
+        collections_report(&exchange),
+        concat!(
+            "client,asset,available,total,peak,since,until,duration_ms,recovered,transactions\n",
+            "2,,-1,4,-1,2024-05-02T09:00:00.000Z,,0,false,2\n",
+        )
+    );
+}
+
+#[test]
+fn test_credit_limits() {
+    let limits = "client,asset,limit\n1,,100\n2,BTC,0.5\n";
+    let input = "\
+type,client,tx,amount,asset,operator,reason
+deposit,1,1,10,,,
+withdrawal,1,2,60,,,
+deposit,2,3,1,BTC,,
+withdrawal,2,4,1.25,BTC,,
+withdrawal,1,5,50.0001,,,
+set_limit,1,6,200,,7,overdraft
+withdrawal,1,7,50.0001,,,
+set_limit,3,8,20,,7,overdraft
+deposit,3,9,5,,,
+withdrawal,3,10,25,,,
+set_limit,2,11,0,BTC,7,overdraft
+";
+
+    let process = |workers: Option<NonZeroUsize>| {
+        let mut exchange = Exchange::new();
+        exchange.set_operators([OperatorId(7)]);
+        exchange.set_credit_limits(CreditLimits::from_csv(limits.as_bytes()).unwrap());
+        let mut processor = Processor::new();
+        if let Some(workers) = workers {
+            processor = processor.workers(workers);
+        }
+        let report = processor
+            .process_exchange(input.as_bytes(), &mut exchange)
+            .expect("Failed to process input");
+        (exchange, report)
+    };
+    let (exchange, report) = process(None);
+    let (sharded, _) = process(Some(sharded()));
+
+    // Client 3 had no client to set a limit on yet
+    assert_eq!(report.rejected, 3);
+    assert_eq!(
+        csv_balances(&exchange),
+        "client,asset,available,held,total,locked,headroom\n\
+         1,,-100.0001,0,-100.0001,false,99.9999\n\
+         2,BTC,-0.25,0,-0.25,false,-0.25\n\
+         3,,5,0,5,false,5\n"
+    );
+    assert_eq!(csv_balances(&sharded), csv_balances(&exchange));
+
+    // Overdrawn clients are in deficit like any other
+    assert_eq!(
+        collections_report(&exchange),
+        concat!(
+            "client,asset,available,total,peak,since,until,duration_ms,recovered,transactions\n",
+            "1,,-100.0001,-100.0001,-100.0001,,,,false,2 7\n",
+            "2,BTC,-0.25,-0.25,-0.25,,,,false,4\n",
+        )
+    );
+}